@@ -1,24 +1,31 @@
 use std::{io::{stdout, stdin, Write}, fmt::Debug, str::FromStr, time::Duration};
-use token_ring::{station::{ActiveStation, GlobalConfig}, id::WorkStationId, err::TResult};
+use token_ring::{station::{ActiveStation, GlobalConfig}, id::WorkStationId, err::TResult, event::{StationJoinedEvent, StationLeftEvent}};
 
 #[tokio::main]
 async fn main() -> TResult {
     println!("Token Ring Chat Auth");
 
-    let name = read_string("Enter ID (max 8 chars ASCII)");
+    let name = read_string("Enter ID (max 32 bytes)");
     let port = read::<u16>("Listen on port");
     let pw = read_string("Enter password (optional)");
     let mut active_station = ActiveStation::host(
         WorkStationId::new(name), GlobalConfig::new(
             pw, true, 32, 5.),
         port).await?;
-    println!("Hosting active station.");
+    println!("Hosting active station on {} ({}).", active_station.local_addr(), active_station.fingerprint());
 
     loop {
         match active_station.recv_all().await {
             Ok(_) => (),
             Err(e) => println!("Recv err: {e}.")
         }
+        while let Some(event) = active_station.poll_event() {
+            if let Some(joined) = event.downcast_ref::<StationJoinedEvent>() {
+                println!("{} joined ({} members).", joined.source, joined.members);
+            } else if let Some(left) = event.downcast_ref::<StationLeftEvent>() {
+                println!("{} left ({} members).", left.source, left.members);
+            }
+        }
         match active_station.poll_token_pass().await {
             Ok(()) => (),
             Err(e) => println!("Token poll err: {e}.")
@@ -5,7 +5,7 @@ use token_ring::{station::PassiveStation, err::TResult, id::WorkStationId, token
 async fn main() -> TResult {
     println!("Token Ring Chat Node");
 
-    let name = read_line("Enter ID (max 8 chars ASCII)");
+    let name = read_line("Enter ID (max 32 bytes)");
     let port = read::<u16>("Listen on port");
     let mut passive_station = PassiveStation::new(
         WorkStationId::new(name), port).await?;
@@ -16,13 +16,17 @@ async fn main() -> TResult {
     let pw = read_line("Enter password");
     passive_station.connect(target_addr, pw).await?;
     loop {
+        if let Err(e) = passive_station.tick() {
+            println!("Join err: {e}.");
+            break Ok(())
+        }
         match passive_station.recv_next().await {
             Ok(_) => {
                 if let Some(curr_token) = passive_station.get_token_mut() {
                     for frame in curr_token.frames.iter() {
                         match &frame.content {
                             TokenFrameType::Data {
-                                send_mode, seq, payload } => {
+                                send_mode, seq, payload, .. } => {
                                     let mut cursor = Cursor::new(payload.as_slice());
                                     let text = token_ring::serialize::read_string(&mut cursor)?;
                                     println!("{:?} wrote: {text}.", frame.id.source);
@@ -34,13 +38,15 @@ async fn main() -> TResult {
                     let mut buf = vec![];
                     write_string(&mut buf, &text)?;
                     passive_station.append_frame(TokenFrameType::Data {
-                        send_mode: TokenSendMode::Broadcast, seq: 0, payload: buf });
+                        send_mode: TokenSendMode::Broadcast, seq: 0, payload: buf, expires_at: None })?;
                     
                     passive_station.pass_on_token()?;
                 }
             },
             Err(e) => println!("Recv err: {e}."),
         }
+        // Token acquire/release events are not used by this client
+        while passive_station.poll_event().is_some() {}
 
 
         // let text = read_line("Write");
@@ -53,7 +59,7 @@ async fn main() -> TResult {
         //         }
         //     };
         //     passive_station.append_frame(TokenFrameType::Data {
-        //         send_mode: TokenSendMode::Broadcast, seq: 0, payload: buf })
+        //         send_mode: TokenSendMode::Broadcast, seq: 0, payload: buf, expires_at: None })
         // }
         stdout().flush().unwrap();
     }
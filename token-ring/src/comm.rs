@@ -1,9 +1,22 @@
-use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}, net::SocketAddr};
+use std::{sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}}, net::SocketAddr, collections::{HashMap, HashSet}, future::Future, io, time::{Duration, Instant}};
 use crossbeam_channel::{Sender, Receiver};
-use tokio::net::UdpSocket;
-use crate::{packet::Packet, err::TResult, serialize::Serializer};
+use ed25519_dalek::Keypair;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use tokio::{net::UdpSocket, sync::{mpsc, Notify}, task::JoinHandle};
+use crate::{id::WorkStationId, packet::{Packet, PacketHeader, PacketType}, err::{TResult, TokenRingError, GlobalError}, serialize::Serializer, signature::Signed, station::AMx};
 
 pub const RECV_BUF_LENGTH: usize = 1024 * 4;
+// How long the send loop sleeps when its queue ran empty
+pub const SEND_POLL_INTERVAL: Duration = Duration::from_millis(1);
+// Pause after a failed socket read, since a broken transport fails again right away
+pub const RECV_ERROR_BACKOFF: Duration = Duration::from_millis(50);
+
+// Longest prefix of a datagram shown in wire dumps
+pub const MAX_WIRE_DUMP_LEN: usize = 256;
+
+// Receives a hex dump line for every sent and received datagram, for protocol
+// debugging (see set_wire_dump on the stations). Off by default.
+pub type WireDump = Arc<dyn Fn(&str) + Send + Sync>;
 
 pub type Sx<T> = Sender<T>;
 pub type Rx<T> = Receiver<T>;
@@ -11,103 +24,496 @@ pub type Channel<T> = (Sx<T>, Rx<T>);
 
 pub struct QueuedPacket(pub Packet, pub SocketAddr);
 
-pub struct WorkStationSender {
+// Packet waiting in the send queue. The header is signed by the send loop
+// right before transmission, keeping the caller's path cheap. Each packet
+// carries the keypair current at queue time (see key rotation).
+pub struct OutgoingPacket {
+    pub header: OutgoingHeader,
+    pub content: PacketType,
+    pub dest: SocketAddr
+}
+
+pub enum OutgoingHeader {
+    Unsigned { keypair: Arc<Keypair>, source: WorkStationId },
+    // Signed once and shared by all packets of a broadcast. The header
+    // does not name the destination, so one signature fits every member.
+    Signed(Signed<PacketHeader>)
+}
+
+impl OutgoingPacket {
+    pub fn new(keypair: Arc<Keypair>, source: WorkStationId, content: PacketType,
+        dest: SocketAddr) -> OutgoingPacket {
+        OutgoingPacket {
+            header: OutgoingHeader::Unsigned { keypair, source }, content, dest
+        }
+    }
+
+    pub fn sign(self) -> TResult<QueuedPacket> {
+        let header = match self.header {
+            OutgoingHeader::Unsigned { keypair, source } =>
+                Signed::new(&keypair, PacketHeader::new(source))?,
+            OutgoingHeader::Signed(header) => header
+        };
+        Ok(QueuedPacket(Packet::new(header, self.content), self.dest))
+    }
+}
+
+// Deadlines for single socket operations. Expired sends are reported back to
+// the station, expired receives are merely logged.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub send: Duration,
+    pub recv: Duration
+}
+
+impl Timeouts {
+    pub fn new(send: Duration, recv: Duration) -> Timeouts {
+        Timeouts {
+            send, recv
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts::new(Duration::from_secs(5), Duration::from_secs(30))
+    }
+}
+
+// Encoding of packets on the wire. Binary is the compact default. JSON is
+// human-readable, for debugging and non-Rust clients, but much larger (tokens
+// must still fit into RECV_BUF_LENGTH). All stations of a ring must agree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    #[default]
+    Binary,
+    #[cfg(feature = "serde")]
+    Json
+}
+
+impl WireFormat {
+    // Clears buf first, see Serializer::serialize_into
+    pub fn encode(&self, packet: &Packet, buf: &mut Vec<u8>) -> TResult {
+        match self {
+            WireFormat::Binary => packet.serialize_into(buf),
+            #[cfg(feature = "serde")]
+            WireFormat::Json => {
+                buf.clear();
+                Ok(serde_json::to_writer(buf, packet)?)
+            }
+        }
+    }
+
+    pub fn decode(&self, buf: &[u8]) -> TResult<Packet> {
+        match self {
+            WireFormat::Binary => Packet::deserialize(buf),
+            #[cfg(feature = "serde")]
+            WireFormat::Json => Ok(serde_json::from_slice(buf)?)
+        }
+    }
+}
+
+// Datagram transport used by the send and receive loops. Implemented for
+// UDP sockets and for the in-memory network below (testing).
+pub trait Transport: Send + Sync + 'static {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr)
+        -> impl Future<Output = io::Result<usize>> + Send;
+    fn recv_from(&self, buf: &mut [u8])
+        -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    // Wrap in Formatted to use another format
+    fn wire_format(&self) -> WireFormat {
+        WireFormat::Binary
+    }
+}
+
+// Transport with a non-default wire format
+pub struct Formatted<T: Transport> {
+    inner: T,
+    format: WireFormat
+}
+
+impl<T: Transport> Formatted<T> {
+    pub fn new(inner: T, format: WireFormat) -> Formatted<T> {
+        Formatted {
+            inner, format
+        }
+    }
+}
+
+impl<T: Transport> Transport for Formatted<T> {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr)
+        -> impl Future<Output = io::Result<usize>> + Send {
+        self.inner.send_to(buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8])
+        -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send {
+        self.inner.recv_from(buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn wire_format(&self) -> WireFormat {
+        self.format
+    }
+}
+
+impl Transport for UdpSocket {
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf).await
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+// Loses and reorders sent datagrams like a bad network (testing). Each
+// datagram is dropped with probability drop_rate, the rest are delayed by a
+// random time of up to reorder_window, so datagrams sent closer together than
+// that may overtake each other. Seeded, so runs see the same losses.
+pub struct LossyTransport<T: Transport> {
+    inner: Arc<T>,
+    drop_rate: f64,
+    reorder_window: Duration,
+    rng: Mutex<StdRng>
+}
+
+impl<T: Transport> LossyTransport<T> {
+    pub fn new(inner: T, drop_rate: f64, reorder_window: Duration, seed: u64) -> TResult<LossyTransport<T>> {
+        // Also rejects NaN
+        if !(0. ..=1.).contains(&drop_rate) {
+            return Err(GlobalError::Internal(TokenRingError::InvalidConfig("drop_rate must be between 0 and 1")))
+        }
+        Ok(LossyTransport {
+            inner: Arc::new(inner), drop_rate, reorder_window,
+            rng: Mutex::new(StdRng::seed_from_u64(seed))
+        })
+    }
+}
+
+impl<T: Transport> Transport for LossyTransport<T> {
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let (dropped, delay) = {
+            let mut rng = self.rng.lock().unwrap();
+            (rng.gen_bool(self.drop_rate), self.reorder_window.mul_f64(rng.gen()))
+        };
+        // Lost datagrams still count as sent, the sender can not tell
+        if dropped {
+            return Ok(buf.len())
+        }
+        if delay.is_zero() {
+            return self.inner.send_to(buf, addr).await
+        }
+        let (inner, payload) = (self.inner.clone(), buf.to_vec());
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let _ = inner.send_to(&payload, addr).await;
+        });
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8])
+        -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send {
+        self.inner.recv_from(buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn wire_format(&self) -> WireFormat {
+        self.inner.wire_format()
+    }
+}
+
+type Datagram = (Vec<u8>, SocketAddr);
+type DropFilter = Box<dyn FnMut(SocketAddr, SocketAddr) -> bool + Send>;
+
+// In-memory datagram network. Transports bound to it deliver to each other
+// by socket addr; datagrams to unbound addrs are lost, just like over UDP.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    endpoints: Arc<Mutex<HashMap<SocketAddr, mpsc::UnboundedSender<Datagram>>>>,
+    drop_filter: Arc<Mutex<Option<DropFilter>>>,
+    stalled: Arc<AtomicBool>,
+    // Addrs whose sends fail, like sockets of a network that went away
+    broken: Arc<Mutex<HashSet<SocketAddr>>>
+}
+
+impl MemoryNetwork {
+    pub fn new() -> MemoryNetwork {
+        MemoryNetwork::default()
+    }
+
+    pub fn bind(&self, addr: SocketAddr) -> MemoryTransport {
+        let (sx, rx) = mpsc::unbounded_channel();
+        self.endpoints.lock().unwrap().insert(addr, sx);
+        MemoryTransport {
+            addr, network: self.clone(), inbox: tokio::sync::Mutex::new(rx)
+        }
+    }
+
+    // Datagrams for which the filter (from, to) returns true are dropped
+    pub fn drop_when(&self, filter: impl FnMut(SocketAddr, SocketAddr) -> bool + Send + 'static) {
+        *self.drop_filter.lock().unwrap() = Some(Box::new(filter));
+    }
+
+    // While stalled, sends on this network never complete
+    pub fn stall_sends(&self, stalled: bool) {
+        self.stalled.store(stalled, Ordering::Relaxed);
+    }
+
+    // Sends of the transport bound to addr fail from now on
+    pub fn break_sends(&self, addr: SocketAddr) {
+        self.broken.lock().unwrap().insert(addr);
+    }
+
+    fn deliver(&self, payload: &[u8], from: SocketAddr, to: SocketAddr) {
+        if let Some(filter) = self.drop_filter.lock().unwrap().as_mut() {
+            if filter(from, to) {
+                return
+            }
+        }
+        if let Some(endpoint) = self.endpoints.lock().unwrap().get(&to) {
+            let _ = endpoint.send((payload.to_vec(), from));
+        }
+    }
+}
+
+pub struct MemoryTransport {
+    addr: SocketAddr,
+    network: MemoryNetwork,
+    inbox: tokio::sync::Mutex<mpsc::UnboundedReceiver<Datagram>>
+}
+
+impl Transport for MemoryTransport {
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        if self.network.stalled.load(Ordering::Relaxed) {
+            std::future::pending::<()>().await;
+        }
+        if self.network.broken.lock().unwrap().contains(&self.addr) {
+            return Err(io::Error::from(io::ErrorKind::NotConnected))
+        }
+        self.network.deliver(buf, self.addr, addr);
+        Ok(buf.len())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (payload, from) = self.inbox.lock().await.recv().await
+            .ok_or(io::Error::from(io::ErrorKind::NotConnected))?;
+        let size = payload.len().min(buf.len());
+        buf[..size].copy_from_slice(&payload[..size]);
+        Ok((size, from))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+pub struct WorkStationSender<T: Transport> {
     running: Arc<AtomicBool>,
-    sock: Arc<UdpSocket>,
-    send_queue: Rx<QueuedPacket>
+    sock: Arc<T>,
+    timeouts: AMx<Timeouts>,
+    wire_dump: AMx<Option<WireDump>>,
+    send_queue: Rx<OutgoingPacket>,
+    send_errors: Sx<TokenRingError>,
+    // Packets queued but not yet sent (or given up on). Incremented by the
+    // station before queuing, see flush_sends.
+    in_flight: Arc<AtomicUsize>
 }
 
-impl WorkStationSender {
-    pub fn new(running: Arc<AtomicBool>, sock: Arc<UdpSocket>, send_queue: Rx<QueuedPacket>)
-        -> Self {
+impl<T: Transport> WorkStationSender<T> {
+    pub fn new(running: Arc<AtomicBool>, sock: Arc<T>, timeouts: AMx<Timeouts>,
+        wire_dump: AMx<Option<WireDump>>, send_queue: Rx<OutgoingPacket>,
+        send_errors: Sx<TokenRingError>, in_flight: Arc<AtomicUsize>) -> Self {
         Self {
-            running, sock, send_queue
+            running, sock, timeouts, wire_dump, send_queue, send_errors, in_flight
+        }
+    }
+
+    async fn send_packet(&self, next_packet: OutgoingPacket, payload: &mut Vec<u8>) {
+        // Catch next packet to be sent from main thread and serialize
+        let (content, dest) = match self.encode(next_packet, payload) {
+            Ok(packet) => packet,
+            Err(e) => {
+                println!("Send queue encountered serialization error: {e}.");
+                return
+            }
+        };
+        dump_wire(&self.wire_dump, "send to", dest, &content, payload);
+
+        // Send packet
+        let send_timeout = self.timeouts.lock().unwrap().send;
+        match tokio::time::timeout(send_timeout, self.sock.send_to(
+            payload.as_slice(), dest)).await {
+            Ok(Ok(size)) => println!("[Send to {:?}] {:?} packet ({size}b).",
+                dest,
+                content),
+            Ok(Err(e)) => println!("Socket failed to send: {e}."),
+            Err(_) => {
+                println!("Send to {:?} timed out after {:?}.", dest, send_timeout);
+                let _ = self.send_errors.send(TokenRingError::SendTimeout(dest));
+            }
         }
     }
+
+    // Encodes into payload (cleared first). Unsigned headers of binary packets
+    // are signed in place (see Packet::sign_into), others are signed first.
+    fn encode(&self, next_packet: OutgoingPacket, payload: &mut Vec<u8>) -> TResult<(PacketType, SocketAddr)> {
+        let format = self.sock.wire_format();
+        if let (OutgoingHeader::Unsigned { keypair, source }, WireFormat::Binary) = (&next_packet.header, format) {
+            Packet::sign_into(keypair, source, &next_packet.content, payload)?;
+            return Ok((next_packet.content, next_packet.dest))
+        }
+        let QueuedPacket(packet, dest) = next_packet.sign()?;
+        format.encode(&packet, payload)?;
+        Ok((packet.content, dest))
+    }
+}
+
+// Space separated hex bytes, cut after MAX_WIRE_DUMP_LEN with an ellipsis
+pub fn hex_dump(bytes: &[u8]) -> String {
+    let mut dump = bytes.iter().take(MAX_WIRE_DUMP_LEN)
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if bytes.len() > MAX_WIRE_DUMP_LEN {
+        dump.push_str(&format!(" ... ({} bytes total)", bytes.len()));
+    }
+    dump
 }
 
-pub fn send_loop(sender: WorkStationSender) -> TResult {
-    tokio::spawn(async move {
+fn dump_wire(wire_dump: &AMx<Option<WireDump>>, direction: &str, addr: SocketAddr, content: &PacketType, bytes: &[u8]) {
+    if let Some(dump) = wire_dump.lock().unwrap().as_ref() {
+        dump(&format!("[Wire {direction} {addr:?}] {:?} packet ({}b): {}",
+            content, bytes.len(), hex_dump(bytes)));
+    }
+}
+
+// Counts the packet in in_flight (see flush_sends) and queues it for the send loop
+pub fn queue_packet(send_queue: &Sender<OutgoingPacket>, in_flight: &AtomicUsize, packet: OutgoingPacket) -> TResult {
+    in_flight.fetch_add(1, Ordering::Relaxed);
+    send_queue.send(packet).map_err(|e| {
+        // Send loop is gone, the packet never leaves
+        in_flight.fetch_sub(1, Ordering::Relaxed);
+        GlobalError::from(e)
+    })
+}
+
+// Resolves once every packet counted in in_flight left the send loop. Every
+// send is bounded by send_timeout, so gives up (returns false) if the loop
+// made no progress for twice as long or is stopping.
+pub async fn flush_sends(in_flight: &AtomicUsize, running: &AtomicBool, send_timeout: Duration) -> bool {
+    let (mut left, mut last_progress) = (in_flight.load(Ordering::Relaxed), Instant::now());
+    while left > 0 {
+        if !running.load(Ordering::Relaxed) || last_progress.elapsed() > send_timeout.saturating_mul(2) {
+            println!("Gave up flushing with {left} packets left in the send queue.");
+            return false
+        }
+        tokio::time::sleep(SEND_POLL_INTERVAL).await;
+        let now_left = in_flight.load(Ordering::Relaxed);
+        if now_left < left {
+            last_progress = Instant::now();
+        }
+        left = now_left;
+    }
+    true
+}
+
+pub fn send_loop<T: Transport>(sender: WorkStationSender<T>) -> TResult<JoinHandle<()>> {
+    Ok(tokio::spawn(async move {
+        // Reused for every packet to avoid an allocation per send
+        let mut payload = Vec::with_capacity(RECV_BUF_LENGTH);
         loop  {
             while let Ok(next_packet) = sender.send_queue.try_recv() {
-                // Catch next packet to be sent from main thread and serialize
-                let payload = match next_packet.0.serialize() {
-                    Ok(payload) => payload,
-                    Err(e) =>  {
-                        println!("Send queue encountered serialization error: {e}.");
-                        continue
-                    },
-                };
-
-                // Send packet
-                match sender.sock.send_to(
-                    payload.as_slice(), next_packet.1).await {
-                    Ok(size) => println!("[Send to {:?}] {:?} packet ({size}b).",
-                        next_packet.1,
-                        next_packet.0.content),
-                    Err(e) => {
-                        println!("Socket failed to send: {e}.");
-                        continue
-                    },
-                }
+                sender.send_packet(next_packet, &mut payload).await;
+                sender.in_flight.fetch_sub(1, Ordering::Relaxed);
             }
 
             if !sender.running.load(Ordering::Relaxed) {
                 break
             }
+            tokio::time::sleep(SEND_POLL_INTERVAL).await;
         }
 
         println!("Send loop stopped.")
-    });
-    Ok(())
+    }))
 }
 
-pub struct WorkStationReceiver {
+pub struct WorkStationReceiver<T: Transport> {
     running: Arc<AtomicBool>,
-    sock: Arc<UdpSocket>,
+    // Wakes the loop up when the station stops, instead of waiting for the
+    // next datagram (see recv_loop)
+    stop: Arc<Notify>,
+    sock: Arc<T>,
+    timeouts: AMx<Timeouts>,
+    wire_dump: AMx<Option<WireDump>>,
     recv_queue: Sx<QueuedPacket>
 }
 
-impl WorkStationReceiver {
-    pub fn new(running: Arc<AtomicBool>, sock: Arc<UdpSocket>, recv_queue: Sx<QueuedPacket>) -> Self {
+impl<T: Transport> WorkStationReceiver<T> {
+    pub fn new(running: Arc<AtomicBool>, stop: Arc<Notify>, sock: Arc<T>, timeouts: AMx<Timeouts>,
+        wire_dump: AMx<Option<WireDump>>, recv_queue: Sx<QueuedPacket>) -> Self {
         Self {
-            running, sock, recv_queue
+            running, stop, sock, timeouts, wire_dump, recv_queue
         }
     }
 }
 
-pub fn recv_loop(recv: WorkStationReceiver) -> TResult {
-    let handle = tokio::spawn(async move {
+// The loop holds on to the socket until it exits, so a stopping station
+// notifies stop to release the socket right away
+pub fn recv_loop<T: Transport>(recv: WorkStationReceiver<T>) -> TResult<JoinHandle<()>> {
+    Ok(tokio::spawn(async move {
         let mut buf = [0u8; RECV_BUF_LENGTH];
         loop {
-            // Readability condition required?
-            if let Err(e) = recv.sock.readable().await {
-                println!("Pending read returned error: {e}.");
-                continue
-            }
-
             // Receive new bytes
-            let (size, addr) = match recv.sock.try_recv_from(&mut buf) {
-                Ok(data) => data,
-                Err(e) => {
-                    match e.kind() {
-                        std::io::ErrorKind::WouldBlock => (),
-                        _ => println!("Failed to read from socket: {e}."),
+            let recv_timeout = recv.timeouts.lock().unwrap().recv;
+            let received = tokio::select! {
+                _ = recv.stop.notified() => break,
+                received = tokio::time::timeout(recv_timeout, recv.sock.recv_from(&mut buf)) => received
+            };
+            let (size, addr) = match received {
+                Ok(Ok(data)) => data,
+                Ok(Err(e)) => {
+                    println!("Failed to read from socket: {e}.");
+                    if !recv.running.load(Ordering::Relaxed) {
+                        break
                     }
+                    tokio::time::sleep(RECV_ERROR_BACKOFF).await;
                     continue
                 },
+                Err(_) => {
+                    println!("Warning: Nothing received for {:?}.", recv_timeout);
+                    if !recv.running.load(Ordering::Relaxed) {
+                        break
+                    }
+                    continue
+                }
             };
 
             // Slice received bytes from buffer and deserialize
             let recv_buf = &buf[0..size];
-            let packet = match Packet::deserialize(recv_buf) {
+            let packet = match recv.sock.wire_format().decode(recv_buf) {
                 Ok(p) => p,
+                // Not a protocol datagram; drop without logging
+                Err(GlobalError::Internal(TokenRingError::BadMagic)) => continue,
                 Err(e) => {
                     println!("Receive queue encountered deserialization error: {e}.");
                     continue
                 },
             };
             
+            dump_wire(&recv.wire_dump, "recv from", addr, &packet.content, recv_buf);
             // Pass to main thread
             println!("[Recv from {:?}{:?}] {:?} packet ({size}b).",
                 packet.header.val.source, addr, packet.content);
@@ -120,6 +526,5 @@ pub fn recv_loop(recv: WorkStationReceiver) -> TResult {
             }
         }
         println!("Recv loop stopped.")
-    });
-    Ok(())
+    }))
 }
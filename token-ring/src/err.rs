@@ -3,7 +3,7 @@ use std::{error::Error, net::SocketAddr};
 use crossbeam_channel::{SendError, RecvError};
 use ed25519_dalek::SignatureError;
 
-use crate::{comm::QueuedPacket, id::WorkStationId, token::Token};
+use crate::{comm::OutgoingPacket, id::WorkStationId, token::Token, packet::DenyReason};
 
 pub type TResult<T = ()> = Result<T, GlobalError>;
 
@@ -11,8 +11,10 @@ pub enum GlobalError {
     Internal(TokenRingError),
     Io(std::io::Error),
     Signature(SignatureError),
-    CrossbeamSend(SendError<QueuedPacket>),
+    CrossbeamSend(SendError<OutgoingPacket>),
     CrossbeamRecv(RecvError),
+    #[cfg(feature = "serde")]
+    Json(serde_json::Error),
     Unknown
 }
 
@@ -24,6 +26,8 @@ impl fmt::Debug for GlobalError {
             GlobalError::Signature(err) => write!(f, "{err}"),
             GlobalError::CrossbeamSend(err) => write!(f, "{err}"),
             GlobalError::CrossbeamRecv(err) => write!(f, "{err}"),
+            #[cfg(feature = "serde")]
+            GlobalError::Json(err) => write!(f, "{err}"),
             GlobalError::Unknown => write!(f, "Unknown error occured!"),
         }
     }
@@ -53,8 +57,8 @@ impl From<SignatureError> for GlobalError {
     }
 }
 
-impl From<SendError<QueuedPacket>> for GlobalError {
-    fn from(value: SendError<QueuedPacket>) -> Self {
+impl From<SendError<OutgoingPacket>> for GlobalError {
+    fn from(value: SendError<OutgoingPacket>) -> Self {
         GlobalError::CrossbeamSend(value)
     }
 }
@@ -65,22 +69,62 @@ impl From<RecvError> for GlobalError {
     }
 }
 
+#[cfg(feature = "serde")]
+impl From<serde_json::Error> for GlobalError {
+    fn from(value: serde_json::Error) -> Self {
+        GlobalError::Json(value)
+    }
+}
+
 // ---
 
 #[derive(Debug, Clone)]
 pub enum TokenRingError {
     InvalidPacketHeader,
     NotConnected,
+    ObserverReadOnly,
     AlreadyConnected,
     StationNotRegistered(WorkStationId, SocketAddr),
+    // Packet type the receiving station's role never handles (see PacketType::is_valid_for)
+    UnexpectedPacket(WorkStationId, SocketAddr),
     InvalidSignature,
+    BadMagic,
+    KeyMismatch(WorkStationId),
+    Banned(WorkStationId, SocketAddr),
     InvalidToken(WorkStationId, Token),
-    RejectedJoinAttempt(WorkStationId, String),
-    FailedJoinAttempt(String),
+    RejectedJoinAttempt(WorkStationId, DenyReason),
+    FailedJoinAttempt(DenyReason),
+    JoinTimedOut(SocketAddr),
+    JoinNonceMismatch,
+    // Peers did not acknowledge a KeyRotation, the current key stays in use
+    KeyRotationTimedOut,
+    SendTimeout(SocketAddr),
+    SendQueueFull,
+    InvalidConfig(&'static str),
+    LengthTooLarge { len: usize, max: usize },
+    InvalidCompression,
+    FrameTooLarge { index: usize, size: usize, max: usize },
+    TooManyFrames { index: usize, max: usize },
+    SelfAddressed { index: usize },
     InvalidWorkStationId(WorkStationId, WorkStationId),
     InvalidSocketAddress(SocketAddr),
+    InvalidAddressFamily(u8),
+    InvalidIdVersion(u8),
+    // Unknown enum variant tag while decoding (kind names the decoded type)
+    InvalidTag { kind: &'static str, tag: u8 },
+    InvalidUtf8,
+    // Subsecond nanos of a decoded duration of a second or more
+    InvalidDuration { nanos: u32 },
+    UnexpectedEof { expected: usize, got: usize },
     EmptyRing,
+    InvalidRotationOrder,
     TokenPending,
+    RotationPaused,
+    QuorumNotMet { members: usize, min: usize },
+    TokenReplay(WorkStationId),
+    UnexpectedTokenSender(WorkStationId),
+    StaleToken { pass: u64, current: u64 },
+    UnexpectedFrameType,
     Unknown
 }
 
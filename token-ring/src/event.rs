@@ -1,9 +1,20 @@
-use crate::{id::WorkStationId, packet::JoinAnswerResult};
+use std::{any::Any, net::SocketAddr};
+use crate::{id::WorkStationId, packet::JoinAnswerResult, token::PresenceStatus};
 
-pub trait Event {
+pub trait Event: Any {
     fn source(&self) -> &WorkStationId;
 }
 
+impl dyn Event {
+    pub fn is<T: Event>(&self) -> bool {
+        (self as &dyn Any).is::<T>()
+    }
+
+    pub fn downcast_ref<T: Event>(&self) -> Option<&T> {
+        (self as &dyn Any).downcast_ref::<T>()
+    }
+}
+
 pub struct JoinAnswerEvent {
     pub source: WorkStationId,
     pub result: JoinAnswerResult
@@ -14,3 +25,150 @@ impl Event for JoinAnswerEvent {
         &self.source
     }
 }
+
+// Local station received the token (source: active station)
+pub struct TokenAcquiredEvent {
+    pub source: WorkStationId
+}
+
+impl Event for TokenAcquiredEvent {
+    fn source(&self) -> &WorkStationId {
+        &self.source
+    }
+}
+
+// Token arrived while the local station still held one. The new token
+// replaced the old one, own frames of the old one were moved over
+// (source: active station)
+pub struct TokenConflictEvent {
+    pub source: WorkStationId,
+    pub moved_frames: usize
+}
+
+impl Event for TokenConflictEvent {
+    fn source(&self) -> &WorkStationId {
+        &self.source
+    }
+}
+
+// Local station passed the token on (source: local station)
+pub struct TokenReleasedEvent {
+    pub source: WorkStationId
+}
+
+impl Event for TokenReleasedEvent {
+    fn source(&self) -> &WorkStationId {
+        &self.source
+    }
+}
+
+// Unicast frame was resent until the retry limit without being acked
+// (source: local station)
+pub struct DeliveryFailedEvent {
+    pub source: WorkStationId,
+    pub dest: WorkStationId,
+    pub seq: u16
+}
+
+impl Event for DeliveryFailedEvent {
+    fn source(&self) -> &WorkStationId {
+        &self.source
+    }
+}
+
+// New station joined the ring (members: ring size after join)
+pub struct StationJoinedEvent {
+    pub source: WorkStationId,
+    pub members: usize
+}
+
+impl Event for StationJoinedEvent {
+    fn source(&self) -> &WorkStationId {
+        &self.source
+    }
+}
+
+// Station left or was removed from the ring (members: ring size after leave)
+pub struct StationLeftEvent {
+    pub source: WorkStationId,
+    pub members: usize
+}
+
+impl Event for StationLeftEvent {
+    fn source(&self) -> &WorkStationId {
+        &self.source
+    }
+}
+
+// Presence frame of another station found in the received token
+pub struct PresenceEvent {
+    pub source: WorkStationId,
+    pub status: PresenceStatus
+}
+
+impl Event for PresenceEvent {
+    fn source(&self) -> &WorkStationId {
+        &self.source
+    }
+}
+
+// Data frame of another station addressed to the local station. Surfaced once
+// per (source, seq), although frames stay in the token for several rotations.
+pub struct DataEvent {
+    pub source: WorkStationId,
+    pub seq: u16,
+    pub payload: Vec<u8>
+}
+
+impl Event for DataEvent {
+    fn source(&self) -> &WorkStationId {
+        &self.source
+    }
+}
+
+// App frame of another station, decoded by the decoder registered for its type
+pub struct AppFrameEvent {
+    pub source: WorkStationId,
+    pub type_id: u16,
+    pub payload: Vec<u8>,
+    pub decoded: Box<dyn Any>
+}
+
+impl AppFrameEvent {
+    pub fn decoded_ref<T: Any>(&self) -> Option<&T> {
+        self.decoded.downcast_ref::<T>()
+    }
+}
+
+impl Event for AppFrameEvent {
+    fn source(&self) -> &WorkStationId {
+        &self.source
+    }
+}
+
+// Validly signed ring traffic from another active station than the one the
+// local station joined, e.g. two active stations hosting the same ring
+// (source: unexpected active station)
+pub struct SplitBrainDetectedEvent {
+    pub expected: WorkStationId,
+    pub seen: WorkStationId,
+    pub addr: SocketAddr
+}
+
+impl Event for SplitBrainDetectedEvent {
+    fn source(&self) -> &WorkStationId {
+        &self.seen
+    }
+}
+
+// Active station removed the local station from the ring (source: active station)
+pub struct KickedEvent {
+    pub source: WorkStationId,
+    pub reason: String
+}
+
+impl Event for KickedEvent {
+    fn source(&self) -> &WorkStationId {
+        &self.source
+    }
+}
@@ -1,19 +1,37 @@
 use core::fmt;
-use std::{io::Cursor, time::SystemTime};
-use byteorder::{WriteBytesExt, BigEndian, ReadBytesExt};
+use std::io::{Cursor, Read};
+use byteorder::{WriteBytesExt, ReadBytesExt};
 
-use crate::{serialize::{Serializable, write_string, read_string}, err::TResult};
+use crate::{serialize::Serializable, err::{TResult, GlobalError, TokenRingError}};
 
-#[derive(Clone, PartialEq, Eq, Hash)]
+// v1 IDs: up to 8 ASCII chars. v2 IDs: up to 32 bytes of UTF-8.
+pub const MAX_V1_ID_LEN: usize = 8;
+pub const MAX_ID_LEN: usize = 32;
+
+/* ID Layout
+    v1: | 0 (1b) | Length (1b) | ASCII name |
+    v2: | 2 (1b) | Length (1b) | UTF-8 name |
+
+    v1 is byte-identical to the legacy encoding (u16 length prefix and
+    name), as the high length byte is always 0 for names of max 8 chars.
+ */
+const ID_V1: u8 = 0;
+const ID_V2: u8 = 2;
+
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct WorkStationId {
-    // Max size 8 chars
+    // Max size 32 bytes (8 chars if ASCII-only and v1 compatible)
     name: String
 }
 
 impl WorkStationId {
     pub fn new(mut name: String) -> WorkStationId {
-        if name.len() > 8 {
-            name.truncate(8);
+        if name.len() > MAX_ID_LEN {
+            let mut len = MAX_ID_LEN;
+            while !name.is_char_boundary(len) {
+                len -= 1;
+            }
+            name.truncate(len);
         }
         // let num = SystemTime::now()
         //     .duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as u16;
@@ -22,18 +40,42 @@ impl WorkStationId {
             name
         }
     }
+
+    // Short ASCII names keep using the legacy (v1) encoding
+    fn version(&self) -> u8 {
+        if self.name.is_ascii() && self.name.len() <= MAX_V1_ID_LEN {
+            ID_V1
+        } else {
+            ID_V2
+        }
+    }
 }
 
 impl Serializable for WorkStationId {
     type Output = WorkStationId;
 
     fn write(&self, buf: &mut Vec<u8>) -> TResult {
-        write_string(buf, &self.name)
+        buf.write_u8(self.version())?;
+        buf.write_u8(self.name.len() as u8)?;
+        buf.extend_from_slice(self.name.as_bytes());
+        Ok(())
         //Ok(buf.write_u16::<BigEndian>(self.num)?)
     }
 
     fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
-        let name = read_string(buf)?;
+        let version = buf.read_u8()?;
+        if version != ID_V1 && version != ID_V2 {
+            return Err(GlobalError::Internal(TokenRingError::InvalidIdVersion(version)))
+        }
+        let len = buf.read_u8()? as usize;
+        let max = if version == ID_V1 { MAX_V1_ID_LEN } else { MAX_ID_LEN };
+        if len > max {
+            return Err(GlobalError::Internal(TokenRingError::LengthTooLarge { len, max }))
+        }
+        let mut bytes = vec![0u8; len];
+        buf.read_exact(&mut bytes)?;
+        let name = String::from_utf8(bytes).map_err(
+            |_| GlobalError::Internal(TokenRingError::InvalidUtf8))?;
         //let num = buf.read_u16::<BigEndian>()?;
         Ok(WorkStationId {
             name
@@ -41,7 +83,22 @@ impl Serializable for WorkStationId {
     }
 
     fn size(&self) -> usize {
-        self.name.len() // Assumes ASCII
+        2 + self.name.len()
+    }
+}
+
+// Plain string; deserialized names are truncated like in WorkStationId::new
+#[cfg(feature = "serde")]
+impl serde::Serialize for WorkStationId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for WorkStationId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(WorkStationId::new)
     }
 }
 
@@ -56,3 +113,57 @@ impl fmt::Display for WorkStationId {
         write!(f, "{}", self.name)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use crate::{serialize::{Serializable, write_string}, err::{GlobalError, TokenRingError}};
+    use super::{WorkStationId, MAX_V1_ID_LEN, MAX_ID_LEN};
+
+    fn round_trip(id: &WorkStationId) -> Vec<u8> {
+        let mut buf = vec![];
+        id.write(&mut buf).unwrap();
+        assert_eq!(buf.len(), id.size());
+        assert_eq!(&WorkStationId::read(&mut Cursor::new(buf.as_slice())).unwrap(), id);
+        buf
+    }
+
+    #[test]
+    fn v1_id() {
+        let id = WorkStationId::new("Alice".to_owned());
+        let buf = round_trip(&id);
+        // Still readable by peers using the legacy string encoding
+        let mut legacy = vec![];
+        write_string(&mut legacy, &"Alice".to_owned()).unwrap();
+        assert_eq!(buf, legacy);
+    }
+
+    #[test]
+    fn v2_id() {
+        let id = WorkStationId::new("Jürgen der Große".to_owned());
+        let buf = round_trip(&id);
+        assert_eq!(buf[0], 2);
+        assert_eq!(id.to_string(), "Jürgen der Große");
+    }
+
+    #[test]
+    fn id_length_bound() {
+        round_trip(&WorkStationId::new("x".repeat(MAX_V1_ID_LEN)));
+        round_trip(&WorkStationId::new("x".repeat(MAX_ID_LEN)));
+        for (version, max) in [(0u8, MAX_V1_ID_LEN), (2, MAX_ID_LEN)] {
+            let mut buf = vec![version, max as u8 + 1];
+            buf.extend(vec![b'x'; max + 1]);
+            match WorkStationId::read(&mut Cursor::new(buf.as_slice())) {
+                Err(GlobalError::Internal(TokenRingError::LengthTooLarge { len, max: m })) =>
+                    assert_eq!((len, m), (max + 1, max)),
+                r => panic!("Expected length too large, got {:?}.", r)
+            }
+        }
+    }
+
+    #[test]
+    fn truncate_at_char_boundary() {
+        let id = WorkStationId::new("ö".repeat(20));
+        assert_eq!(id.to_string(), "ö".repeat(16));
+    }
+}
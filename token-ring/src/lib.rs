@@ -8,7 +8,11 @@ pub mod comm;
 pub mod event;
 pub mod station;
 pub mod pass;
+pub mod snapshot;
+pub mod metrics;
 pub mod util;
+#[cfg(test)]
+mod proptests;
 
 pub fn add(left: usize, right: usize) -> usize {
     left + right
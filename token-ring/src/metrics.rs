@@ -0,0 +1,18 @@
+use std::time::Duration;
+
+// Ring-wide health counters of an active station, e.g. for periodic logging
+// or exporting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RingMetrics {
+    pub members: usize,
+    pub tokens_passed: u64,
+    // None until the first rotation completed
+    pub avg_rotation_time: Option<Duration>,
+    // Stations whose last turn ended in a timeout
+    pub timed_out_stations: usize,
+    // Encoded size of all passed tokens
+    pub bytes_relayed: u64,
+    // Mean Token::overhead_ratio of the last OVERHEAD_WINDOW_LEN passed tokens
+    // in per mille (rounded, 1000 for tokens without payload), None until the first pass
+    pub avg_token_overhead_permille: Option<u16>
+}
@@ -1,9 +1,12 @@
-use std::{io::Cursor};
-use byteorder::{WriteBytesExt, ReadBytesExt};
-use crate::{token::Token, id::WorkStationId, serialize::{Serializable, write_byte_vec, read_byte_vec, Serializer, write_string, read_string}, err::TResult, signature::Signed};
+use std::{io::Cursor, time::Duration};
+use ed25519_dalek::{Keypair, PublicKey, PUBLIC_KEY_LENGTH};
+use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
+use crate::{token::Token, id::WorkStationId, serialize::{Serializable, Serializer, write_string, read_string_max, read_byte_arr, write_byte_arr, write_byte_vec, read_byte_vec, remaining}, err::{TResult, GlobalError, TokenRingError}, signature::{Signed, write_signed}};
 
 /* Packet Layout (in bytes)
-    ---------------------------------------------  
+    ---------------------------------------------
+    |           Magic (4b)                      |
+    |-------------------------------------------|
     |           Public Key (32b)                | \
     |-------------------------------------------|  |
     |           Signature (64b)                 |  |
@@ -18,7 +21,17 @@ use crate::{token::Token, id::WorkStationId, serialize::{Serializable, write_byt
     ---------------------------------------------
  */
 
+// Prefix of every packet. Datagrams without it are not part of the protocol.
+pub const PACKET_MAGIC: [u8; 4] = *b"TRNG";
+// Longest join password accepted on the wire. Longer ones are rejected before allocation.
+pub const MAX_PASSWORD_LEN: usize = 256;
+// Longest kick reason accepted on the wire
+pub const MAX_REASON_LEN: usize = 256;
+// Longest display name accepted on the wire (see JoinMetadata)
+pub const MAX_DISPLAY_NAME_LEN: usize = 64;
+
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PacketHeader {
     pub source: WorkStationId,
     //pub destination: WorkStationId
@@ -52,6 +65,7 @@ impl Serializable for PacketHeader {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Packet {
     pub header: Signed<PacketHeader>,
     pub content: PacketType
@@ -63,45 +77,52 @@ impl Packet {
             header, content
         }
     }
+
+    // Writes the same bytes as Packet::new(Signed::new(keypair, PacketHeader::new(source))?, content)
+    // serialized into buf, but signs the header where it is written. Nothing is
+    // allocated once buf has grown to fit, so the send loop reuses one buffer.
+    pub fn sign_into(keypair: &Keypair, source: &WorkStationId, content: &PacketType, buf: &mut Vec<u8>) -> TResult {
+        buf.clear();
+        write_byte_arr(buf, &PACKET_MAGIC)?;
+        // PacketHeader is just the source
+        write_signed(keypair, buf, |buf| source.write(buf))?;
+        content.write(buf)
+    }
 }
 
 impl Serializable for Packet {
     type Output = Packet;
     
     fn write(&self, buf: &mut Vec<u8>) -> TResult {
+        write_byte_arr(buf, &PACKET_MAGIC)?;
         self.header.write(buf)?;
         self.content.write(buf)
     }
 
     fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
+        // Check magic before parsing anything else
+        match read_byte_arr::<4>(buf) {
+            Ok(magic) if magic == PACKET_MAGIC => (),
+            _ => return Err(GlobalError::Internal(TokenRingError::BadMagic))
+        }
         let header = Signed::read(buf)?;
         let content = PacketType::read(buf)?;
         Ok(Packet::new(header, content))
     }
 
     fn size(&self) -> usize {
-        self.header.size() + self.content.size()
+        PACKET_MAGIC.len() + self.header.size() + self.content.size()
     }
 }
 
 impl Serializer for Packet {
-    fn serialize(&self) -> TResult<Vec<u8>> {
-        let mut buf = vec![];
-        self.write(&mut buf)?;
-        Ok(buf)
-    }
-
-    fn deserialize(buf: &[u8]) -> TResult<Self::Output> {
-        let mut cursor = Cursor::new(buf);
-        let packet = Self::read(&mut cursor)?;
-        Ok(packet)
-    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum JoinAnswerResult {
     Confirm(WorkStationId),
-    Deny(String)
+    Deny(DenyReason)
 }
 
 impl Serializable for JoinAnswerResult {
@@ -115,7 +136,7 @@ impl Serializable for JoinAnswerResult {
             },
             JoinAnswerResult::Deny(reason) => {
                 buf.write_u8(1)?;
-                write_byte_vec(buf, &reason.as_bytes().to_vec())
+                reason.write(buf)
             },
         }?)
     }
@@ -123,25 +144,245 @@ impl Serializable for JoinAnswerResult {
     fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
         Ok(match buf.read_u8()? {
             0 => JoinAnswerResult::Confirm(WorkStationId::read(buf)?),
-            1 => JoinAnswerResult::Deny(String::from_utf8(read_byte_vec(buf)?).unwrap()),
-            n @ _ => panic!("Index out of bounds: {n}.")
+            1 => JoinAnswerResult::Deny(DenyReason::read(buf)?),
+            tag => return Err(GlobalError::Internal(TokenRingError::InvalidTag { kind: "JoinAnswerResult", tag }))
         })
     }
 
     fn size(&self) -> usize {
         1 + match self {
             JoinAnswerResult::Confirm(id) => id.size(),
-            JoinAnswerResult::Deny(reason) => reason.len(),
+            JoinAnswerResult::Deny(reason) => reason.size(),
         }
     }
 }
 
+// Why an active station refused a join request
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DenyReason {
+    AlreadyJoined,
+    ConnectionsBlocked,
+    RingFull(u16 /* Max connections */),
+    WrongPassword,
+    IdInUse,
+    KeyNotAuthorized
+}
+
+impl Serializable for DenyReason {
+    type Output = DenyReason;
+
+    fn write(&self, buf: &mut Vec<u8>) -> TResult {
+        Ok(match self {
+            DenyReason::AlreadyJoined => buf.write_u8(0)?,
+            DenyReason::ConnectionsBlocked => buf.write_u8(1)?,
+            DenyReason::RingFull(max) => {
+                buf.write_u8(2)?;
+                buf.write_u16::<BigEndian>(*max)?
+            },
+            DenyReason::WrongPassword => buf.write_u8(3)?,
+            DenyReason::IdInUse => buf.write_u8(4)?,
+            DenyReason::KeyNotAuthorized => buf.write_u8(5)?
+        })
+    }
+
+    fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
+        Ok(match buf.read_u8()? {
+            0 => DenyReason::AlreadyJoined,
+            1 => DenyReason::ConnectionsBlocked,
+            2 => DenyReason::RingFull(buf.read_u16::<BigEndian>()?),
+            3 => DenyReason::WrongPassword,
+            4 => DenyReason::IdInUse,
+            5 => DenyReason::KeyNotAuthorized,
+            tag => return Err(GlobalError::Internal(TokenRingError::InvalidTag { kind: "DenyReason", tag }))
+        })
+    }
+
+    fn size(&self) -> usize {
+        1 + match self {
+            DenyReason::RingFull(_) => 2,
+            _ => 0
+        }
+    }
+}
+
+impl std::fmt::Display for DenyReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DenyReason::AlreadyJoined => write!(f, "Already joined"),
+            DenyReason::ConnectionsBlocked => write!(f, "New connections blocked"),
+            DenyReason::RingFull(max) => write!(f, "Max connections reached ({max})"),
+            DenyReason::WrongPassword => write!(f, "Incorrect password"),
+            DenyReason::IdInUse => write!(f, "ID already in use"),
+            DenyReason::KeyNotAuthorized => write!(f, "Key not authorized")
+        }
+    }
+}
+
+// Optional protocol features of a station, appended to its join request.
+// Stations that predate them send nothing, which reads as none.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Capabilities {
+    // Accepts TokenPassCompressed
+    pub compressed_tokens: bool
+}
+
+impl Serializable for Capabilities {
+    type Output = Capabilities;
+
+    fn write(&self, buf: &mut Vec<u8>) -> TResult {
+        Ok(buf.write_u8(self.compressed_tokens as u8)?)
+    }
+
+    fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
+        // Unknown bits are features of newer stations
+        let flags = buf.read_u8()?;
+        Ok(Capabilities {
+            compressed_tokens: flags & 1 != 0
+        })
+    }
+
+    fn size(&self) -> usize {
+        1
+    }
+}
+
+// Describes the joining station to the active station, which keeps it for
+// the membership. Appended after the nonce, missing for older stations.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct JoinMetadata {
+    // Software version of the station, 0 if unknown
+    pub client_version: u32,
+    // Name to show instead of the (short) station ID
+    pub display_name: Option<String>
+}
+
+impl Serializable for JoinMetadata {
+    type Output = JoinMetadata;
+
+    fn write(&self, buf: &mut Vec<u8>) -> TResult {
+        buf.write_u32::<BigEndian>(self.client_version)?;
+        match &self.display_name {
+            Some(name) => {
+                buf.write_u8(1)?;
+                write_string(buf, name)
+            },
+            None => Ok(buf.write_u8(0)?)
+        }
+    }
+
+    fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
+        let client_version = buf.read_u32::<BigEndian>()?;
+        let display_name = match buf.read_u8()? {
+            0 => None,
+            _ => Some(read_string_max(buf, MAX_DISPLAY_NAME_LEN)?)
+        };
+        Ok(JoinMetadata { client_version, display_name })
+    }
+
+    fn size(&self) -> usize {
+        4 + 1 + self.display_name.as_ref().map_or(0, |name| 2 + name.len())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct JoinRequest {
+    pub password: String,
+    // Encoded in the packet type tag
+    pub observer: bool,
+    pub capabilities: Capabilities,
+    // Picked by the joining station and echoed in the reply, so a recorded
+    // reply can not be replayed to a later join attempt
+    pub nonce: u64,
+    pub metadata: JoinMetadata
+}
+
+impl JoinRequest {
+    pub fn new(password: String) -> JoinRequest {
+        JoinRequest {
+            password, observer: false, capabilities: Capabilities::default(), nonce: 0,
+            metadata: JoinMetadata::default()
+        }
+    }
+}
+
+impl Serializable for JoinRequest {
+    type Output = JoinRequest;
+
+    fn write(&self, buf: &mut Vec<u8>) -> TResult {
+        write_string(buf, &self.password)?;
+        self.capabilities.write(buf)?;
+        buf.write_u64::<BigEndian>(self.nonce)?;
+        self.metadata.write(buf)
+    }
+
+    // Fields after the password were added over time and default if missing.
+    // Observer must be set from the tag.
+    fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
+        let password = read_string_max(buf, MAX_PASSWORD_LEN)?;
+        let capabilities = if remaining(buf) > 0 {
+            Capabilities::read(buf)?
+        } else {
+            Capabilities::default()
+        };
+        // Missing nonce (older stations) never matches a reply
+        let nonce = read_nonce(buf)?;
+        let metadata = if remaining(buf) > 0 {
+            JoinMetadata::read(buf)?
+        } else {
+            JoinMetadata::default()
+        };
+        Ok(JoinRequest { password, observer: false, capabilities, nonce, metadata })
+    }
+
+    fn size(&self) -> usize {
+        2 + self.password.len() + self.capabilities.size() + 8 + self.metadata.size()
+    }
+}
+
 #[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PacketType {
-    JoinRequest(String),
-    JoinReply(JoinAnswerResult),
+    JoinRequest(JoinRequest),
+    // Echoes the nonce of the answered request
+    JoinReply(JoinAnswerResult, u64 /* Nonce */),
     TokenPass(Token),
-    Leave()
+    // Token::compress output, only sent to stations with Capabilities::compressed_tokens
+    TokenPassCompressed(Vec<u8>),
+    // Active station removed the receiver from the ring
+    Kicked { reason: String },
+    // Receipt of a token pass, sent right away (see Config::ack_tokens)
+    TokenAck { pass: u64 },
+    Leave(),
+    // Announces the sender's next signing key. Signed with the current key.
+    KeyRotation { new_key: PublicKey },
+    // Receipt of a KeyRotation. The announcing station keeps its current key until then.
+    KeyRotationAck { new_key: PublicKey },
+    // Token holder asks for more time in its current turn (millisecond precision)
+    TokenHoldRequest { extra: Duration }
+}
+
+// Receiving side of a packet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StationRole {
+    Active,
+    Passive
+}
+
+impl PacketType {
+    // Whether a station of the given role may receive this packet at all
+    pub fn is_valid_for(&self, role: StationRole) -> bool {
+        match self {
+            PacketType::TokenPass(_) | PacketType::TokenPassCompressed(_)
+                | PacketType::KeyRotation { .. } | PacketType::KeyRotationAck { .. } => true,
+            PacketType::JoinRequest(_) | PacketType::TokenAck { .. } | PacketType::Leave()
+                | PacketType::TokenHoldRequest { .. } => role == StationRole::Active,
+            PacketType::JoinReply(..) | PacketType::Kicked { .. } => role == StationRole::Passive
+        }
+    }
 }
 
 impl Serializable for PacketType {
@@ -149,13 +390,15 @@ impl Serializable for PacketType {
 
     fn write(&self, buf: &mut Vec<u8>) -> TResult {
         Ok(match self {
-            PacketType::JoinRequest(pw) => {
-                buf.write_u8(0)?;
-                write_string(buf, pw)
+            PacketType::JoinRequest(request) => {
+                // Member joins keep the original layout
+                buf.write_u8(if request.observer { 5 } else { 0 })?;
+                request.write(buf)
             },
-            PacketType::JoinReply(result) => {
+            PacketType::JoinReply(result, nonce) => {
                 buf.write_u8(1)?;
-                result.write(buf)
+                result.write(buf)?;
+                Ok(buf.write_u64::<BigEndian>(*nonce)?)
             },
             PacketType::TokenPass(token) => {
                 buf.write_u8(2)?;
@@ -164,48 +407,109 @@ impl Serializable for PacketType {
             PacketType::Leave() => {
                 buf.write_u8(3)?;
                 Ok(())
+            },
+            PacketType::KeyRotation { new_key } => {
+                buf.write_u8(4)?;
+                write_byte_arr(buf, new_key.as_bytes())
+            },
+            PacketType::TokenPassCompressed(bytes) => {
+                buf.write_u8(6)?;
+                write_byte_vec(buf, bytes)
+            },
+            PacketType::Kicked { reason } => {
+                buf.write_u8(7)?;
+                write_string(buf, reason)
+            },
+            PacketType::TokenAck { pass } => {
+                buf.write_u8(8)?;
+                Ok(buf.write_u64::<BigEndian>(*pass)?)
+            },
+            PacketType::TokenHoldRequest { extra } => {
+                buf.write_u8(9)?;
+                Ok(buf.write_u32::<BigEndian>(extra.as_millis().min(u32::MAX as u128) as u32)?)
+            },
+            PacketType::KeyRotationAck { new_key } => {
+                buf.write_u8(10)?;
+                write_byte_arr(buf, new_key.as_bytes())
             }
         }?)
     }
 
     fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
         Ok(match buf.read_u8()? {
-            0 => {
-                PacketType::JoinRequest(read_string(buf)?)
+            n @ (0 | 5) => {
+                let mut request = JoinRequest::read(buf)?;
+                request.observer = n == 5;
+                PacketType::JoinRequest(request)
+            },
+            1 => {
+                let result = JoinAnswerResult::read(buf)?;
+                PacketType::JoinReply(result, read_nonce(buf)?)
             },
-            1 => PacketType::JoinReply(JoinAnswerResult::read(buf)?),
             2 => PacketType::TokenPass(Token::read(buf)?),
             3 => PacketType::Leave(),
-            n @ _ => panic!("Index out of bounds: {n}.")
+            4 => PacketType::KeyRotation {
+                new_key: PublicKey::from_bytes(&read_byte_arr::<PUBLIC_KEY_LENGTH>(buf)?)?
+            },
+            6 => PacketType::TokenPassCompressed(read_byte_vec(buf)?),
+            7 => PacketType::Kicked { reason: read_string_max(buf, MAX_REASON_LEN)? },
+            8 => PacketType::TokenAck { pass: buf.read_u64::<BigEndian>()? },
+            9 => PacketType::TokenHoldRequest {
+                extra: Duration::from_millis(buf.read_u32::<BigEndian>()? as u64)
+            },
+            10 => PacketType::KeyRotationAck {
+                new_key: PublicKey::from_bytes(&read_byte_arr::<PUBLIC_KEY_LENGTH>(buf)?)?
+            },
+            tag => return Err(GlobalError::Internal(TokenRingError::InvalidTag { kind: "PacketType", tag }))
         })
     }
 
     fn size(&self) -> usize {
         1 + match self {
-            PacketType::JoinRequest(pw) => pw.len(),
-            PacketType::JoinReply(result) => result.size(),
+            PacketType::JoinRequest(request) => request.size(),
+            PacketType::JoinReply(result, _) => result.size() + 8,
             PacketType::TokenPass(token) => token.size(),
-            PacketType::Leave() => 0
+            PacketType::Leave() => 0,
+            PacketType::KeyRotation { .. } | PacketType::KeyRotationAck { .. } => PUBLIC_KEY_LENGTH,
+            PacketType::TokenPassCompressed(bytes) => 2 + bytes.len(),
+            PacketType::Kicked { reason } => 2 + reason.len(),
+            PacketType::TokenAck { .. } => 8,
+            PacketType::TokenHoldRequest { .. } => 4
         }
     }
 }
 
+fn read_nonce(buf: &mut Cursor<&[u8]>) -> TResult<u64> {
+    Ok(if remaining(buf) > 0 {
+        buf.read_u64::<BigEndian>()?
+    } else {
+        0
+    })
+}
+
 impl std::fmt::Debug for PacketType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            PacketType::JoinRequest(request) if request.observer => write!(f, "Observer join request"),
             PacketType::JoinRequest(_) => write!(f, "Join request"),
-            PacketType::JoinReply(result) => write!(f, "Join reply: {:?}.", result),
+            PacketType::JoinReply(result, _) => write!(f, "Join reply: {:?}.", result),
             PacketType::TokenPass(token) => write!(f, "Token pass"),
-            PacketType::Leave() => write!(f, "Leave")
+            PacketType::Leave() => write!(f, "Leave"),
+            PacketType::KeyRotation { .. } => write!(f, "Key rotation"),
+            PacketType::KeyRotationAck { .. } => write!(f, "Key rotation ack"),
+            PacketType::TokenPassCompressed(_) => write!(f, "Compressed token pass"),
+            PacketType::Kicked { reason } => write!(f, "Kicked: {reason}"),
+            PacketType::TokenAck { pass } => write!(f, "Token ack ({pass})"),
+            PacketType::TokenHoldRequest { extra } => write!(f, "Token hold request ({extra:?})")
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::Cursor;
-    use crate::{id::WorkStationId, signature::{generate_keypair, Signed}, serialize::Serializable};
-    use super::{Packet, PacketHeader, JoinAnswerResult, PacketType};
+    use std::{io::Cursor, time::Duration};
+    use crate::{id::WorkStationId, signature::{generate_keypair, Signed}, serialize::{Serializable, Serializer}, err::{GlobalError, TokenRingError}, token::{Token, TokenHeader, TokenFrame, TokenFrameId, TokenFrameType, TokenSendMode}};
+    use super::{Packet, PacketHeader, JoinRequest, JoinMetadata, JoinAnswerResult, PacketType, DenyReason, Capabilities, MAX_PASSWORD_LEN};
 
     fn create_packet() -> Packet {
         let keypair = generate_keypair();
@@ -214,7 +518,7 @@ mod tests {
         let signed_header = Signed::new(&keypair, header).unwrap();
         Packet::new(signed_header, 
             PacketType::JoinReply(JoinAnswerResult::Confirm(
-                WorkStationId::new("Alice".to_owned()))))
+                WorkStationId::new("Alice".to_owned())), 7))
     }
 
     #[test]
@@ -227,4 +531,160 @@ mod tests {
         let new_packet = Packet::read(&mut cursor).unwrap();
         assert_eq!(packet, new_packet)
     }
+
+    #[test]
+    fn serialize_into_reused_buffer() {
+        let mut buf = vec![];
+        for packet in [create_packet(), Packet::new(create_packet().header, PacketType::Leave())] {
+            packet.serialize_into(&mut buf).unwrap();
+            assert_eq!(buf, packet.serialize().unwrap());
+        }
+    }
+
+    #[test]
+    fn sign_into_reused_buffer() {
+        let keypair = generate_keypair();
+        let source = WorkStationId::new("Bob".to_owned());
+        let packet = Packet::new(Signed::new(&keypair, PacketHeader::new(source.clone())).unwrap(), PacketType::Leave());
+        let mut buf = create_packet().serialize().unwrap();
+        Packet::sign_into(&keypair, &source, &PacketType::Leave(), &mut buf).unwrap();
+        assert_eq!(buf, packet.serialize().unwrap());
+    }
+
+    #[test]
+    fn join_request_metadata() {
+        let request = JoinRequest {
+            password: "pw".to_owned(), observer: true, capabilities: Capabilities { compressed_tokens: true },
+            nonce: 42, metadata: JoinMetadata { client_version: 3, display_name: Some("Alice Liddell".to_owned()) }
+        };
+        let content = PacketType::JoinRequest(request.clone());
+        let mut buf = vec![];
+        content.write(&mut buf).unwrap();
+        assert_eq!(buf.len(), content.size());
+        assert_eq!(PacketType::read(&mut Cursor::new(buf.as_slice())).unwrap(), content);
+
+        // Stations that predate the metadata end after the nonce
+        buf.truncate(buf.len() - request.metadata.size());
+        match PacketType::read(&mut Cursor::new(buf.as_slice())).unwrap() {
+            PacketType::JoinRequest(legacy) => {
+                assert_eq!(legacy.metadata, JoinMetadata::default());
+                assert_eq!((legacy.nonce, legacy.observer), (42, true));
+            },
+            p => panic!("Expected join request, got {:?}.", p)
+        }
+    }
+
+    #[test]
+    fn oversized_password() {
+        let content = PacketType::JoinRequest(JoinRequest::new("x".repeat(MAX_PASSWORD_LEN + 1)));
+        let mut buf = vec![];
+        content.write(&mut buf).unwrap();
+        match PacketType::read(&mut Cursor::new(buf.as_slice())) {
+            Err(GlobalError::Internal(TokenRingError::LengthTooLarge { len, max })) =>
+                assert_eq!((len, max), (MAX_PASSWORD_LEN + 1, MAX_PASSWORD_LEN)),
+            r => panic!("Expected length too large, got {:?}.", r)
+        }
+    }
+
+    #[test]
+    fn deny_reasons() {
+        for reason in [DenyReason::AlreadyJoined, DenyReason::ConnectionsBlocked,
+            DenyReason::RingFull(32), DenyReason::WrongPassword, DenyReason::IdInUse] {
+            let result = JoinAnswerResult::Deny(reason);
+            let mut buf = vec![];
+            result.write(&mut buf).unwrap();
+            assert_eq!(buf.len(), result.size());
+            assert_eq!(JoinAnswerResult::read(&mut Cursor::new(buf.as_slice())).unwrap(), result);
+        }
+    }
+
+    #[test]
+    fn unknown_tags() {
+        // Join reply denied for reason 200
+        assert!(matches!(PacketType::read(&mut Cursor::new([1u8, 1, 200].as_slice())),
+            Err(GlobalError::Internal(TokenRingError::InvalidTag { kind: "DenyReason", tag: 200 }))));
+        assert!(matches!(PacketType::read(&mut Cursor::new([1u8, 9].as_slice())),
+            Err(GlobalError::Internal(TokenRingError::InvalidTag { kind: "JoinAnswerResult", tag: 9 }))));
+        assert!(matches!(PacketType::read(&mut Cursor::new([255u8].as_slice())),
+            Err(GlobalError::Internal(TokenRingError::InvalidTag { kind: "PacketType", tag: 255 }))));
+    }
+
+    #[test]
+    fn bad_magic() {
+        let noise = [0x45, 0x00, 0x00, 0x3c, 0x1c, 0x46, 0x40, 0x00, 0x40, 0x06, 0xb1, 0xe6];
+        assert!(matches!(Packet::deserialize(&noise),
+            Err(GlobalError::Internal(TokenRingError::BadMagic))));
+        assert!(matches!(Packet::deserialize(&[]),
+            Err(GlobalError::Internal(TokenRingError::BadMagic))));
+    }
+
+    #[test]
+    fn serialize_reserves_exact_size() {
+        let keypair = generate_keypair();
+        let mut token = Token::new(Signed::new(&keypair,
+            TokenHeader::new(WorkStationId::new("Host".to_owned()), 1)).unwrap());
+        for seq in 0..64 {
+            token.frames.push(TokenFrame::new(TokenFrameId::new(WorkStationId::new("Bob".to_owned())),
+                TokenFrameType::Data { send_mode: TokenSendMode::Broadcast, seq,
+                    payload: vec![seq as u8; 512], expires_at: None }));
+        }
+        let packet = Packet::new(create_packet().header, PacketType::TokenPass(token));
+
+        let mut unreserved = vec![];
+        packet.write(&mut unreserved).unwrap();
+        let buf = packet.serialize().unwrap();
+        assert_eq!(buf, unreserved);
+        // Reserved once with the exact size, never regrown
+        assert_eq!(buf.capacity(), buf.len());
+    }
+
+    // Exact wire size, e.g. for fragmenting to an MTU
+    #[test]
+    fn packet_size() {
+        let keypair = generate_keypair();
+        let token = Token::new(Signed::new(&keypair,
+            TokenHeader::new(WorkStationId::new("Host".to_owned()), 1)).unwrap());
+        let mut request = JoinRequest::new("pw".to_owned());
+        request.metadata.display_name = Some("Bob".to_owned());
+        for content in [PacketType::JoinRequest(request),
+            PacketType::JoinReply(JoinAnswerResult::Deny(DenyReason::RingFull(8)), 7),
+            PacketType::TokenPass(token.clone()), PacketType::TokenPassCompressed(token.compress().unwrap()),
+            PacketType::Kicked { reason: "Spam".to_owned() }, PacketType::TokenAck { pass: 1 },
+            PacketType::Leave(), PacketType::KeyRotation { new_key: keypair.public },
+            PacketType::KeyRotationAck { new_key: keypair.public },
+            PacketType::TokenHoldRequest { extra: Duration::from_millis(250) }] {
+            let packet = Packet::new(create_packet().header, content);
+            assert_eq!(packet.serialize().unwrap().len(), packet.size(), "{:?}", packet.content);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trip() {
+        use crate::{comm::WireFormat, token::PresenceStatus};
+        let keypair = generate_keypair();
+        let mut token = Token::new(Signed::new(&keypair,
+            TokenHeader::new(WorkStationId::new("Host".to_owned()), 1)).unwrap());
+        let mut frame = TokenFrame::new(TokenFrameId::new(WorkStationId::new("Bob".to_owned())),
+            TokenFrameType::Data { send_mode: TokenSendMode::Unicast(WorkStationId::new("Alice".to_owned())),
+                seq: 3, payload: vec![1, 2, 3], expires_at: Some(42) });
+        frame.sign(&keypair).unwrap();
+        token.frames.push(frame);
+        token.frames.push(TokenFrame::new(TokenFrameId::new(WorkStationId::new("Alice".to_owned())),
+            TokenFrameType::Presence { status: PresenceStatus::Away }));
+        let packet = Packet::new(create_packet().header, PacketType::TokenPass(token));
+
+        let (mut json, mut binary) = (vec![], vec![]);
+        WireFormat::Json.encode(&packet, &mut json).unwrap();
+        WireFormat::Binary.encode(&packet, &mut binary).unwrap();
+        let from_json = WireFormat::Json.decode(&json).unwrap();
+        assert_eq!(from_json, WireFormat::Binary.decode(&binary).unwrap());
+        assert_eq!(from_json, packet);
+        // Signatures still verify after the JSON round trip
+        assert!(from_json.header.verify());
+        match from_json.content {
+            PacketType::TokenPass(token) => assert!(token.header.verify() && token.frames[0].verify()),
+            _ => panic!("Expected token pass.")
+        }
+    }
 }
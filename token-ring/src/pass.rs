@@ -1,47 +1,273 @@
-use std::{collections::HashMap, time::Instant};
-use crate::{id::WorkStationId, token::Token, err::{TResult, TokenRingError, GlobalError}};
+use std::{collections::HashMap, time::{Duration, Instant}};
+use crate::{id::WorkStationId, token::Token, err::{TResult, TokenRingError, GlobalError}, util::{Clock, SystemClock}};
 
-pub struct StationStatus(pub bool /* Received token this round? */, /* u32 (Checksum?) */);
+pub struct StationStatus(pub bool /* Received token this round? */, pub u32 /* Consecutive timeouts */, /* u32 (Checksum?) */);
 
 pub enum TokenPassMode {
     Idle, // Token sending paused or not enough stations connected
     Passed, // Token passed to station (waiting for timeout or retrieval)
     Received, // Token has been received by station and can be passed on
+    TimedOut, // Station did not return token in time (can be passed on)
 }
 
 pub struct TokenState(pub WorkStationId /* Sent to */, pub Instant /* Sent when */);
 
+// Handling of tokens returned by another member than the one they were passed to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WrongSenderPolicy {
+    // Discard the token
+    #[default]
+    Strict,
+    // Accept it from any member and treat the sender as the holder (e.g. after
+    // the ring was reconfigured while the token was out)
+    Lenient
+}
+
+// Station that gets the very first token of the ring
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum InitialHolder {
+    // First in rotation order (join order unless reordered)
+    #[default]
+    FirstJoined,
+    // Last in rotation order
+    LastJoined,
+    // Falls back to FirstJoined if the station is not a member
+    Station(WorkStationId)
+}
+
 pub struct TokenPasser {
     pub curr_token: Option<Token>,
     state: Option<TokenState>,
     pass_mode: TokenPassMode,
+    // Token is out with the holder. Set by pass_token, cleared once the holder
+    // returns it or times out, so that a stray token from another member does
+    // not allow a second pass.
+    in_flight: bool,
+    // Current holder confirmed receipt (see PacketType::TokenAck)
+    acked: bool,
+    // Holders that did not ack within this time are assumed to never have
+    // received the token. Off if None (holders that do not ack are not penalized).
+    ack_timeout: Option<Duration>,
     max_passover_time: f32,
-    // List with all connected stations, sets the order in which passive stations
-    // receive token and stores if they were owned one in current rotation.
-    // TODO: Set order of stations! Hash maps are not ordered, hence the token will
-    // be passed randomly between stations.
+    // Extra time granted to the current holder on request (see extend_hold),
+    // up to max_hold_extension per turn
+    hold_extension: Duration,
+    max_hold_extension: Duration,
+    // Least time between two passes, even if the holder returned the token
+    // right away (caps how fast an empty ring spins)
+    min_pass_interval: Duration,
+    // Consecutive timeouts after which a station sits out the next rotation
+    max_timeouts: u32,
+    wrong_sender: WrongSenderPolicy,
+    initial_holder: InitialHolder,
+    clock: Box<dyn Clock>,
+    // List with all connected stations, stores if they were owned one in current rotation.
     pub station_status: HashMap<WorkStationId, StationStatus>,
+    // Order in which passive stations receive the token (join order by default)
+    rotation_order: Vec<WorkStationId>,
+    // Order set during a rotation, applied when the next rotation starts
+    next_order: Option<Vec<WorkStationId>>,
+    rotation_start: Option<Instant>,
+    // Last selected station is the first of a rotation
+    rotation_started: bool,
+    // Completed rotations and their summed duration
+    rotations: u32,
+    rotation_time: Duration,
 }
 
 impl TokenPasser {
     pub fn new(max_passover_time: f32) -> TokenPasser {
+        Self::with_clock(max_passover_time, Box::new(SystemClock))
+    }
+
+    pub fn with_clock(max_passover_time: f32, clock: Box<dyn Clock>) -> TokenPasser {
         TokenPasser {
-            curr_token: None, state: None, pass_mode: TokenPassMode::Idle,
-            max_passover_time, station_status: HashMap::new()
+            curr_token: None, state: None, pass_mode: TokenPassMode::Idle, in_flight: false,
+            acked: false, ack_timeout: None, max_passover_time, hold_extension: Duration::ZERO, max_hold_extension: Duration::ZERO, min_pass_interval: Duration::ZERO, max_timeouts: 3, wrong_sender: WrongSenderPolicy::default(), initial_holder: InitialHolder::default(), clock, station_status: HashMap::new(),
+            rotation_order: vec![], next_order: None,
+            rotation_start: None, rotation_started: false, rotations: 0, rotation_time: Duration::ZERO
+        }
+    }
+
+    // New stations are appended to the end of the rotation
+    pub fn add_station(&mut self, id: WorkStationId) {
+        if self.station_status.insert(id.clone(), StationStatus(false, 0)).is_none() {
+            self.rotation_order.push(id.clone());
+            if let Some(order) = self.next_order.as_mut() {
+                order.push(id);
+            }
+        }
+    }
+
+    pub fn remove_station(&mut self, id: &WorkStationId) {
+        // Token left with its holder, it will not come back
+        if self.state.as_ref().is_some_and(|TokenState(holder_id, _)| holder_id == id) {
+            self.in_flight = false;
+        }
+        self.station_status.remove(id);
+        self.rotation_order.retain(|o| o != id);
+        if let Some(order) = self.next_order.as_mut() {
+            order.retain(|o| o != id);
         }
     }
 
+    // Configured order (including one still waiting for the next rotation)
+    pub fn rotation_order(&self) -> &[WorkStationId] {
+        self.next_order.as_ref().unwrap_or(&self.rotation_order)
+    }
+
+    // Order must contain every station exactly once. Applies immediately if no
+    // station held the token in this rotation yet, else at the next rotation.
+    pub fn set_rotation_order(&mut self, order: Vec<WorkStationId>) -> TResult {
+        let mut sorted = order.iter().collect::<Vec<_>>();
+        sorted.sort();
+        sorted.dedup();
+        if sorted.len() != order.len() || order.len() != self.station_status.len()
+            || !order.iter().all(|id| self.station_status.contains_key(id)) {
+            return Err(GlobalError::Internal(TokenRingError::InvalidRotationOrder))
+        }
+        if self.station_status.values().any(|status| status.0) {
+            self.next_order = Some(order);
+        } else {
+            self.rotation_order = order;
+        }
+        Ok(())
+    }
+
+    pub fn set_max_timeouts(&mut self, max_timeouts: u32) {
+        self.max_timeouts = max_timeouts;
+    }
+
+    pub fn set_wrong_sender_policy(&mut self, wrong_sender: WrongSenderPolicy) {
+        self.wrong_sender = wrong_sender;
+    }
+
+    // Only affects the first pass
+    pub fn set_initial_holder(&mut self, initial_holder: InitialHolder) {
+        self.initial_holder = initial_holder;
+    }
+
+    pub fn set_ack_timeout(&mut self, ack_timeout: Option<Duration>) {
+        self.ack_timeout = ack_timeout;
+    }
+
+    // Seconds. Also applies to the current holder.
+    pub fn set_max_passover_time(&mut self, max_passover_time: f32) {
+        self.max_passover_time = max_passover_time;
+    }
+
+    pub fn max_passover_time(&self) -> f32 {
+        self.max_passover_time
+    }
+
+    // Upper bound of all extensions within one turn (zero rejects hold requests)
+    pub fn set_max_hold_extension(&mut self, max_hold_extension: Duration) {
+        self.max_hold_extension = max_hold_extension;
+    }
+
+    // Gives the current holder more time for this turn only. Returns false if
+    // the station does not hold the token or no extension is allowed.
+    pub fn extend_hold(&mut self, id: &WorkStationId, extra: Duration) -> bool {
+        match (self.state.as_ref(), &self.pass_mode) {
+            (Some(TokenState(holder_id, _)), TokenPassMode::Passed)
+                if holder_id == id && !self.max_hold_extension.is_zero() => {
+                self.hold_extension = self.hold_extension.saturating_add(extra).min(self.max_hold_extension);
+                true
+            },
+            _ => false
+        }
+    }
+
+    // Passover time of the current turn, including any hold extension
+    fn turn_time(&self) -> f32 {
+        self.max_passover_time + self.hold_extension.as_secs_f32()
+    }
+
+    pub fn set_min_pass_interval(&mut self, min_pass_interval: Duration) {
+        self.min_pass_interval = min_pass_interval;
+    }
+
+    // Returns false if the station does not hold the token
+    pub fn acknowledge(&mut self, id: &WorkStationId) -> bool {
+        match (self.state.as_ref(), &self.pass_mode) {
+            (Some(TokenState(holder_id, _)), TokenPassMode::Passed) if holder_id == id => {
+                self.acked = true;
+                true
+            },
+            _ => false
+        }
+    }
+
+    // Received but slow, as opposed to never received
+    pub fn holder_acked(&self) -> bool {
+        self.acked
+    }
+
+    pub fn avg_rotation_time(&self) -> Option<Duration> {
+        (self.rotations > 0).then(|| self.rotation_time / self.rotations)
+    }
+
+    // Whether the last select_next_station began a new rotation
+    pub fn rotation_started(&self) -> bool {
+        self.rotation_started
+    }
+
+    // Stations with at least one timeout since they last returned the token
+    pub fn timed_out_stations(&self) -> usize {
+        self.station_status.values().filter(|status| status.1 > 0).count()
+    }
+
+    // Drops the token and forgets the current rotation, so the next pass
+    // starts over like the very first one (see InitialHolder)
+    pub fn restart(&mut self) {
+        self.curr_token = None;
+        for status in self.station_status.values_mut() {
+            *status = StationStatus(false, 0);
+        }
+        self.rotation_start = None;
+        self.in_flight = false;
+    }
+
+    // Pauses passing. A token that is out still has to come back (or time
+    // out) before the next pass. Returns false if already paused.
+    pub fn set_idle(&mut self) -> bool {
+        let was_idle = matches!(self.pass_mode, TokenPassMode::Idle);
+        self.pass_mode = TokenPassMode::Idle;
+        !was_idle
+    }
+
     pub fn pass_ready(&mut self) -> bool {
         if let Some(TokenState(
-            _, send_time)) = self.state.as_mut() {
+            holder_id, send_time)) = self.state.as_ref() {
+            if self.clock.now().duration_since(*send_time) < self.min_pass_interval {
+                return false
+            }
             match self.pass_mode {
-                TokenPassMode::Received => {
+                // Idle passers resume right away unless the token is still out
+                TokenPassMode::Idle | TokenPassMode::TimedOut if !self.in_flight => {
                     true
                 },
+                TokenPassMode::Received if !self.in_flight => true,
                 _ => {
-                    if Instant::now().duration_since(*send_time)
-                        .as_secs_f32() >= self.max_passover_time {
-                        println!("Current token holder took too long for token pass.");
+                    let elapsed = self.clock.now().duration_since(*send_time);
+                    let unacked = !self.acked && self.ack_timeout.is_some_and(|t| elapsed >= t);
+                    if unacked || elapsed.as_secs_f32() >= self.turn_time() {
+                        if unacked {
+                            println!("Current token holder did not acknowledge token pass.");
+                        } else {
+                            println!("Current token holder took too long for token pass.");
+                        }
+                        // Turn is over for this rotation; count towards skipping,
+                        // unless passing was paused meanwhile
+                        let idle = matches!(self.pass_mode, TokenPassMode::Idle);
+                        if let Some(status) = self.station_status.get_mut(holder_id) {
+                            status.0 = true;
+                            if !idle {
+                                status.1 += 1;
+                            }
+                        }
+                        self.pass_mode = TokenPassMode::TimedOut;
+                        self.in_flight = false;
                         true
                     } else {
                         false
@@ -55,15 +281,30 @@ impl TokenPasser {
     }
 
     pub fn recv_token(&mut self, new_token: Token, sender_id: &WorkStationId) -> TResult {
+        // Members never given the token do not get to touch the rotation
+        // (unless WrongSenderPolicy::Lenient)
+        let unexpected = self.state.as_ref().is_some_and(|TokenState(holder_id, _)| holder_id != sender_id);
+        if unexpected && self.wrong_sender == WrongSenderPolicy::Strict && self.station_status.contains_key(sender_id) {
+            println!("Received token from {sender_id}, which does not hold it. Ignoring.");
+            return Err(GlobalError::Internal(TokenRingError::UnexpectedTokenSender(sender_id.clone())))
+        }
         if let Some(status) = self.get_station(sender_id) {
             // Whether or not token is valid, this station is ticked off the list.
             status.0 = true;
             self.pass_mode = TokenPassMode::Received;
 
-            match self.check_token_validity(&new_token, sender_id) {
+            let result = self.check_token_validity(&new_token, sender_id);
+            // Late or invalid tokens of the holder still end its turn
+            if self.state.as_ref().is_some_and(|TokenState(holder_id, _)| holder_id == sender_id) {
+                self.in_flight = false;
+            }
+            match result {
                 Ok(()) => {
                     // Update new token
                     self.curr_token = Some(new_token);
+                    if let Some(status) = self.get_station(sender_id) {
+                        status.1 = 0;
+                    }
                     // Set pass mode so that new token may be sent
                     
                     println!("Received valid token from {sender_id}. Ready to pass on.");
@@ -77,17 +318,24 @@ impl TokenPasser {
         }
     }
 
-    fn check_token_validity(&self, token: &Token, sender_id: &WorkStationId) -> TResult {
+    fn check_token_validity(&mut self, token: &Token, sender_id: &WorkStationId) -> TResult {
+        let lenient = self.wrong_sender == WrongSenderPolicy::Lenient;
+        let turn_time = self.turn_time();
         if let Some(TokenState(
-            id, send_time)) = self.state.as_ref() {
-            let total_pass_time = Instant::now().duration_since(*send_time).as_secs_f32();
+            id, send_time)) = self.state.as_mut() {
+            let total_pass_time = self.clock.now().duration_since(*send_time).as_secs_f32();
             // Has station overstepped the time limit?
-            if total_pass_time <= self.max_passover_time {
+            if total_pass_time <= turn_time {
                 // Is token header valid (i.e., is it actually from the active station)?
                 if token.header.verify() {
                     // Is the sender of the token actually the expected sender currently registered?
                     if sender_id == id {
                         return Ok(())
+                    } else if lenient {
+                        // Caller made sure the sender is a member
+                        println!("Received token from {sender_id} instead of {id}. Accepting.");
+                        *id = sender_id.clone();
+                        return Ok(())
                     } else {
                         println!("Received token from wrong station: {sender_id}. Expecting: {id}. Discarding.");
                     }
@@ -102,27 +350,59 @@ impl TokenPasser {
     }
 
     pub fn pass_token(&mut self, to_id: WorkStationId) {
-        self.state = Some(TokenState(to_id, Instant::now()));
+        self.state = Some(TokenState(to_id, self.clock.now()));
         self.pass_mode = TokenPassMode::Passed;
+        self.in_flight = true;
+        self.acked = false;
+        self.hold_extension = Duration::ZERO;
     }
 
     pub fn select_next_station(&mut self) -> Option<WorkStationId> {
-        if self.station_status.len() == 0 {
+        if self.rotation_order.is_empty() {
             return None
         }
 
+        let now = self.clock.now();
+        let initial_holder = if self.rotation_start.is_none() {
+            self.initial_holder()
+        } else {
+            None
+        };
+        self.rotation_started = self.rotation_start.is_none();
+        let rotation_start = *self.rotation_start.get_or_insert(now);
+
         // If there are stations on the list that didn't yet hold the token, send there.
-        let next_station = if let Some((next_station_id, _)) = self.station_status.iter()
-            .find(|(_, status)| !status.0) {
+        let max_timeouts = self.max_timeouts;
+        let next_station = if let Some(initial_holder) = initial_holder {
+            initial_holder
+        } else if let Some(next_station_id) = self.rotation_order.iter()
+            .find(|id| {
+                let status = &self.station_status[*id];
+                !status.0 && status.1 < max_timeouts
+            }) {
             next_station_id.clone()
         } else {
             // This token rotation is over. Reset status of all stations and send
-            // new token.
+            // new token. Stations that kept timing out sit this rotation out.
+            self.rotations += 1;
+            self.rotation_started = true;
+            self.rotation_time += now.duration_since(rotation_start);
+            self.rotation_start = Some(now);
+            if let Some(order) = self.next_order.take() {
+                self.rotation_order = order;
+            }
             let mut station_order = vec![];
-            self.station_status.iter_mut().for_each(|(id, status)| {
-                status.0 = false;
-                station_order.push(id);
-            });
+            for id in self.rotation_order.iter() {
+                let status = self.station_status.get_mut(id).unwrap();
+                if status.1 >= max_timeouts {
+                    println!("Skipping {id} this rotation after {} timeouts.", status.1);
+                    status.0 = true;
+                    status.1 = 0;
+                } else {
+                    status.0 = false;
+                    station_order.push(id);
+                }
+            }
 
             println!("Token passing order:");
             for s_o in station_order.into_iter() {
@@ -130,15 +410,283 @@ impl TokenPasser {
             }
             println!(".");
             
-            // Select the next station to hold the new token (here: last station in hashmap)
-            self.station_status.keys().last().unwrap().clone()
+            // Select the next station to hold the new token (first in order, even
+            // if every station is skipped)
+            self.rotation_order.iter().find(|id| !self.station_status[*id].0)
+                .unwrap_or(&self.rotation_order[0]).clone()
         };
 
         self.pass_token(next_station.clone());
         Some(next_station)
     }
 
+    // None if the rotation order decides
+    fn initial_holder(&self) -> Option<WorkStationId> {
+        match &self.initial_holder {
+            InitialHolder::FirstJoined => None,
+            InitialHolder::LastJoined => self.rotation_order.last().cloned(),
+            InitialHolder::Station(id) => self.station_status.contains_key(id).then(|| id.clone())
+        }
+    }
+
     fn get_station(&mut self, id: &WorkStationId) -> Option<&mut StationStatus> {
         self.station_status.get_mut(&id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::{id::WorkStationId, util::MockClock, signature::{generate_keypair, Signed}, token::{Token, TokenHeader}, err::{GlobalError, TokenRingError}};
+    use super::{TokenPasser, WrongSenderPolicy, InitialHolder, TokenPassMode};
+
+    #[test]
+    fn pass_timeout() {
+        let clock = MockClock::new();
+        let mut passer = TokenPasser::with_clock(2., Box::new(clock.clone()));
+        passer.pass_token(WorkStationId::new("Bob".to_owned()));
+        assert!(!passer.pass_ready());
+
+        clock.advance(Duration::from_millis(1999));
+        assert!(!passer.pass_ready());
+        clock.advance(Duration::from_millis(1));
+        assert!(passer.pass_ready());
+    }
+
+    #[test]
+    fn wrong_sender_policy() {
+        let keypair = generate_keypair();
+        let token = || Token::new(Signed::new(&keypair, TokenHeader::new(
+            WorkStationId::new("Host".to_owned()), 1)).unwrap());
+        let (alice, bob) = (WorkStationId::new("Alice".to_owned()), WorkStationId::new("Bob".to_owned()));
+        let mut passer = TokenPasser::new(2.);
+        passer.add_station(alice.clone());
+        passer.add_station(bob.clone());
+
+        passer.pass_token(alice.clone());
+        assert!(passer.recv_token(token(), &bob).is_err());
+        assert!(passer.curr_token.is_none());
+
+        passer.set_wrong_sender_policy(WrongSenderPolicy::Lenient);
+        passer.pass_token(alice.clone());
+        passer.recv_token(token(), &bob).unwrap();
+        assert!(passer.curr_token.is_some());
+        // Strangers are still rejected
+        passer.pass_token(alice.clone());
+        assert!(passer.recv_token(token(), &WorkStationId::new("Eve".to_owned())).is_err());
+    }
+
+    #[test]
+    fn initial_holder() {
+        let ids = ["Alice", "Bob", "Carol"].map(|name| WorkStationId::new(name.to_owned()));
+        for (initial_holder, expected) in [(InitialHolder::FirstJoined, &ids[0]),
+            (InitialHolder::LastJoined, &ids[2]), (InitialHolder::Station(ids[1].clone()), &ids[1]),
+            (InitialHolder::Station(WorkStationId::new("Eve".to_owned())), &ids[0])] {
+            let mut passer = TokenPasser::new(2.);
+            passer.set_initial_holder(initial_holder);
+            for id in ids.iter() {
+                passer.add_station(id.clone());
+            }
+            assert_eq!(passer.select_next_station().as_ref(), Some(expected));
+        }
+    }
+
+    #[test]
+    fn ack_timeout() {
+        let clock = MockClock::new();
+        let mut passer = TokenPasser::with_clock(2., Box::new(clock.clone()));
+        passer.set_ack_timeout(Some(Duration::from_millis(500)));
+        let (alice, bob) = (WorkStationId::new("Alice".to_owned()), WorkStationId::new("Bob".to_owned()));
+
+        // Acked holders get the full passover time
+        passer.pass_token(alice.clone());
+        assert!(!passer.acknowledge(&bob));
+        assert!(passer.acknowledge(&alice) && passer.holder_acked());
+        clock.advance(Duration::from_millis(1000));
+        assert!(!passer.pass_ready());
+
+        // Silent holders time out early
+        passer.pass_token(bob.clone());
+        assert!(!passer.holder_acked());
+        clock.advance(Duration::from_millis(499));
+        assert!(!passer.pass_ready());
+        clock.advance(Duration::from_millis(1));
+        assert!(passer.pass_ready());
+    }
+
+    #[test]
+    fn skip_timed_out_station() {
+        let clock = MockClock::new();
+        let mut passer = TokenPasser::with_clock(2., Box::new(clock.clone()));
+        passer.set_max_timeouts(2);
+        let (alice, bob) = (WorkStationId::new("Alice".to_owned()), WorkStationId::new("Bob".to_owned()));
+        passer.add_station(alice.clone());
+        passer.add_station(bob.clone());
+        let keypair = generate_keypair();
+        // Alice returns the token, Bob never does
+        let serve = |passer: &mut TokenPasser, id: &WorkStationId| {
+            if id == &alice {
+                let token = Token::new(Signed::new(&keypair, TokenHeader::new(
+                    WorkStationId::new("Host".to_owned()), 1)).unwrap());
+                passer.recv_token(token, id).unwrap();
+            } else {
+                clock.advance(Duration::from_secs(3));
+            }
+            assert!(passer.pass_ready());
+        };
+
+        while passer.station_status[&bob].1 < 2 {
+            let next = passer.select_next_station().unwrap();
+            serve(&mut passer, &next);
+        }
+        // Bob sits out the current and the next rotation...
+        for _ in 0..2 {
+            let next = passer.select_next_station().unwrap();
+            assert_eq!(next, alice);
+            serve(&mut passer, &next);
+        }
+        // ...and is tried again afterwards
+        let mut selected = vec![];
+        for _ in 0..2 {
+            let next = passer.select_next_station().unwrap();
+            serve(&mut passer, &next);
+            selected.push(next);
+        }
+        assert!(selected.contains(&bob));
+    }
+
+    #[test]
+    fn custom_rotation_order() {
+        let mut passer = TokenPasser::new(2.);
+        let ids = ["Alice", "Bob", "Carol"].map(|name| WorkStationId::new(name.to_owned()));
+        for id in ids.iter() {
+            passer.add_station(id.clone());
+        }
+        let keypair = generate_keypair();
+        let rotate = |passer: &mut TokenPasser| (0..3).map(|_| {
+            let next = passer.select_next_station().unwrap();
+            let token = Token::new(Signed::new(&keypair, TokenHeader::new(
+                WorkStationId::new("Host".to_owned()), 1)).unwrap());
+            passer.recv_token(token, &next).unwrap();
+            next
+        }).collect::<Vec<_>>();
+        assert_eq!(rotate(&mut passer), ids.to_vec());
+
+        let order = vec![ids[2].clone(), ids[0].clone(), ids[1].clone()];
+        assert!(passer.set_rotation_order(vec![ids[0].clone(), ids[1].clone()]).is_err());
+        assert!(passer.set_rotation_order(vec![ids[0].clone(), ids[0].clone(), ids[1].clone()]).is_err());
+        passer.set_rotation_order(order.clone()).unwrap();
+        assert_eq!(passer.rotation_order(), order.as_slice());
+        assert_eq!(rotate(&mut passer), order);
+    }
+
+    #[test]
+    fn order_change_waits_for_next_rotation() {
+        let mut passer = TokenPasser::new(2.);
+        let ids = ["Alice", "Bob", "Carol"].map(|name| WorkStationId::new(name.to_owned()));
+        for id in ids.iter() {
+            passer.add_station(id.clone());
+        }
+        let keypair = generate_keypair();
+        let serve = |passer: &mut TokenPasser| {
+            let next = passer.select_next_station().unwrap();
+            let token = Token::new(Signed::new(&keypair, TokenHeader::new(
+                WorkStationId::new("Host".to_owned()), 1)).unwrap());
+            passer.recv_token(token, &next).unwrap();
+            next
+        };
+        assert_eq!(serve(&mut passer), ids[0]);
+        passer.set_rotation_order(vec![ids[2].clone(), ids[1].clone(), ids[0].clone()]).unwrap();
+        // Current rotation continues in the old order
+        assert_eq!(serve(&mut passer), ids[1]);
+        assert_eq!(serve(&mut passer), ids[2]);
+        assert_eq!(serve(&mut passer), ids[2]);
+        assert_eq!(serve(&mut passer), ids[1]);
+        assert_eq!(serve(&mut passer), ids[0]);
+    }
+
+    #[test]
+    fn min_pass_interval() {
+        let keypair = generate_keypair();
+        let alice = WorkStationId::new("Alice".to_owned());
+        let clock = MockClock::new();
+        let mut passer = TokenPasser::with_clock(2., Box::new(clock.clone()));
+        passer.set_min_pass_interval(Duration::from_millis(100));
+        passer.add_station(alice.clone());
+
+        // Token comes back immediately, next pass still waits
+        passer.pass_token(alice.clone());
+        passer.recv_token(Token::new(Signed::new(&keypair, TokenHeader::new(
+            WorkStationId::new("Host".to_owned()), 1)).unwrap()), &alice).unwrap();
+        assert!(!passer.pass_ready());
+        clock.advance(Duration::from_millis(99));
+        assert!(!passer.pass_ready());
+        clock.advance(Duration::from_millis(1));
+        assert!(passer.pass_ready());
+    }
+
+    #[test]
+    fn set_max_passover_time() {
+        let clock = MockClock::new();
+        let mut passer = TokenPasser::with_clock(2., Box::new(clock.clone()));
+        passer.pass_token(WorkStationId::new("Bob".to_owned()));
+        clock.advance(Duration::from_millis(1000));
+        assert!(!passer.pass_ready());
+
+        passer.set_max_passover_time(0.5);
+        assert_eq!(passer.max_passover_time(), 0.5);
+        passer.pass_token(WorkStationId::new("Alice".to_owned()));
+        clock.advance(Duration::from_millis(499));
+        assert!(!passer.pass_ready());
+        clock.advance(Duration::from_millis(1));
+        assert!(passer.pass_ready());
+    }
+
+    #[test]
+    fn unexpected_token_sender() {
+        let keypair = generate_keypair();
+        let (alice, bob) = (WorkStationId::new("Alice".to_owned()), WorkStationId::new("Bob".to_owned()));
+        let mut passer = TokenPasser::new(2.);
+        passer.add_station(alice.clone());
+        passer.add_station(bob.clone());
+        passer.pass_token(alice.clone());
+
+        let token = Token::new(Signed::new(&keypair, TokenHeader::new(
+            WorkStationId::new("Host".to_owned()), 1)).unwrap());
+        assert!(matches!(passer.recv_token(token, &bob),
+            Err(GlobalError::Internal(TokenRingError::UnexpectedTokenSender(id))) if id == bob));
+        assert!(!passer.station_status[&bob].0);
+        assert!(matches!(passer.pass_mode, TokenPassMode::Passed));
+        assert!(passer.curr_token.is_none() && !passer.pass_ready());
+    }
+
+    #[test]
+    fn extend_hold() {
+        let clock = MockClock::new();
+        let (alice, bob) = (WorkStationId::new("Alice".to_owned()), WorkStationId::new("Bob".to_owned()));
+        let mut passer = TokenPasser::with_clock(2., Box::new(clock.clone()));
+        passer.pass_token(alice.clone());
+        // Disabled by default
+        assert!(!passer.extend_hold(&alice, Duration::from_secs(1)));
+
+        passer.set_max_hold_extension(Duration::from_secs(3));
+        assert!(!passer.extend_hold(&bob, Duration::from_secs(1)));
+        assert!(passer.extend_hold(&alice, Duration::from_secs(2)));
+        assert!(passer.extend_hold(&alice, Duration::from_secs(2)));
+        clock.advance(Duration::from_millis(4999));
+        assert!(!passer.pass_ready());
+        clock.advance(Duration::from_millis(1));
+        assert!(passer.pass_ready());
+
+        // Only for the turn it was requested in
+        passer.pass_token(bob);
+        clock.advance(Duration::from_secs(2));
+        assert!(passer.pass_ready());
+
+        // Huge extensions saturate instead of overflowing
+        passer.set_max_hold_extension(Duration::MAX);
+        passer.pass_token(alice.clone());
+        assert!(passer.extend_hold(&alice, Duration::MAX));
+        assert!(passer.extend_hold(&alice, Duration::MAX));
+    }
+}
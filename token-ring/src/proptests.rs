@@ -0,0 +1,185 @@
+// Round-trip properties for everything that goes over the wire:
+// deserialize(serialize(x)) == x and serialize(x).len() == x.size()
+use std::{io::Cursor, fmt::Debug, time::Duration};
+use proptest::{prelude::*, arbitrary::Arbitrary, strategy::BoxedStrategy};
+use crate::{id::WorkStationId, packet::{PacketType, JoinRequest, JoinMetadata, JoinAnswerResult, DenyReason, Capabilities}, serialize::Serializable, signature::{generate_keypair, Signed}, token::{Token, TokenHeader, TokenFrame, TokenFrameId, TokenFrameType, TokenSendMode, PresenceStatus, MAX_MULTICAST_RECIPIENTS}};
+
+impl Arbitrary for WorkStationId {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        // Short ASCII names take the v1 encoding, everything else v2
+        prop_oneof![
+            "[a-zA-Z0-9]{0,8}",
+            "\\PC{0,40}"
+        ].prop_map(WorkStationId::new).boxed()
+    }
+}
+
+impl Arbitrary for DenyReason {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        prop_oneof![
+            Just(DenyReason::AlreadyJoined),
+            Just(DenyReason::ConnectionsBlocked),
+            any::<u16>().prop_map(DenyReason::RingFull),
+            Just(DenyReason::WrongPassword),
+            Just(DenyReason::IdInUse),
+            Just(DenyReason::KeyNotAuthorized)
+        ].boxed()
+    }
+}
+
+impl Arbitrary for JoinAnswerResult {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        prop_oneof![
+            any::<WorkStationId>().prop_map(JoinAnswerResult::Confirm),
+            any::<DenyReason>().prop_map(JoinAnswerResult::Deny)
+        ].boxed()
+    }
+}
+
+impl Arbitrary for TokenSendMode {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        prop_oneof![
+            any::<WorkStationId>().prop_map(TokenSendMode::Unicast),
+            Just(TokenSendMode::Broadcast),
+            prop::collection::vec(any::<WorkStationId>(), 0..=MAX_MULTICAST_RECIPIENTS)
+                .prop_map(TokenSendMode::Multicast)
+        ].boxed()
+    }
+}
+
+impl Arbitrary for PresenceStatus {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        prop_oneof![
+            Just(PresenceStatus::Online),
+            Just(PresenceStatus::Away),
+            Just(PresenceStatus::Typing)
+        ].boxed()
+    }
+}
+
+impl Arbitrary for TokenFrameType {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        prop_oneof![
+            Just(TokenFrameType::Empty),
+            (any::<TokenSendMode>(), any::<u16>(), prop::collection::vec(any::<u8>(), 0..256),
+                any::<Option<u64>>()).prop_map(|(send_mode, seq, payload, expires_at)|
+                TokenFrameType::Data { send_mode, seq, payload, expires_at }),
+            (any::<WorkStationId>(), any::<u16>()).prop_map(|(source, seq)|
+                TokenFrameType::DataReceived { source, seq }),
+            any::<PresenceStatus>().prop_map(|status| TokenFrameType::Presence { status }),
+            (any::<u16>(), prop::collection::vec(any::<u8>(), 0..256)).prop_map(|(type_id, payload)|
+                TokenFrameType::App { type_id, payload }),
+            (any::<WorkStationId>(), any::<WorkStationId>(), any::<u16>()).prop_map(|(source, dest, seq)|
+                TokenFrameType::DeliveryFailed { source, dest, seq })
+        ].boxed()
+    }
+}
+
+impl Arbitrary for TokenFrame {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (any::<WorkStationId>(), any::<u64>(), any::<TokenFrameType>(), any::<bool>())
+            .prop_map(|(source, timestamp, content, signed)| {
+                let mut frame = TokenFrame::new(
+                    TokenFrameId::with_timestamp(source, timestamp), content);
+                if signed {
+                    frame.sign(&generate_keypair()).unwrap();
+                }
+                frame
+            }).boxed()
+    }
+}
+
+impl Arbitrary for Token {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (any::<WorkStationId>(), any::<u64>(), prop::collection::vec(any::<TokenFrame>(), 0..8))
+            .prop_map(|(origin, generation, frames)| {
+                let mut token = Token::new(Signed::new(&generate_keypair(),
+                    TokenHeader::new(origin, generation)).unwrap());
+                token.frames = frames;
+                token
+            }).boxed()
+    }
+}
+
+impl Arbitrary for PacketType {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        prop_oneof![
+            ("\\PC{0,64}", any::<bool>(), any::<bool>(), any::<u64>(), any::<u32>(), prop::option::of("\\PC{0,16}"))
+                .prop_map(|(password, observer, compressed_tokens, nonce, client_version, display_name)|
+                PacketType::JoinRequest(JoinRequest { password, observer, capabilities: Capabilities { compressed_tokens },
+                    nonce, metadata: JoinMetadata { client_version, display_name } })),
+            (any::<JoinAnswerResult>(), any::<u64>()).prop_map(|(result, nonce)| PacketType::JoinReply(result, nonce)),
+            any::<Token>().prop_map(PacketType::TokenPass),
+            prop::collection::vec(any::<u8>(), 0..64).prop_map(PacketType::TokenPassCompressed),
+            "\\PC{0,64}".prop_map(|reason| PacketType::Kicked { reason }),
+            any::<u64>().prop_map(|pass| PacketType::TokenAck { pass }),
+            any::<u32>().prop_map(|millis| PacketType::TokenHoldRequest { extra: Duration::from_millis(millis as u64) }),
+            Just(PacketType::Leave()),
+            Just(()).prop_map(|_| PacketType::KeyRotation { new_key: generate_keypair().public }),
+            Just(()).prop_map(|_| PacketType::KeyRotationAck { new_key: generate_keypair().public })
+        ].boxed()
+    }
+}
+
+fn round_trip<T: Serializable<Output = T> + PartialEq + Debug>(val: &T) -> Result<(), TestCaseError> {
+    let mut buf = vec![];
+    val.write(&mut buf).unwrap();
+    prop_assert_eq!(buf.len(), val.size());
+    let read = T::read(&mut Cursor::new(buf.as_slice())).unwrap();
+    prop_assert_eq!(&read, val);
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn work_station_id(id in any::<WorkStationId>()) {
+        round_trip(&id)?;
+    }
+
+    #[test]
+    fn join_answer_result(result in any::<JoinAnswerResult>()) {
+        round_trip(&result)?;
+    }
+
+    #[test]
+    fn token_frame(frame in any::<TokenFrame>()) {
+        round_trip(&frame)?;
+    }
+
+    #[test]
+    fn token(token in any::<Token>()) {
+        round_trip(&token)?;
+    }
+
+    #[test]
+    fn packet_type(packet in any::<PacketType>()) {
+        round_trip(&packet)?;
+    }
+}
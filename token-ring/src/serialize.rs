@@ -1,6 +1,6 @@
-use std::{io::{Cursor, Write, Read}, net::{SocketAddr, IpAddr}};
+use std::{io::{Cursor, Write, Read, ErrorKind}, net::{SocketAddr, IpAddr}, time::Duration};
 use byteorder::{WriteBytesExt, BigEndian, ReadBytesExt};
-use crate::err::TResult;
+use crate::err::{TResult, GlobalError, TokenRingError};
 
 pub trait Serializable {
     type Output;
@@ -30,13 +30,28 @@ pub fn write_arr<T: Serializable, const N: usize>(buf: &mut Vec<u8>, arr: &[T; N
 
 pub fn read_arr<T: Serializable<Output = T> + Copy + Default, const N: usize>(buf: &mut Cursor<&[u8]>) -> TResult<[T; N]> {
     let mut arr = [T::default(); N];
-    for i in 0..N {
-        arr[i] = T::read(buf)?;
+    for (i, t) in arr.iter_mut().enumerate() {
+        *t = read_element(buf, i, N)?;
     }
     Ok(arr)
 }
 
+// Reads element at index of a sequence with len elements. Running out of
+// bytes is reported as UnexpectedEof with expected element count and the
+// index of the truncated element (= number of complete elements) as got.
+fn read_element<T: Serializable<Output = T>>(buf: &mut Cursor<&[u8]>, index: usize, len: usize) -> TResult<T> {
+    T::read(buf).map_err(|e| match e {
+        GlobalError::Io(e) if e.kind() == ErrorKind::UnexpectedEof =>
+            GlobalError::Internal(TokenRingError::UnexpectedEof { expected: len, got: index }),
+        e => e
+    })
+}
+
+// Fails instead of truncating the length prefix of vecs beyond u16::MAX bytes
 pub fn write_byte_vec(buf: &mut Vec<u8>, vec: &Vec<u8>) -> TResult {
+    if vec.len() > u16::MAX as usize {
+        return Err(GlobalError::Internal(TokenRingError::LengthTooLarge { len: vec.len(), max: u16::MAX as usize }))
+    }
     buf.write_u16::<BigEndian>(vec.len() as u16)?;
     Ok(buf.write_all(vec)?)
 }
@@ -58,9 +73,11 @@ pub fn write_vec<T: Serializable>(buf: &mut Vec<u8>, vec: &Vec<T>) -> TResult {
 
 pub fn read_vec<T: Serializable<Output = T>>(buf: &mut Cursor<&[u8]>) -> TResult<Vec<T>> {
     let len = buf.read_u32::<BigEndian>()? as usize;
-    let mut vec = Vec::with_capacity(len);
-    for _ in 0..len {
-        vec.push(T::read(buf)?);
+    // Every element takes at least one byte, so a bogus length can not
+    // reserve more than the buffer holds
+    let mut vec = Vec::with_capacity(len.min(remaining(buf)));
+    for i in 0..len {
+        vec.push(read_element(buf, i, len)?);
     }
     Ok(vec)
 }
@@ -70,10 +87,22 @@ pub fn write_string(buf: &mut Vec<u8>, str: &String) -> TResult {
     write_byte_vec(buf, bytes)
 }
 
+// Unbounded (up to the u16 length prefix). Use read_string_max for fields
+// with a known maximum.
 pub fn read_string(buf: &mut Cursor<&[u8]>) -> TResult<String> {
     let bytes = read_byte_vec(buf)?;
-    let string = String::from_utf8(bytes).unwrap();
-    Ok(string) // TODO: Check err...
+    String::from_utf8(bytes).map_err(|_| GlobalError::Internal(TokenRingError::InvalidUtf8))
+}
+
+// Checks the length prefix before allocating, for strings a peer may inflate
+pub fn read_string_max(buf: &mut Cursor<&[u8]>, max: usize) -> TResult<String> {
+    let len = buf.read_u16::<BigEndian>()? as usize;
+    if len > max {
+        return Err(GlobalError::Internal(TokenRingError::LengthTooLarge { len, max }))
+    }
+    let mut bytes = vec![0u8; len];
+    buf.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| GlobalError::Internal(TokenRingError::InvalidUtf8))
 }
 
 pub fn write_sock_addr(buf: &mut Vec<u8>, addr: &SocketAddr) -> TResult {
@@ -93,15 +122,29 @@ pub fn write_sock_addr(buf: &mut Vec<u8>, addr: &SocketAddr) -> TResult {
 
 pub fn read_sock_addr(buf: &mut Cursor<&[u8]>) -> TResult<SocketAddr> {
     let ip_addr_type = buf.read_u8()?;
+    // Check that declared address family matches the octets actually present
+    let octets = match ip_addr_type {
+        0 => 4,
+        1 => 16,
+        n => return Err(GlobalError::Internal(TokenRingError::InvalidAddressFamily(n)))
+    };
+    if remaining(buf) < octets + 2 {
+        return Err(GlobalError::Internal(TokenRingError::UnexpectedEof {
+            expected: octets + 2, got: remaining(buf) }))
+    }
     let ip_addr = match ip_addr_type {
         0 => IpAddr::V4(read_byte_arr::<4>(buf)?.into()),
-        1 => IpAddr::V6(read_byte_arr::<16>(buf)?.into()),
-        n @ _ => panic!("Index out of bounds: {n}.")
-    };    
+        _ => IpAddr::V6(read_byte_arr::<16>(buf)?.into())
+    };
     let port = buf.read_u16::<BigEndian>()?;
     Ok((ip_addr, port).into())
 }
 
+// Number of bytes left to read in buffer
+pub fn remaining(buf: &Cursor<&[u8]>) -> usize {
+    (buf.get_ref().len() as u64).saturating_sub(buf.position()) as usize
+}
+
 pub fn get_sock_addr_size(addr: &SocketAddr) -> usize {
     (if addr.is_ipv4() {
         4
@@ -110,26 +153,136 @@ pub fn get_sock_addr_size(addr: &SocketAddr) -> usize {
     }) + 2
 }
 
-// WARNING: write_instant()/read_instant() not usable, as Instant uses
-// floating types internally, which can not be routinely parsed without losing
-// data integrity.
+// Instants are local to a machine and can not be sent. Send the duration that
+// is actually meant (elapsed, remaining, timeout) instead.
+// Layout: seconds (u64), subsecond nanos (u32). Lossless.
+pub fn write_duration(buf: &mut Vec<u8>, duration: &Duration) -> TResult {
+    buf.write_u64::<BigEndian>(duration.as_secs())?;
+    Ok(buf.write_u32::<BigEndian>(duration.subsec_nanos())?)
+}
 
-// pub fn write_instant(buf: &mut Vec<u8>, time: Instant) -> TResult {
-//     Ok(buf.write_f32::<BigEndian>(time.elapsed().as_secs_f32())?)
-// }
-// pub fn read_instant(buf: &mut Cursor<&[u8]>) -> TResult<Instant> {
-//     // TODO: Improve serialization
-//     let elapsed = Duration::from_secs_f32(buf.read_f32::<BigEndian>()?);
-//     Ok(Instant::now().checked_sub(elapsed).unwrap())
-// }
+pub fn read_duration(buf: &mut Cursor<&[u8]>) -> TResult<Duration> {
+    let secs = buf.read_u64::<BigEndian>()?;
+    let nanos = buf.read_u32::<BigEndian>()?;
+    // Written from subsec_nanos, so more is malformed (and Duration::new
+    // would panic carrying it over into u64::MAX seconds)
+    if nanos >= 1_000_000_000 {
+        return Err(GlobalError::Internal(TokenRingError::InvalidDuration { nanos }))
+    }
+    Ok(Duration::new(secs, nanos))
+}
+
+impl Serializable for Duration {
+    type Output = Duration;
+
+    fn write(&self, buf: &mut Vec<u8>) -> TResult {
+        write_duration(buf, self)
+    }
+
+    fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
+        read_duration(buf)
+    }
+
+    fn size(&self) -> usize {
+        8 + 4
+    }
+}
 
 pub trait Serializer : Serializable {
+    // Buffers are reserved with the exact size upfront to avoid regrowing
     fn serialize(&self) -> TResult<Vec<u8>> {
-        let mut buf = vec![];
+        let mut buf = Vec::with_capacity(self.size());
         self.write(&mut buf)?;
         Ok(buf)
     }
+    // Serializes into an existing buffer (cleared first), so that callers
+    // sending many values can reuse one allocation.
+    fn serialize_into(&self, buf: &mut Vec<u8>) -> TResult {
+        buf.clear();
+        buf.reserve(self.size());
+        self.write(buf)
+    }
     fn deserialize(buf: &[u8]) -> TResult<Self::Output> {
         Ok(Self::read(&mut Cursor::new(&buf))?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, net::SocketAddr, time::Duration};
+    use crate::err::{GlobalError, TokenRingError};
+    use super::{Serializable, write_sock_addr, read_sock_addr, write_vec, read_vec, write_byte_vec, read_string};
+
+    #[test]
+    fn sock_addr() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 5000));
+        let mut buf = vec![];
+        write_sock_addr(&mut buf, &addr).unwrap();
+        let new_addr = read_sock_addr(&mut Cursor::new(buf.as_slice())).unwrap();
+        assert_eq!(addr, new_addr)
+    }
+
+    #[test]
+    fn unknown_address_family() {
+        let buf = [7u8, 127, 0, 0, 1, 0, 80];
+        match read_sock_addr(&mut Cursor::new(&buf[..])) {
+            Err(GlobalError::Internal(TokenRingError::InvalidAddressFamily(7))) => (),
+            r => panic!("Expected invalid address family, got {:?}.", r)
+        }
+    }
+
+    #[test]
+    fn truncated_v6_address() {
+        let buf = [1u8, 127, 0, 0, 1];
+        match read_sock_addr(&mut Cursor::new(&buf[..])) {
+            Err(GlobalError::Internal(TokenRingError::UnexpectedEof { expected: 18, got: 4 })) => (),
+            r => panic!("Expected unexpected EOF, got {:?}.", r)
+        }
+    }
+
+    #[test]
+    fn duration() {
+        let durations = [Duration::ZERO, Duration::from_nanos(1), Duration::from_millis(250),
+            Duration::new(5 * 3600 + 17, 999_999_999), Duration::MAX];
+        for duration in durations {
+            let mut buf = vec![];
+            duration.write(&mut buf).unwrap();
+            assert_eq!(buf.len(), duration.size());
+            assert_eq!(Duration::read(&mut Cursor::new(buf.as_slice())).unwrap(), duration);
+        }
+
+        let mut buf = vec![];
+        Duration::MAX.write(&mut buf).unwrap();
+        buf[8..].copy_from_slice(&1_000_000_000u32.to_be_bytes());
+        assert!(matches!(Duration::read(&mut Cursor::new(buf.as_slice())),
+            Err(GlobalError::Internal(TokenRingError::InvalidDuration { nanos: 1_000_000_000 }))));
+    }
+
+    #[test]
+    fn truncated_vec() {
+        let durations = vec![Duration::from_secs(1), Duration::from_secs(2), Duration::from_secs(3)];
+        let mut buf = vec![];
+        write_vec(&mut buf, &durations).unwrap();
+        buf.truncate(buf.len() - 1);
+        match read_vec::<Duration>(&mut Cursor::new(buf.as_slice())) {
+            Err(GlobalError::Internal(TokenRingError::UnexpectedEof { expected: 3, got: 2 })) => (),
+            r => panic!("Expected unexpected EOF at third element, got {:?}.", r)
+        }
+    }
+
+    #[test]
+    fn oversized_byte_vec() {
+        let mut buf = vec![];
+        write_byte_vec(&mut buf, &vec![0; u16::MAX as usize]).unwrap();
+        assert!(matches!(write_byte_vec(&mut buf, &vec![0; u16::MAX as usize + 1]),
+            Err(GlobalError::Internal(TokenRingError::LengthTooLarge { .. }))));
+    }
+
+    #[test]
+    fn invalid_utf8_string() {
+        let mut buf = vec![];
+        write_byte_vec(&mut buf, &vec![b'o', 0xff, b'k']).unwrap();
+        assert!(matches!(read_string(&mut Cursor::new(buf.as_slice())),
+            Err(GlobalError::Internal(TokenRingError::InvalidUtf8))));
+    }
+}
@@ -1,6 +1,7 @@
 use std::{io::Cursor, fmt::{Debug, Formatter}};
 use ed25519_dalek::{PublicKey, Signature as S, Keypair, Signer, Verifier, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH, ed25519::signature::Signature};
-use crate::{serialize::{Serializable, read_byte_arr, write_byte_arr, write_byte_vec, read_byte_vec}, err::TResult};
+use sha2::{Sha256, Digest};
+use crate::{serialize::{Serializable, read_byte_arr, write_byte_arr, write_byte_vec, read_byte_vec}, err::{TResult, GlobalError, TokenRingError}, id::WorkStationId};
 
 #[derive(Clone, PartialEq)]
 pub struct Signed<T: Serializable + Debug> {
@@ -38,6 +39,40 @@ impl<T: Serializable + Debug> Signed<T> {
     pub fn verify(&self) -> bool {
         self.key.verify(&self.val_bytes, &self.signature).is_ok()
     }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.key
+    }
+
+    // Valid signature made by the given key?
+    pub fn verify_key(&self, key: &PublicKey) -> bool {
+        &self.key == key && self.verify()
+    }
+
+    // Writes the same bytes as Signed::new(keypair, val)?.write(buf), but
+    // serializes val only once, straight into buf
+    pub fn write_new(keypair: &Keypair, val: &T, buf: &mut Vec<u8>) -> TResult {
+        write_signed(keypair, buf, |buf| val.write(buf))
+    }
+}
+
+// Signed<T> encoding of whatever write_val appends to buf. The value is signed
+// where it was written, and the signature and length are filled in afterwards.
+pub fn write_signed(keypair: &Keypair, buf: &mut Vec<u8>,
+    write_val: impl FnOnce(&mut Vec<u8>) -> TResult) -> TResult {
+    write_byte_arr(buf, &keypair.public.to_bytes())?;
+    let signature_start = buf.len();
+    buf.resize(signature_start + SIGNATURE_LENGTH + 2, 0);
+    let val_start = buf.len();
+    write_val(buf)?;
+    let len = buf.len() - val_start;
+    if len > u16::MAX as usize {
+        return Err(GlobalError::Internal(TokenRingError::LengthTooLarge { len, max: u16::MAX as usize }))
+    }
+    let signature = keypair.sign(&buf[val_start..]);
+    buf[signature_start..val_start - 2].copy_from_slice(&signature.to_bytes());
+    buf[val_start - 2..val_start].copy_from_slice(&(len as u16).to_be_bytes());
+    Ok(())
 }
 
 impl<T: Serializable<Output = T> + Debug> Serializable for Signed<T> {
@@ -64,7 +99,85 @@ impl<T: Serializable<Output = T> + Debug> Serializable for Signed<T> {
     }
 
     fn size(&self) -> usize {
-        PUBLIC_KEY_LENGTH + SIGNATURE_LENGTH + self.val_bytes.len()
+        PUBLIC_KEY_LENGTH + SIGNATURE_LENGTH + 2 + self.val_bytes.len()
+    }
+}
+
+// Only the value is sent along, its bytes are restored from the (deterministic)
+// binary encoding so that the signature still verifies
+#[cfg(feature = "serde")]
+impl<T: Serializable + Debug + serde::Serialize> serde::Serialize for Signed<T> {
+    fn serialize<Ser: serde::Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("Signed", 3)?;
+        state.serialize_field("key", &self.key)?;
+        state.serialize_field("signature", &self.signature)?;
+        state.serialize_field("val", &self.val)?;
+        state.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: Serializable + Debug + serde::Deserialize<'de>> serde::Deserialize<'de> for Signed<T> {
+    fn deserialize<De: serde::Deserializer<'de>>(deserializer: De) -> Result<Self, De::Error> {
+        #[derive(serde::Deserialize)]
+        struct Fields<T> {
+            key: PublicKey,
+            signature: S,
+            val: T
+        }
+        let fields = Fields::<T>::deserialize(deserializer)?;
+        let mut val_bytes = vec![];
+        fields.val.write(&mut val_bytes).map_err(serde::de::Error::custom)?;
+        Ok(Self {
+            key: fields.key, signature: fields.signature, val: fields.val, val_bytes
+        })
+    }
+}
+
+// Signature kept apart from the signed value, for values that are serialized
+// in place (e.g. token frames) instead of being wrapped in Signed<T>.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DetachedSignature {
+    key: PublicKey,
+    signature: S
+}
+
+impl DetachedSignature {
+    pub fn new(keypair: &Keypair, bytes: &[u8]) -> DetachedSignature {
+        DetachedSignature {
+            key: keypair.public, signature: keypair.sign(bytes)
+        }
+    }
+
+    pub fn verify(&self, bytes: &[u8]) -> bool {
+        self.key.verify(bytes, &self.signature).is_ok()
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.key
+    }
+}
+
+impl Serializable for DetachedSignature {
+    type Output = DetachedSignature;
+
+    fn write(&self, buf: &mut Vec<u8>) -> TResult {
+        write_byte_arr(buf, &self.key.to_bytes())?;
+        write_byte_arr(buf, &self.signature.to_bytes())
+    }
+
+    fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
+        let key = PublicKey::from_bytes(&read_byte_arr::<PUBLIC_KEY_LENGTH>(buf)?)?;
+        let signature = Signature::from_bytes(&read_byte_arr::<SIGNATURE_LENGTH>(buf)?)?;
+        Ok(DetachedSignature {
+            key, signature
+        })
+    }
+
+    fn size(&self) -> usize {
+        PUBLIC_KEY_LENGTH + SIGNATURE_LENGTH
     }
 }
 
@@ -73,11 +186,27 @@ pub fn generate_keypair() -> Keypair {
     Keypair::generate(&mut rng)
 }
 
+// Short colon-hex digest of a station identity (ID and public key), for
+// comparing identities out-of-band.
+pub fn fingerprint(id: &WorkStationId, key: &PublicKey) -> String {
+    let mut id_bytes = vec![];
+    // Writing into a Vec cannot fail
+    id.write(&mut id_bytes).unwrap();
+    let digest = Sha256::new()
+        .chain(&id_bytes)
+        .chain(key.as_bytes())
+        .finalize();
+    digest[..16].iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
-    use crate::{serialize::{Serializable, write_string, read_string}, err::TResult};
-    use super::{generate_keypair, Signed};
+    use crate::{serialize::{Serializable, write_string, read_string}, err::TResult, id::WorkStationId};
+    use super::{generate_keypair, fingerprint, Signed};
 
     #[derive(Debug, Clone, PartialEq)]
     struct Stub(String);
@@ -116,6 +245,18 @@ mod tests {
         assert_eq!(stub, new_stub)
     }
 
+    #[test]
+    fn write_new() {
+        let keypair = generate_keypair();
+        let val = Stub("Test".to_owned());
+        let mut expected = vec![];
+        Signed::new(&keypair, val.clone()).unwrap().write(&mut expected).unwrap();
+        // Appends to what is already in the buffer
+        let mut buf = vec![7];
+        Signed::write_new(&keypair, &val, &mut buf).unwrap();
+        assert_eq!(buf[1..], expected[..]);
+    }
+
     #[test]
     fn sign() {
         let signed_stub = create_stub();
@@ -132,4 +273,14 @@ mod tests {
         let deserialized_stub = Signed::<Stub>::read(&mut cursor).unwrap();
         assert!(deserialized_stub.verify());
     }
+
+    #[test]
+    fn fingerprints() {
+        let id = WorkStationId::new("Host".to_owned());
+        let (keypair, other_keypair) = (generate_keypair(), generate_keypair());
+        let print = fingerprint(&id, &keypair.public);
+        assert_eq!(print, fingerprint(&id, &keypair.public));
+        assert_eq!(print.len(), 16 * 3 - 1);
+        assert_ne!(print, fingerprint(&id, &other_keypair.public));
+    }
 }
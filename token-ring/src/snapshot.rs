@@ -0,0 +1,221 @@
+use std::{io::Cursor, net::SocketAddr};
+use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
+use ed25519_dalek::{PublicKey, PUBLIC_KEY_LENGTH};
+use crate::{id::WorkStationId, token::Token, station::{ConnectionMode, BanTarget}, packet::JoinMetadata, err::{TResult, GlobalError, TokenRingError}, serialize::{Serializable, write_sock_addr, read_sock_addr, get_sock_addr_size, write_vec, read_vec, write_byte_arr, read_byte_arr}};
+
+// Checkpoint of an active station's ring state. Lets a restarted active
+// station resume the ring without every passive station re-joining.
+// The station keypair is not part of it, persist it separately (see
+// ActiveStation::keypair), since members only accept tokens signed with it.
+#[derive(Clone, PartialEq)]
+pub struct RingSnapshot {
+    // Members in rotation order, followed by observers
+    pub members: Vec<SnapshotMember>,
+    pub token_generation: u64,
+    pub token: Option<Token>,
+    pub banned: Vec<BanTarget>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotMember {
+    pub id: WorkStationId,
+    pub addr: SocketAddr,
+    // Key the member signed its join request with
+    pub key: Option<PublicKey>,
+    pub observer: bool,
+    // Held the token in the current rotation?
+    pub held_token: bool,
+    pub compressed_tokens: bool,
+    pub metadata: Option<JoinMetadata>
+}
+
+impl Serializable for SnapshotMember {
+    type Output = SnapshotMember;
+
+    fn write(&self, buf: &mut Vec<u8>) -> TResult {
+        self.id.write(buf)?;
+        write_sock_addr(buf, &self.addr)?;
+        match &self.key {
+            Some(key) => {
+                buf.write_u8(1)?;
+                write_byte_arr(buf, key.as_bytes())?;
+            },
+            None => buf.write_u8(0)?
+        }
+        buf.write_u8(self.observer as u8)?;
+        buf.write_u8(self.held_token as u8)?;
+        buf.write_u8(self.compressed_tokens as u8)?;
+        match &self.metadata {
+            Some(metadata) => {
+                buf.write_u8(1)?;
+                metadata.write(buf)
+            },
+            None => Ok(buf.write_u8(0)?)
+        }
+    }
+
+    fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
+        let id = WorkStationId::read(buf)?;
+        let addr = read_sock_addr(buf)?;
+        let key = match buf.read_u8()? {
+            0 => None,
+            _ => Some(PublicKey::from_bytes(&read_byte_arr::<PUBLIC_KEY_LENGTH>(buf)?)?)
+        };
+        let observer = buf.read_u8()? != 0;
+        let held_token = buf.read_u8()? != 0;
+        let compressed_tokens = buf.read_u8()? != 0;
+        let metadata = match buf.read_u8()? {
+            0 => None,
+            _ => Some(JoinMetadata::read(buf)?)
+        };
+        Ok(SnapshotMember {
+            id, addr, key, observer, held_token, compressed_tokens, metadata
+        })
+    }
+
+    fn size(&self) -> usize {
+        self.id.size() + 1 + get_sock_addr_size(&self.addr)
+            + 1 + self.key.map_or(0, |_| PUBLIC_KEY_LENGTH) + 3
+            + 1 + self.metadata.as_ref().map_or(0, |m| m.size())
+    }
+}
+
+impl Serializable for BanTarget {
+    type Output = BanTarget;
+
+    fn write(&self, buf: &mut Vec<u8>) -> TResult {
+        match self {
+            BanTarget::Id(id) => {
+                buf.write_u8(0)?;
+                id.write(buf)
+            },
+            BanTarget::Addr(addr) => {
+                buf.write_u8(1)?;
+                write_sock_addr(buf, addr)
+            }
+        }
+    }
+
+    fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
+        Ok(match buf.read_u8()? {
+            0 => BanTarget::Id(WorkStationId::read(buf)?),
+            1 => BanTarget::Addr(read_sock_addr(buf)?),
+            tag => return Err(GlobalError::Internal(TokenRingError::InvalidTag { kind: "BanTarget", tag }))
+        })
+    }
+
+    fn size(&self) -> usize {
+        1 + match self {
+            BanTarget::Id(id) => id.size(),
+            BanTarget::Addr(addr) => 1 + get_sock_addr_size(addr)
+        }
+    }
+}
+
+impl Serializable for RingSnapshot {
+    type Output = RingSnapshot;
+
+    fn write(&self, buf: &mut Vec<u8>) -> TResult {
+        write_vec(buf, &self.members)?;
+        buf.write_u64::<BigEndian>(self.token_generation)?;
+        match &self.token {
+            Some(token) => {
+                buf.write_u8(1)?;
+                token.write(buf)?;
+            },
+            None => buf.write_u8(0)?
+        }
+        write_vec(buf, &self.banned)
+    }
+
+    fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
+        let members = read_vec(buf)?;
+        let token_generation = buf.read_u64::<BigEndian>()?;
+        let token = match buf.read_u8()? {
+            0 => None,
+            _ => Some(Token::read(buf)?)
+        };
+        let banned = read_vec(buf)?;
+        Ok(RingSnapshot {
+            members, token_generation, token, banned
+        })
+    }
+
+    fn size(&self) -> usize {
+        4 + self.members.iter().map(|m| m.size()).sum::<usize>() + 8 + 1 +
+            self.token.as_ref().map_or(0, |t| t.size()) +
+            4 + self.banned.iter().map(|b| b.size()).sum::<usize>()
+    }
+}
+
+// State of a passive station, e.g. for a status command or diagnostics dump
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionStatus {
+    pub mode: ConnectionMode,
+    pub holds_token: bool,
+    // Frames waiting for the next token
+    pub cached_frames: usize
+}
+
+impl Serializable for ConnectionMode {
+    type Output = ConnectionMode;
+
+    fn write(&self, buf: &mut Vec<u8>) -> TResult {
+        match self {
+            ConnectionMode::Offline => buf.write_u8(0)?,
+            ConnectionMode::Pending(addr) => {
+                buf.write_u8(1)?;
+                write_sock_addr(buf, addr)?;
+            },
+            ConnectionMode::Connected(id, addr) => {
+                buf.write_u8(2)?;
+                id.write(buf)?;
+                write_sock_addr(buf, addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
+        Ok(match buf.read_u8()? {
+            0 => ConnectionMode::Offline,
+            1 => ConnectionMode::Pending(read_sock_addr(buf)?),
+            2 => {
+                let id = WorkStationId::read(buf)?;
+                ConnectionMode::Connected(id, read_sock_addr(buf)?)
+            },
+            tag => return Err(GlobalError::Internal(TokenRingError::InvalidTag { kind: "ConnectionMode", tag }))
+        })
+    }
+
+    fn size(&self) -> usize {
+        1 + match self {
+            ConnectionMode::Offline => 0,
+            ConnectionMode::Pending(addr) => 1 + get_sock_addr_size(addr),
+            ConnectionMode::Connected(id, addr) => id.size() + 1 + get_sock_addr_size(addr)
+        }
+    }
+}
+
+impl Serializable for ConnectionStatus {
+    type Output = ConnectionStatus;
+
+    fn write(&self, buf: &mut Vec<u8>) -> TResult {
+        self.mode.write(buf)?;
+        buf.write_u8(self.holds_token as u8)?;
+        Ok(buf.write_u32::<BigEndian>(self.cached_frames as u32)?)
+    }
+
+    fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
+        let mode = ConnectionMode::read(buf)?;
+        let holds_token = buf.read_u8()? != 0;
+        let cached_frames = buf.read_u32::<BigEndian>()? as usize;
+        Ok(ConnectionStatus {
+            mode, holds_token, cached_frames
+        })
+    }
+
+    fn size(&self) -> usize {
+        self.mode.size() + 1 + 4
+    }
+}
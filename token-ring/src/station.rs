@@ -1,25 +1,207 @@
-use std::{sync::{Arc, atomic::{AtomicBool, Ordering}, Mutex}, collections::HashMap, net::{SocketAddr, SocketAddrV4, Ipv4Addr}, time::Duration};
+use std::{any::Any, sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}, Mutex}, collections::{HashMap, HashSet, VecDeque}, net::{SocketAddr, SocketAddrV4, Ipv4Addr}, time::{Duration, Instant}};
 use crossbeam_channel::{Sender, Receiver, unbounded};
-use ed25519_dalek::Keypair;
-use tokio::net::UdpSocket;
-use crate::{id::WorkStationId, comm::{QueuedPacket, WorkStationSender, WorkStationReceiver, send_loop, recv_loop}, signature::{generate_keypair, Signed}, err::{TResult, GlobalError, TokenRingError}, packet::{Packet, PacketType, PacketHeader, JoinAnswerResult}, token::{Token, TokenHeader, TokenFrame, TokenFrameType, TokenFrameId}, pass::{TokenPasser, StationStatus}};
+use ed25519_dalek::{Keypair, PublicKey};
+use tokio::{net::UdpSocket, sync::Notify, task::JoinHandle};
+use crate::{id::WorkStationId, comm::{QueuedPacket, OutgoingPacket, OutgoingHeader, WorkStationSender, WorkStationReceiver, send_loop, recv_loop, queue_packet, flush_sends, Transport, Timeouts, WireDump}, signature::{generate_keypair, fingerprint, Signed}, err::{TResult, GlobalError, TokenRingError}, serialize::Serializable, packet::{PacketHeader, PacketType, StationRole, JoinRequest, JoinMetadata, JoinAnswerResult, DenyReason, Capabilities, MAX_PASSWORD_LEN, MAX_REASON_LEN, MAX_DISPLAY_NAME_LEN}, token::{Token, TokenHeader, TokenFrame, TokenFrameType, TokenFrameId, TokenSendMode, MAX_MULTICAST_RECIPIENTS}, pass::{TokenPasser, WrongSenderPolicy, InitialHolder}, snapshot::{RingSnapshot, SnapshotMember, ConnectionStatus}, metrics::RingMetrics, event::{Event, TokenAcquiredEvent, TokenReleasedEvent, TokenConflictEvent, StationJoinedEvent, StationLeftEvent, PresenceEvent, DataEvent, AppFrameEvent, DeliveryFailedEvent, KickedEvent, SplitBrainDetectedEvent}, util::{timestamp, seq_cmp, SeqWindow}};
 
 pub type AMx<T> = Arc<Mutex<T>>;
 
+// Number of recently returned tokens the active station remembers to detect replays
+pub const SEEN_TOKENS_LEN: usize = 64;
+// Number of recently passed tokens averaged for RingMetrics::avg_token_overhead_permille
+pub const OVERHEAD_WINDOW_LEN: usize = 32;
+// Number of recently surfaced broadcast and multicast frames a passive station
+// remembers to surface each only once
+pub const SEEN_DATA_LEN: usize = 1024;
+// Number of foreign active stations (key, addr) a passive station remembers
+// after reporting them, see SplitBrainDetectedEvent
+pub const SEEN_SPLIT_BRAIN_LEN: usize = 16;
+// Reports of newly seen foreign active stations are at least this far apart
+pub const SPLIT_BRAIN_INTERVAL: Duration = Duration::from_secs(1);
+
+// Turns the payload of an app frame into an application value
+pub type AppFrameDecoder = Box<dyn Fn(&[u8]) -> TResult<Box<dyn Any>> + Send>;
+// Edits the first token of a rotation before it is passed (see ActiveStation::on_new_token)
+pub type NewTokenHook = Box<dyn FnMut(&mut Token) + Send>;
+
 pub fn create_amx<T>(val: T) -> AMx<T> {
     Arc::new(Mutex::new(val))
 }
 
 pub struct Config {
     pub id: WorkStationId,
-    pub keypair: Keypair,
-    pub accept_conns: bool
+    // Shared with packets waiting in the send queue
+    pub keypair: Arc<Keypair>,
+    pub accept_conns: bool,
+    // Sort data frames of received tokens by frame timestamp (opt-in, costs a sort)
+    pub sort_frames: bool,
+    // Sign every appended frame with the station keypair
+    pub sign_frames: bool,
+    pub join_retry: JoinRetry,
+    // Resend schedule for unacknowledged key rotations (see rotate_key)
+    pub key_rotation_retry: JoinRetry,
+    pub frame_limits: FrameLimits,
+    // Queue frames while not connected; they go out with the first token
+    pub buffer_offline: bool,
+    pub resend: ResendPolicy,
+    pub departed_frames: DepartedFrames,
+    // Outbound packets that may wait for the send loop before sends fail
+    pub max_queued_packets: usize,
+    // Send tokens compressed to peers that support it (see Capabilities)
+    pub compress_tokens: bool,
+    // Confirm every received token right away with a TokenAck
+    pub ack_tokens: bool,
+    // Sent with join requests
+    pub join_metadata: JoinMetadata
+}
+
+// Resend schedule for unanswered join requests and key rotations (exponential backoff)
+#[derive(Debug, Clone, Copy)]
+pub struct JoinRetry {
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub max_attempts: u32
+}
+
+impl JoinRetry {
+    pub fn new(base_delay: Duration, max_delay: Duration, max_attempts: u32) -> JoinRetry {
+        JoinRetry {
+            base_delay, max_delay, max_attempts
+        }
+    }
+
+    // Delay after the n-th (1-based) sent request
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.base_delay.saturating_mul(factor).min(self.max_delay)
+    }
+}
+
+impl Default for JoinRetry {
+    fn default() -> Self {
+        JoinRetry::new(Duration::from_secs(1), Duration::from_secs(8), 5)
+    }
+}
+
+// Bounds for frames a passive station queues for the token. Defaults keep one
+// station's frames within the receive buffer (RECV_BUF_LENGTH). The token as a
+// whole is only bounded by the active station's SourceBudget and frame expiry.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameLimits {
+    // Frames of this station waiting in cache or current token
+    pub max_frames: usize,
+    // Encoded size of a single frame content in bytes
+    pub max_frame_size: usize
+}
+
+impl FrameLimits {
+    pub fn new(max_frames: usize, max_frame_size: usize) -> FrameLimits {
+        FrameLimits {
+            max_frames, max_frame_size
+        }
+    }
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        FrameLimits::new(8, 384)
+    }
+}
+
+// When unicast data frames without a DataReceived ack are resent
+#[derive(Debug, Clone, Copy)]
+pub struct ResendPolicy {
+    // Token receipts (including the one carrying the frame) to wait for an ack
+    pub ack_rotations: u32,
+    // Resends before the frame is given up on (see DeliveryFailedEvent)
+    pub max_retries: u32
+}
+
+impl ResendPolicy {
+    pub fn new(ack_rotations: u32, max_retries: u32) -> ResendPolicy {
+        ResendPolicy {
+            ack_rotations, max_retries
+        }
+    }
+}
+
+impl Default for ResendPolicy {
+    fn default() -> Self {
+        ResendPolicy::new(3, 3)
+    }
+}
+
+// What the active station does with unicast frames in the token whose
+// destination left the ring
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DepartedFrames {
+    #[default]
+    Drop,
+    // Replace with a DeliveryFailed frame for the sender
+    Notify
+}
+
+// Unicast frame waiting for its ack
+struct PendingAck {
+    frame: TokenFrameType,
+    rotations: u32,
+    retries: u32
+}
+
+// Station ID or address on the active station's deny-list
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BanTarget {
+    Id(WorkStationId),
+    Addr(SocketAddr)
+}
+
+impl From<WorkStationId> for BanTarget {
+    fn from(id: WorkStationId) -> Self {
+        BanTarget::Id(id)
+    }
+}
+
+impl From<SocketAddr> for BanTarget {
+    fn from(addr: SocketAddr) -> Self {
+        BanTarget::Addr(addr)
+    }
+}
+
+// Frames (and their encoded bytes) a single station may have in the token.
+// The active station drops the newest frames beyond it.
+#[derive(Debug, Clone, Copy)]
+pub struct SourceBudget {
+    pub max_frames: usize,
+    pub max_bytes: usize
+}
+
+impl SourceBudget {
+    pub fn new(max_frames: usize, max_bytes: usize) -> SourceBudget {
+        SourceBudget {
+            max_frames, max_bytes
+        }
+    }
 }
 
 pub struct GlobalConfig {
     password: String,
+    // Password is not checked if false (e.g. when joins are restricted to authorized keys)
+    require_password: bool,
+    // Longer passwords are rejected without comparing (at most MAX_PASSWORD_LEN)
+    max_password_len: usize,
+    // Only stations signing with one of these keys may join, if set
+    authorized_keys: Option<Vec<PublicKey>>,
     accept_connections: bool,
     max_connections: u16,
+    // Stations in rotation needed before the first token is passed
+    min_members: u16,
+    // Frames each holder may add to the token per turn (unlimited if None)
+    source_budget: Option<SourceBudget>,
+    // Tokens returned by another member than the expected holder
+    wrong_sender: WrongSenderPolicy,
+    initial_holder: InitialHolder,
+    // Passes after which the token is regenerated with only its data frames (unlimited if None)
+    max_hops: Option<u32>,
     max_passover_time: f32
 }
 
@@ -27,16 +209,36 @@ impl GlobalConfig {
     pub fn new(password: String, accept_connections: bool, max_connections: u16,
         max_passover_time: f32) -> GlobalConfig {
         GlobalConfig {
-            password, accept_connections, max_connections, max_passover_time
+            password, require_password: true, max_password_len: MAX_PASSWORD_LEN, authorized_keys: None,
+            accept_connections, max_connections, min_members: 0, source_budget: None,
+            wrong_sender: WrongSenderPolicy::default(),
+            initial_holder: InitialHolder::default(), max_hops: None, max_passover_time
         }
     }
+
+    // Rejects values the ring can not run with. Checked when hosting.
+    pub fn validate(&self) -> TResult {
+        let reason = if !(self.max_passover_time.is_finite() && self.max_passover_time > 0.) {
+            "max_passover_time must be positive"
+        } else if self.max_connections == 0 {
+            "max_connections must be at least 1"
+        } else {
+            return Ok(())
+        };
+        Err(GlobalError::Internal(TokenRingError::InvalidConfig(reason)))
+    }
 }
 
 impl Config {
     pub fn new(id: WorkStationId) -> Config {
-        let keypair = generate_keypair();
+        let keypair = Arc::new(generate_keypair());
         Config {
-            id, keypair, accept_conns: true
+            id, keypair, accept_conns: true, sort_frames: false, sign_frames: false,
+            join_retry: JoinRetry::default(), key_rotation_retry: JoinRetry::default(), frame_limits: FrameLimits::default(),
+            buffer_offline: false, resend: ResendPolicy::default(),
+            departed_frames: DepartedFrames::default(),
+            max_queued_packets: 1024, compress_tokens: false, ack_tokens: false,
+            join_metadata: JoinMetadata::default()
         }
     }
 }
@@ -49,68 +251,500 @@ pub trait WorkStation {
 pub struct ActiveStation {
     config: Config,
     global_config: GlobalConfig,
-    sock: Arc<UdpSocket>,
     running: Arc<AtomicBool>,
+    // Stops the recv loop (see recv_loop)
+    recv_stop: Arc<Notify>,
+    local_addr: SocketAddr,
     connected_stations: HashMap<WorkStationId, SocketAddr>,
+    // Reverse of connected_stations
+    station_ids: HashMap<SocketAddr, WorkStationId>,
+    // Members that only read tokens. Not part of the rotation.
+    observers: HashSet<WorkStationId>,
+    // Public key each member joined with (trust on first use)
+    known_keys: HashMap<WorkStationId, PublicKey>,
+    // Key members used before their last KeyRotation. Accepted until they
+    // sign with the new one, since they keep the old key until our ack arrives.
+    prev_keys: HashMap<WorkStationId, PublicKey>,
+    key_rotation: Option<KeyRotationAttempt>,
+    // Members that advertised Capabilities::compressed_tokens at join
+    compressed_peers: HashSet<WorkStationId>,
+    // What members told about themselves when joining
+    member_metadata: HashMap<WorkStationId, JoinMetadata>,
+    banned: HashSet<BanTarget>,
     token_passer: TokenPasser,
+    // Maintenance mode: members stay connected, but no token is passed
+    rotation_paused: bool,
+    token_generation: u64,
+    // Pass counter of the last passed token (see TokenHeader::pass)
+    token_pass: u64,
+    // (Generation, pass, origin) of recently returned tokens, oldest first
+    seen_tokens: VecDeque<(u64, u64, WorkStationId)>,
+    // Frames of the last passed token. Anything else in a returned token was
+    // added during the holder's turn.
+    passed_frames: HashSet<TokenFrameId>,
+    // Kicked since the token was last passed on (see pass_on_token)
+    kicked: HashSet<WorkStationId>,
+    tokens_passed: u64,
+    bytes_relayed: u64,
+    // Overhead ratios of recently passed tokens, oldest first
+    overhead_samples: VecDeque<f32>,
+    new_token_hook: Option<NewTokenHook>,
+    events: VecDeque<Box<dyn Event>>,
+    timeouts: AMx<Timeouts>,
+    wire_dump: AMx<Option<WireDump>>,
 
-    send_queue: Sender<QueuedPacket>,
-    recv_queue: Receiver<QueuedPacket>
+    send_queue: Sender<OutgoingPacket>,
+    in_flight: Arc<AtomicUsize>,
+    recv_queue: Receiver<QueuedPacket>,
+    send_errors: Receiver<TokenRingError>,
+    // Feeds recv_queue without the transport (see inject_packet)
+    #[cfg(test)]
+    recv_inject: Sender<QueuedPacket>
 }
 
 impl ActiveStation {
+    // Port 0 lets the OS pick a free port (see local_addr)
     pub async fn host(id: WorkStationId, global_config: GlobalConfig, port: u16) -> TResult<ActiveStation> {
+        // Fail before binding
+        global_config.validate()?;
         // Bind socket to local addr and port and wrap into arc for passing to bg threads
         let sock = UdpSocket::bind(SocketAddrV4::new(
             Ipv4Addr::UNSPECIFIED, port)).await?;
-        let sock_arced = Arc::new(sock);
+        Self::with_transport(id, global_config, Arc::new(sock))
+    }
+
+    pub fn with_transport<T: Transport>(id: WorkStationId, global_config: GlobalConfig,
+        sock_arced: Arc<T>) -> TResult<ActiveStation> {
+        global_config.validate()?;
         let running = Arc::new(AtomicBool::new(true));
+        let local_addr = sock_arced.local_addr()?;
 
         // Sender handles all outgoing packets (serializing, transport) in a
         // background thread
+        let timeouts = create_amx(Timeouts::default());
         let send_queue = unbounded();
-        let sender = WorkStationSender::new(running.clone(),
-            sock_arced.clone(), send_queue.1);
+        let send_errors = unbounded();
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let wire_dump = create_amx(None);
+        let sender = WorkStationSender::new(running.clone(), sock_arced.clone(), timeouts.clone(),
+            wire_dump.clone(), send_queue.1, send_errors.0, in_flight.clone());
         send_loop(sender)?;
         
         // Recv handles all incoming packets, deserializing, buffering
         // and event generation in a backtround thread
         let recv_queue = unbounded();
-        let recv = WorkStationReceiver::new(
-            running.clone(), sock_arced.clone(), recv_queue.0);
+        let recv_stop = Arc::new(Notify::new());
+        let recv = WorkStationReceiver::new(running.clone(), recv_stop.clone(),
+            sock_arced.clone(), timeouts.clone(), wire_dump.clone(), recv_queue.0.clone());
         recv_loop(recv)?;
         
         // The token passer stores current token rotating in the ring and
         // stores which stations already owned the token and in which
         // order and time it should be passed on.
-        let token_passer = TokenPasser::new(global_config.max_passover_time);
+        let mut token_passer = TokenPasser::new(global_config.max_passover_time);
+        token_passer.set_wrong_sender_policy(global_config.wrong_sender);
+        token_passer.set_initial_holder(global_config.initial_holder.clone());
         Ok(ActiveStation {
-            config: Config::new(id), global_config: global_config,
-            sock: sock_arced, running,
-            connected_stations: HashMap::new(), token_passer,
-            send_queue: send_queue.0, recv_queue: recv_queue.1
+            config: Config::new(id), global_config, running, recv_stop, local_addr,
+            connected_stations: HashMap::new(), station_ids: HashMap::new(), observers: HashSet::new(), known_keys: HashMap::new(), prev_keys: HashMap::new(), key_rotation: None, compressed_peers: HashSet::new(), member_metadata: HashMap::new(), banned: HashSet::new(), token_passer, rotation_paused: false, token_generation: 0, token_pass: 0, seen_tokens: VecDeque::new(), passed_frames: HashSet::new(), kicked: HashSet::new(), tokens_passed: 0, bytes_relayed: 0, overhead_samples: VecDeque::new(), new_token_hook: None,
+            events: VecDeque::new(), timeouts, wire_dump,
+            send_queue: send_queue.0, in_flight, recv_queue: recv_queue.1, send_errors: send_errors.1,
+            #[cfg(test)]
+            recv_inject: recv_queue.0
+        })
+    }
+
+    // Hosts a ring that resumes from a snapshot. Members are not notified,
+    // they keep passing the token to the same address. Takes the keypair the
+    // snapshotted station signed with, since members pinned its public key.
+    pub fn restore<T: Transport>(id: WorkStationId, global_config: GlobalConfig, keypair: Arc<Keypair>,
+        sock_arced: Arc<T>, snapshot: RingSnapshot) -> TResult<ActiveStation> {
+        let mut station = Self::with_transport(id, global_config, sock_arced)?;
+        station.config.keypair = keypair;
+        for member in snapshot.members.into_iter() {
+            station.connected_stations.insert(member.id.clone(), member.addr);
+            station.station_ids.insert(member.addr, member.id.clone());
+            if let Some(key) = member.key {
+                station.known_keys.insert(member.id.clone(), key);
+            }
+            if member.compressed_tokens {
+                station.compressed_peers.insert(member.id.clone());
+            }
+            if let Some(metadata) = member.metadata {
+                station.member_metadata.insert(member.id.clone(), metadata);
+            }
+            if member.observer {
+                station.observers.insert(member.id);
+                continue
+            }
+            station.token_passer.add_station(member.id.clone());
+            if let Some(status) = station.token_passer.station_status.get_mut(&member.id) {
+                status.0 = member.held_token;
+            }
+        }
+        station.banned = snapshot.banned.into_iter().collect();
+        station.token_generation = snapshot.token_generation;
+        station.token_passer.curr_token = snapshot.token;
+        Ok(station)
+    }
+
+    // Hosts a ring with a predefined membership in the given rotation order,
+    // skipping the join handshake (e.g. for testing rotation logic). Members
+    // must sign with the given keys, just as if they had joined with them.
+    pub fn with_members<T: Transport>(id: WorkStationId, global_config: GlobalConfig,
+        sock_arced: Arc<T>, members: Vec<(WorkStationId, SocketAddr, PublicKey)>) -> TResult<ActiveStation> {
+        let members = members.into_iter()
+            .map(|(id, addr, key)| SnapshotMember {
+                id, addr, key: Some(key), observer: false, held_token: false,
+                compressed_tokens: false, metadata: None
+            })
+            .collect();
+        Self::restore(id, global_config, Arc::new(generate_keypair()), sock_arced, RingSnapshot {
+            members, token_generation: 0, token: None, banned: vec![]
         })
     }
 
+    pub fn snapshot(&self) -> RingSnapshot {
+        let members = self.token_passer.rotation_order().iter().chain(self.observers.iter()).filter_map(|id|
+            self.connected_stations.get(id).map(|addr| SnapshotMember {
+                id: id.clone(), addr: *addr,
+                key: self.known_keys.get(id).copied(),
+                observer: self.observers.contains(id),
+                held_token: self.token_passer.station_status.get(id).is_some_and(|s| s.0),
+                compressed_tokens: self.compressed_peers.contains(id),
+                metadata: self.member_metadata.get(id).cloned()
+            })).collect::<Vec<_>>();
+        RingSnapshot {
+            members, token_generation: self.token_generation,
+            token: self.token_passer.curr_token.clone(),
+            banned: self.banned.iter().cloned().collect()
+        }
+    }
+
+    // Signing keypair, to be persisted alongside snapshots (see restore)
+    pub fn keypair(&self) -> Arc<Keypair> {
+        self.config.keypair.clone()
+    }
+
+    // Address the station's socket is actually bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // Version and display name the member joined with
+    pub fn member_metadata(&self, id: &WorkStationId) -> Option<&JoinMetadata> {
+        self.member_metadata.get(id)
+    }
+
+    // Members and their addresses in rotation order, followed by observers
+    pub fn connections(&self) -> Vec<(WorkStationId, SocketAddr)> {
+        self.token_passer.rotation_order().iter().chain(self.observers.iter()).filter_map(|id|
+            self.connected_stations.get(id).map(|addr| (id.clone(), *addr))).collect()
+    }
+
+    // Member registered under the address, e.g. to address a unicast frame
+    // (TokenSendMode::Unicast) to a peer only known by its address
+    pub fn id_for_addr(&self, addr: SocketAddr) -> Option<WorkStationId> {
+        self.station_ids.get(&addr).cloned()
+    }
+
+    // Whether the member had its turn in the current rotation (returned the
+    // token or timed out). None for observers and unknown stations.
+    pub fn has_held_token(&self, id: &WorkStationId) -> Option<bool> {
+        self.token_passer.station_status.get(id).map(|status| status.0)
+    }
+
+    pub fn ring_metrics(&self) -> RingMetrics {
+        RingMetrics {
+            members: self.connected_stations.len(),
+            tokens_passed: self.tokens_passed,
+            avg_rotation_time: self.token_passer.avg_rotation_time(),
+            timed_out_stations: self.token_passer.timed_out_stations(),
+            bytes_relayed: self.bytes_relayed,
+            avg_token_overhead_permille: (!self.overhead_samples.is_empty()).then(||
+                (self.overhead_samples.iter().sum::<f32>() / self.overhead_samples.len() as f32 * 1000.).round() as u16)
+        }
+    }
+
+    pub fn rotation_order(&self) -> &[WorkStationId] {
+        self.token_passer.rotation_order()
+    }
+
+    // Reorders the ring; must name every member exactly once (see TokenPasser)
+    pub fn set_rotation_order(&mut self, order: Vec<WorkStationId>) -> TResult {
+        self.token_passer.set_rotation_order(order)
+    }
+
+    // Fingerprint of this station's identity to share with joining users
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.config.id, &self.config.keypair.public)
+    }
+
     pub fn shutdown(&mut self) {
         self.running.store(false, Ordering::Relaxed);
+        self.recv_stop.notify_one();
+    }
+
+    // Waits until all queued packets were sent (or timed out). False if it
+    // gave up on a stuck or stopped send loop.
+    pub async fn flush(&self) -> bool {
+        let send_timeout = self.timeouts.lock().unwrap().send;
+        flush_sends(&self.in_flight, &self.running, send_timeout).await
+    }
+
+    pub fn poll_event(&mut self) -> Option<Box<dyn Event>> {
+        self.events.pop_front()
+    }
+
+    // All queued events, oldest first
+    pub fn poll_events(&mut self) -> Vec<Box<dyn Event>> {
+        self.events.drain(..).collect()
+    }
+
+    // Seals (false) or reopens (true) the ring for new stations. Current
+    // members are not affected.
+    pub fn set_accepting(&mut self, accept: bool) {
+        self.global_config.accept_connections = accept;
+    }
+
+    // Applies to the next send/recv of the background loops
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        *self.timeouts.lock().unwrap() = timeouts;
+    }
+
+    // Hex dumps of every sent and received datagram (None disables)
+    pub fn set_wire_dump(&mut self, wire_dump: Option<WireDump>) {
+        *self.wire_dump.lock().unwrap() = wire_dump;
+    }
+
+    // Called with the first token of every rotation, and with tokens minted
+    // from scratch (see force_new_token, GlobalConfig::max_hops), e.g. to
+    // append frames of the active station. Replaces the previous hook.
+    pub fn on_new_token(&mut self, hook: NewTokenHook) {
+        self.new_token_hook = Some(hook);
+    }
+
+    // Restricts joins to stations signing with one of the keys (None allows any key).
+    // Current members are not affected.
+    pub fn set_authorized_keys(&mut self, keys: Option<Vec<PublicKey>>) {
+        self.global_config.authorized_keys = keys;
+    }
+
+    pub fn set_source_budget(&mut self, source_budget: Option<SourceBudget>) {
+        self.global_config.source_budget = source_budget;
+    }
+
+    pub fn set_max_hops(&mut self, max_hops: Option<u32>) {
+        self.global_config.max_hops = max_hops;
+    }
+
+    // Has no effect once the first token was passed
+    pub fn set_initial_holder(&mut self, initial_holder: InitialHolder) {
+        self.global_config.initial_holder = initial_holder.clone();
+        self.token_passer.set_initial_holder(initial_holder);
+    }
+
+    pub fn set_wrong_sender_policy(&mut self, wrong_sender: WrongSenderPolicy) {
+        self.global_config.wrong_sender = wrong_sender;
+        self.token_passer.set_wrong_sender_policy(wrong_sender);
+    }
+
+    // Holders that do not ack a token pass within this time are skipped early.
+    // Only useful if all members ack (see Config::ack_tokens).
+    pub fn set_ack_timeout(&mut self, ack_timeout: Option<Duration>) {
+        self.token_passer.set_ack_timeout(ack_timeout);
+    }
+
+    // Lets operators tune the ring while it runs. Also applies to the current holder.
+    pub fn set_max_passover_time(&mut self, max_passover_time: Duration) -> TResult {
+        if max_passover_time.is_zero() {
+            return Err(GlobalError::Internal(TokenRingError::InvalidConfig("max_passover_time must be positive")))
+        }
+        self.global_config.max_passover_time = max_passover_time.as_secs_f32();
+        self.token_passer.set_max_passover_time(max_passover_time.as_secs_f32());
+        Ok(())
+    }
+
+    pub fn max_passover_time(&self) -> Duration {
+        Duration::from_secs_f32(self.token_passer.max_passover_time())
+    }
+
+    // Most extra time a holder may get per turn with hold requests (zero, the
+    // default, ignores them)
+    pub fn set_max_hold_extension(&mut self, max_hold_extension: Duration) {
+        self.token_passer.set_max_hold_extension(max_hold_extension);
+    }
+
+    // Throttles rotation: passes are at least this far apart (no limit if zero)
+    pub fn set_min_pass_interval(&mut self, min_pass_interval: Duration) {
+        self.token_passer.set_min_pass_interval(min_pass_interval);
+    }
+
+    // Only applies to members that advertised support when joining
+    pub fn set_compress_tokens(&mut self, compress_tokens: bool) {
+        self.config.compress_tokens = compress_tokens;
+    }
+
+    // Passing waits until this many stations are in rotation (observers do not count)
+    pub fn set_min_members(&mut self, min_members: u16) {
+        self.global_config.min_members = min_members;
+    }
+
+    // Join requests with longer passwords are denied. Capped at MAX_PASSWORD_LEN,
+    // which is enforced when the packet is read.
+    pub fn set_max_password_len(&mut self, max_password_len: usize) {
+        self.global_config.max_password_len = max_password_len.min(MAX_PASSWORD_LEN);
+    }
+
+    // Skips the password check on join, e.g. when keys are authorized instead
+    pub fn set_require_password(&mut self, require_password: bool) {
+        self.global_config.require_password = require_password;
+    }
+
+    pub fn set_departed_frames(&mut self, departed_frames: DepartedFrames) {
+        self.config.departed_frames = departed_frames;
+    }
+
+    // Kicks matching members and drops all further packets from the ID or address
+    pub fn ban(&mut self, target: impl Into<BanTarget>) {
+        let target = target.into();
+        let kicked = self.connected_stations.iter()
+            .filter(|(id, addr)| match &target {
+                BanTarget::Id(banned_id) => *id == banned_id,
+                BanTarget::Addr(banned_addr) => *addr == banned_addr
+            })
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        for id in kicked.iter() {
+            println!("Removing banned station {id}.");
+            self.remove_station(id);
+        }
+        self.banned.insert(target);
+    }
+
+    pub fn unban(&mut self, target: impl Into<BanTarget>) {
+        self.banned.remove(&target.into());
+    }
+
+    // Removes a member without banning it. Frames it appended are dropped
+    // with it; it may join again.
+    pub async fn kick(&mut self, id: &WorkStationId, reason: String) -> TResult {
+        if reason.len() > MAX_REASON_LEN {
+            return Err(GlobalError::Internal(TokenRingError::LengthTooLarge {
+                len: reason.len(), max: MAX_REASON_LEN }))
+        }
+        let addr = self.get_station_addr(id).ok_or_else(|| GlobalError::Internal(
+            TokenRingError::InvalidWorkStationId(id.clone(), self.config.id.clone())))?;
+        println!("Kicking {id}{:?}: {reason}.", addr);
+        self.send_packet(addr, PacketType::Kicked { reason }).await?;
+        // The token may be out, so its frames are cleared when passed on next
+        self.kicked.insert(id.clone());
+        self.remove_station(id);
+        Ok(())
+    }
+
+    fn is_banned(&self, id: &WorkStationId, addr: SocketAddr) -> bool {
+        self.banned.contains(&BanTarget::Id(id.clone())) ||
+            self.banned.contains(&BanTarget::Addr(addr))
+    }
+
+    // Sends fail with SendQueueFull while this many packets are waiting
+    pub fn set_max_queued_packets(&mut self, max_queued_packets: usize) {
+        self.config.max_queued_packets = max_queued_packets;
+    }
+
+    // Switches to a fresh keypair. Every member is told the new key in a
+    // packet signed with the old one, which stays in use until all members
+    // acknowledged the new key. Unacknowledged announcements are resent by recv_all.
+    pub async fn rotate_key(&mut self) -> TResult {
+        let keypair = Arc::new(generate_keypair());
+        self.send_to_all(PacketType::KeyRotation { new_key: keypair.public }).await?;
+        self.key_rotation = Some(KeyRotationAttempt::new(keypair, &self.config.key_rotation_retry));
+        self.finish_key_rotation();
+        Ok(())
+    }
+
+    pub fn set_key_rotation_retry(&mut self, key_rotation_retry: JoinRetry) {
+        self.config.key_rotation_retry = key_rotation_retry;
+    }
+
+    // Switches to the announced key once every member acknowledged it
+    fn finish_key_rotation(&mut self) {
+        if self.key_rotation.as_ref().is_some_and(|rotation|
+            self.connected_stations.keys().all(|id| rotation.acked.contains(id))) {
+            println!("All members acknowledged the new key. Switching to it.");
+            self.config.keypair = self.key_rotation.take().unwrap().keypair;
+        }
+    }
+
+    // Announces the new key again to members that did not acknowledge it.
+    // Gives up with KeyRotationTimedOut and keeps the current key after the
+    // configured max attempts.
+    async fn resend_key_rotation(&mut self) -> TResult {
+        self.finish_key_rotation();
+        let retry = self.config.key_rotation_retry;
+        let rotation = match self.key_rotation.as_mut() {
+            Some(rotation) if Instant::now() >= rotation.next_retry => rotation,
+            _ => return Ok(())
+        };
+        if rotation.attempts >= retry.max_attempts {
+            println!("Members did not acknowledge the new key after {} announcements. Keeping the current key.",
+                rotation.attempts);
+            self.key_rotation = None;
+            return Err(GlobalError::Internal(TokenRingError::KeyRotationTimedOut))
+        }
+        rotation.attempts += 1;
+        rotation.next_retry = Instant::now() + retry.delay(rotation.attempts);
+        let new_key = rotation.keypair.public;
+        let addrs = self.connected_stations.iter()
+            .filter(|(id, _)| !rotation.acked.contains(*id))
+            .map(|(_, addr)| *addr)
+            .collect::<Vec<_>>();
+        for addr in addrs.into_iter() {
+            self.send_packet(addr, PacketType::KeyRotation { new_key }).await?;
+        }
+        Ok(())
+    }
+
+    // Stations that miss the passover time this many times in a row are
+    // skipped for a rotation
+    pub fn set_max_timeouts(&mut self, max_timeouts: u32) {
+        self.token_passer.set_max_timeouts(max_timeouts);
     }
 
     async fn send_packet(&mut self, dest_addr: SocketAddr,
         packet: PacketType) -> TResult {
-        let packet = Packet::new(
-            // Move packet header signature into background send thread?
-            // Hash generation is fast on eddsa algorithm but send loop exists for a reason 
-            Signed::new(&self.config.keypair, 
-                PacketHeader::new(self.config.id.clone()))?, 
-            packet);
-        Ok(self.send_queue.send(QueuedPacket(packet, dest_addr))?)
+        if self.send_queue.len() >= self.config.max_queued_packets {
+            return Err(GlobalError::Internal(TokenRingError::SendQueueFull))
+        }
+        queue_packet(&self.send_queue, &self.in_flight, OutgoingPacket::new(self.config.keypair.clone(),
+            self.config.id.clone(), packet, dest_addr))
+    }
+
+    // Queues the same packet for every member. The header is signed once
+    // here instead of once per packet in the send loop.
+    async fn send_to_all(&mut self, packet: PacketType) -> TResult {
+        let count = self.connected_stations.len();
+        if self.send_queue.len() + count > self.config.max_queued_packets {
+            return Err(GlobalError::Internal(TokenRingError::SendQueueFull))
+        }
+        let header = Signed::new(&self.config.keypair, PacketHeader::new(self.config.id.clone()))?;
+        for addr in self.connected_stations.values() {
+            queue_packet(&self.send_queue, &self.in_flight, OutgoingPacket {
+                header: OutgoingHeader::Signed(header.clone()), content: packet.clone(), dest: *addr })?;
+        }
+        Ok(())
     }
 
     // async fn recv_packet(&mut self) -> TResult<PacketType> {
     // }
 
     pub async fn recv_all(&mut self) -> TResult {
+        // Surface sends the background loop had to give up on
+        if let Ok(e) = self.send_errors.try_recv() {
+            return Err(GlobalError::Internal(e))
+        }
+        self.resend_key_rotation().await?;
         while let Ok(packet) = self.recv_queue.try_recv() {
             let source_id = &packet.0.header.val.source;
             // Check signature and destination ID
@@ -119,14 +753,74 @@ impl ActiveStation {
                     source_id, packet.1);
                 return Err(e)
             } else {
+                let signed_with_pinned = self.known_keys.get(source_id) == Some(packet.0.header.public_key());
+                // Member switched to its new key, the old one is void
+                if signed_with_pinned && self.prev_keys.remove(source_id).is_some() {
+                    println!("{:?}{:?} switched to its new key.", source_id, packet.1);
+                }
                 match packet.0.content {
-                    PacketType::JoinRequest(pw) => 
-                        self.recv_join_request(packet.1, source_id.clone(), pw).await?,
-                    PacketType::JoinReply(_) => {
-                        println!("Received join reply by {:?}{:?} as active station. Discarding.", source_id, packet.1)
+                    PacketType::JoinRequest(request) => {
+                        let rejoin = self.get_station_addr(source_id).is_some();
+                        self.recv_join_request(packet.1, source_id.clone(), request.password,
+                            request.observer, request.nonce, packet.0.header.public_key()).await?;
+                        // A confirmed re-join was signed with the pinned key, membership is unchanged
+                        if rejoin {
+                            continue
+                        }
+                        // Pin the key the station joined with
+                        self.known_keys.insert(source_id.clone(), *packet.0.header.public_key());
+                        self.member_metadata.insert(source_id.clone(), request.metadata);
+                        if request.capabilities.compressed_tokens {
+                            self.compressed_peers.insert(source_id.clone());
+                        } else {
+                            self.compressed_peers.remove(source_id);
+                        }
                     },
+                    PacketType::JoinReply(..) | PacketType::Kicked { .. } =>
+                        unreachable!("Rejected by verify_recv_packet"),
                     PacketType::TokenPass(token) => self.recv_token_pass(packet.1, source_id, token).await?,
+                    PacketType::TokenPassCompressed(bytes) =>
+                        self.recv_token_pass(packet.1, source_id, Token::decompress(&bytes)?).await?,
                     PacketType::Leave() => self.recv_leave(packet. 1, source_id).await?,
+                    PacketType::TokenAck { pass } => {
+                        if pass == self.token_pass && self.token_passer.acknowledge(source_id) {
+                            println!("{:?}{:?} acknowledged token.", source_id, packet.1);
+                        } else {
+                            println!("{:?}{:?} acknowledged token it does not hold. Ignoring.", source_id, packet.1);
+                        }
+                    },
+                    PacketType::TokenHoldRequest { extra } => {
+                        if self.token_passer.extend_hold(source_id, extra) {
+                            println!("{:?}{:?} holds token for up to {:?} longer.", source_id, packet.1, extra);
+                        } else {
+                            println!("{:?}{:?} requested to hold token it does not hold. Ignoring.", source_id, packet.1);
+                        }
+                    },
+                    PacketType::KeyRotation { new_key } => {
+                        // Only the pinned key may hand over trust. Announcements
+                        // resent with the previous key (lost ack) are only acknowledged again.
+                        if signed_with_pinned && self.known_keys.get(source_id) != Some(&new_key) {
+                            println!("{:?}{:?} rotated its signing key.", source_id, packet.1);
+                            if let Some(old_key) = self.known_keys.insert(source_id.clone(), new_key) {
+                                self.prev_keys.insert(source_id.clone(), old_key);
+                            }
+                        }
+                        if self.known_keys.get(source_id) == Some(&new_key) {
+                            self.send_packet(packet.1, PacketType::KeyRotationAck { new_key }).await?;
+                        } else {
+                            println!("{:?}{:?} announced a new key signed with an outdated one. Ignoring.", source_id, packet.1);
+                        }
+                    },
+                    PacketType::KeyRotationAck { new_key } => {
+                        match self.key_rotation.as_mut() {
+                            Some(rotation) if rotation.keypair.public == new_key => {
+                                rotation.acked.insert(source_id.clone());
+                                self.finish_key_rotation();
+                            },
+                            _ => println!("{:?}{:?} acknowledged a key that is not being rotated to. Ignoring.",
+                                source_id, packet.1)
+                        }
+                    }
                 };
             }
         }
@@ -134,78 +828,137 @@ impl ActiveStation {
     }
 
     async fn recv_join_request(&mut self, join_addr: SocketAddr, join_id: WorkStationId,
-        pw: String) -> TResult {
+        pw: String, observer: bool, nonce: u64, key: &PublicKey) -> TResult {
         if let Some(addr) = self.get_station_addr(&join_id) {
-            if addr == join_addr {
-                println!("{:?}{:?} attempted to join ring twice. Blocking attempt.", join_id, join_id);
-                self.send_packet(addr, 
-                    PacketType::JoinReply(
-                        JoinAnswerResult::Deny("Already joined".to_owned()))).await?;
-                return Err(GlobalError::Internal(
-                    TokenRingError::RejectedJoinAttempt(join_id, "Already Joined".to_owned())))
+            let pinned = self.known_keys.get(&join_id) == Some(key);
+            if pinned && addr == join_addr {
+                // Reconnect (e.g. join reply got lost). Confirm again, membership is unchanged.
+                println!("{:?}{:?} requested to join again. Confirming.", join_id, addr);
+                return self.send_packet(addr, PacketType::JoinReply(
+                    JoinAnswerResult::Confirm(self.config.id.clone()), nonce)).await
+            } else if pinned && !self.station_ids.contains_key(&join_addr) {
+                // Moved to another address (see PassiveStation::rebind). The pinned
+                // key vouches for it, membership is unchanged.
+                println!("{:?} rejoined from {:?}, was {:?}. Confirming.", join_id, join_addr, addr);
+                let observer = self.observers.contains(&join_id);
+                self.add_station(join_id, join_addr, observer);
+                return self.send_packet(join_addr, PacketType::JoinReply(
+                    JoinAnswerResult::Confirm(self.config.id.clone()), nonce)).await
             } else {
-                // Work station joined again but with new socket addr.
-                println!("{:?}{:?} attempted to join with new socket addr {:?}. Passing.", join_id, addr, join_addr)
+                // Never hand an existing ID over to another address or key
+                println!("{:?}{:?} attempted to join with ID of {:?}. Denying.", join_id, join_addr, addr);
+                self.send_packet(join_addr, PacketType::JoinReply(
+                    JoinAnswerResult::Deny(DenyReason::IdInUse), nonce)).await?;
+                return Err(GlobalError::Internal(
+                    TokenRingError::RejectedJoinAttempt(join_id, DenyReason::IdInUse)))
             }
         }
 
-        if let Err(e) = self.check_join_request(&join_id, pw) {
-            // TOOD: Improve deny reason
+        // Without a required password its content is never looked at
+        if self.global_config.require_password && pw.len() > self.global_config.max_password_len {
+            println!("Denied join request of {:?}{:?}: password too long.", join_id, join_addr);
+            self.send_packet(join_addr, PacketType::JoinReply(
+                JoinAnswerResult::Deny(DenyReason::WrongPassword), nonce)).await?;
+            return Err(GlobalError::Internal(TokenRingError::LengthTooLarge {
+                len: pw.len(), max: self.global_config.max_password_len }))
+        }
+
+        if let Err(reason) = self.check_join_request(pw, key) {
+            println!("Denied join request of {:?}{:?}: {reason}.", join_id, join_addr);
             self.send_packet(join_addr, 
                 PacketType::JoinReply(
-                    JoinAnswerResult::Deny("Invalid config".to_owned()))).await?;
-            return Err(e)
+                    JoinAnswerResult::Deny(reason.clone()), nonce)).await?;
+            return Err(GlobalError::Internal(
+                TokenRingError::RejectedJoinAttempt(join_id, reason)))
         } else {
-            let join_reply = PacketType::JoinReply(JoinAnswerResult::Confirm(self.config.id.clone()));
+            let join_reply = PacketType::JoinReply(JoinAnswerResult::Confirm(self.config.id.clone()), nonce);
             self.send_packet(join_addr, 
                 join_reply).await?;
-            self.add_station(join_id.clone(), join_addr);
+            self.add_station(join_id.clone(), join_addr, observer);
 
-            println!("Added new station to ring: {:?}{:?}.", join_id, join_addr);
+            println!("Added new {} to ring: {:?}{:?}.",
+                if observer { "observer" } else { "station" }, join_id, join_addr);
             Ok(())
         }
     }
 
-    fn check_join_request(&self, join_id: &WorkStationId, pw: String) -> TResult {
-        let err = if !self.global_config.accept_connections {
-            TokenRingError::RejectedJoinAttempt(
-                join_id.clone(), "New connections blocked".to_owned())
+    fn check_join_request(&self, pw: String, key: &PublicKey) -> Result<(), DenyReason> {
+        if !self.global_config.accept_connections {
+            Err(DenyReason::ConnectionsBlocked)
         } else if self.connected_stations.len() >=
             self.global_config.max_connections as usize {
-            TokenRingError::RejectedJoinAttempt(
-                join_id.clone(), format!("Max connections reached ({})", self.global_config.max_connections))
-        } else if self.global_config.password != pw {
-            TokenRingError::RejectedJoinAttempt(
-                join_id.clone(), "Incorrect password".to_owned())
+            Err(DenyReason::RingFull(self.global_config.max_connections))
+        } else if self.global_config.authorized_keys.as_ref().is_some_and(|keys| !keys.contains(key)) {
+            Err(DenyReason::KeyNotAuthorized)
+        } else if self.global_config.require_password && self.global_config.password != pw {
+            Err(DenyReason::WrongPassword)
         } else {
-            return Ok(())
-        };
-        Err(GlobalError::Internal(err))
+            Ok(())
+        }
     }
 
-    fn add_station(&mut self, id: WorkStationId, addr: SocketAddr) {
+    fn add_station(&mut self, id: WorkStationId, addr: SocketAddr, observer: bool) {
         if let Some(prev_station) = self.connected_stations.insert(
             id.clone(), addr) {
             println!("New station has same ID as {:?}{:?}. Replacing contact.", id, prev_station);
+            self.station_ids.remove(&prev_station);
+            self.station_ids.insert(addr, id);
         } else {
+            self.station_ids.insert(addr, id.clone());
             // If this ID didnt exist before, add to status list
-            self.token_passer.station_status.insert(id, StationStatus(false));
+            if observer {
+                self.observers.insert(id.clone());
+            } else {
+                self.token_passer.add_station(id.clone());
+            }
+            self.events.push_back(Box::new(StationJoinedEvent {
+                source: id, members: self.connected_stations.len() }));
         }
     }
 
     fn remove_station(&mut self, id: &WorkStationId) {
-        if let Some(_) = self.connected_stations.remove(id) {
-            self.token_passer.station_status.remove(id);
+        if let Some(addr) = self.connected_stations.remove(id) {
+            self.station_ids.remove(&addr);
+            self.observers.remove(id);
+            self.token_passer.remove_station(id);
+            // Station may rejoin with a fresh keypair
+            self.known_keys.remove(id);
+            self.prev_keys.remove(id);
+            self.compressed_peers.remove(id);
+            self.member_metadata.remove(id);
+            self.events.push_back(Box::new(StationLeftEvent {
+                source: id.clone(), members: self.connected_stations.len() }));
         } else {
             println!("Did not find connected station with id {id}.")
         }
     }
 
+    // Unicast frames to a departed station would circulate forever. Applied
+    // to the token being passed on, since a copy kept while the token was out
+    // is replaced once the holder returns it.
+    fn clear_departed_frames(&self, token: &mut Token) {
+        let notify = self.config.departed_frames == DepartedFrames::Notify;
+        let mut notifications = vec![];
+        token.frames.retain(|f| match &f.content {
+            TokenFrameType::Data { send_mode: TokenSendMode::Unicast(dest), seq, .. }
+                if !self.connected_stations.contains_key(dest) => {
+                if notify {
+                    notifications.push(TokenFrame::new(TokenFrameId::new(self.config.id.clone()),
+                        TokenFrameType::DeliveryFailed {
+                            source: f.id.source.clone(), dest: dest.clone(), seq: *seq }));
+                }
+                false
+            },
+            _ => true
+        });
+        token.frames.append(&mut notifications);
+    }
+
     fn get_station_addr(&self, id: &WorkStationId) -> Option<SocketAddr> {
         self.connected_stations.get(id).copied()
     }
 
-    async fn recv_token_pass(&mut self, addr: SocketAddr, id: &WorkStationId, token: Token) -> TResult {
+    async fn recv_token_pass(&mut self, addr: SocketAddr, id: &WorkStationId, mut token: Token) -> TResult {
         // Check if socket addr of token sender equals addr stored in id hashmap
         if let Some(station_addr) = self.get_station_addr(id) {
             if station_addr != addr {
@@ -213,10 +966,64 @@ impl ActiveStation {
                 return Err(GlobalError::Internal(TokenRingError::InvalidToken(id.clone(), token)));
             }
         }
-        self.token_passer.recv_token(token, id)
+        let seen = (token.generation(), token.pass(), token.origin().clone());
+        if self.seen_tokens.contains(&seen) {
+            println!("{:?}{:?} passed token of pass {} again. Discarding replay.", id, addr, seen.1);
+            return Err(GlobalError::Internal(TokenRingError::TokenReplay(id.clone())))
+        }
+        // Every pass gets a new pass counter, so older ones were superseded
+        // (e.g. delayed, or discarded by force_new_token)
+        if token.origin() == &self.config.id
+            && (token.generation(), token.pass()) < (self.token_generation, self.token_pass) {
+            println!("{:?}{:?} passed token of pass {}, current is {}. Discarding stale token.",
+                id, addr, token.pass(), self.token_pass);
+            return Err(GlobalError::Internal(TokenRingError::StaleToken {
+                pass: token.pass(), current: self.token_pass }))
+        }
+        self.enforce_source_budget(id, &mut token);
+        self.token_passer.recv_token(token, id)?;
+        self.seen_tokens.push_back(seen);
+        if self.seen_tokens.len() > SEEN_TOKENS_LEN {
+            self.seen_tokens.pop_front();
+        }
+        Ok(())
+    }
+
+    // Queues a packet as if it had been received from the given address
+    #[cfg(test)]
+    pub fn inject_packet(&mut self, packet: crate::packet::Packet, from: SocketAddr) {
+        self.recv_inject.send(QueuedPacket(packet, from)).unwrap();
+    }
+
+    // Stops passing tokens until resume_rotation. Joins and leaves are still handled.
+    pub fn pause_rotation(&mut self) {
+        self.rotation_paused = true;
+        // Resuming must not count the current holder as timed out
+        self.token_passer.set_idle();
+    }
+
+    pub fn resume_rotation(&mut self) {
+        self.rotation_paused = false;
     }
 
     pub async fn poll_token_pass(&mut self) -> TResult {
+        if self.rotation_paused {
+            return Err(GlobalError::Internal(TokenRingError::RotationPaused))
+        }
+        let members = self.token_passer.rotation_order().len();
+        let min = self.global_config.min_members as usize;
+        if members < min {
+            self.token_passer.set_idle();
+            return Err(GlobalError::Internal(TokenRingError::QuorumNotMet { members, min }))
+        }
+        // A lone member would only pass frames to itself. Hold the token
+        // until a second station joins.
+        if members == 1 {
+            if self.token_passer.set_idle() {
+                println!("Only one station in ring. Holding token until another one joins.");
+            }
+            return Ok(())
+        }
         if self.token_passer.pass_ready() {
             self.pass_on_token().await
         } else {
@@ -224,6 +1031,17 @@ impl ActiveStation {
         }
     }
 
+    // Recovery: discards the circulating token with its frames and passes a
+    // fresh one to the initial holder, starting a new rotation
+    pub async fn force_new_token(&mut self) -> TResult {
+        if self.rotation_paused {
+            return Err(GlobalError::Internal(TokenRingError::RotationPaused))
+        }
+        println!("Discarding current token. Passing a new one.");
+        self.token_passer.restart();
+        self.pass_on_token().await
+    }
+
     async fn pass_on_token(&mut self) -> TResult {
         let next_station = if let Some(next_station) =
             self.token_passer.select_next_station() {
@@ -232,21 +1050,114 @@ impl ActiveStation {
             return Err(GlobalError::Internal(TokenRingError::EmptyRing))
         };
         let addr = self.get_station_addr(&next_station).unwrap();
-        // If token becomes too full, clear frames
-        let token = if let Some(token) = self.token_passer.curr_token.as_mut() {
-            if token.frames.len() > self.connected_stations.len() * 2 {
-                token.frames.clear();
+        // Every pass gets a freshly signed header with a new pass counter, so
+        // that each returned token is unique and replays can be told apart.
+        let prev_token = self.token_passer.curr_token.take();
+        let mut hops = prev_token.as_ref().map_or(0, |t| t.hops().saturating_add(1));
+        let regenerated = self.global_config.max_hops.is_some_and(|max| hops > max);
+        if regenerated {
+            println!("Warning: Token was passed {} times without reset. Regenerating it.", hops - 1);
+            hops = 0;
+        }
+        let fresh = prev_token.is_none();
+        let mut token = if fresh || regenerated {
+            self.generate_token(hops)?
+        } else {
+            self.reissue_token(hops)?
+        };
+        if let Some(prev_token) = prev_token {
+            token.frames = prev_token.frames;
+            if regenerated {
+                // Data frames still circulating may not have reached every recipient
+                token.frames.retain(|f| matches!(f.content, TokenFrameType::Data { .. }));
+            }
+            self.clear_departed_frames(&mut token);
+            token.frames.retain(|f| !self.kicked.contains(&f.id.source));
+            token.drop_expired_frames(timestamp());
+            // Presence frames have gone full circle once they are back at their source
+            token.frames.retain(|f| !(matches!(f.content, TokenFrameType::Presence { .. })
+                && f.id.source == next_station));
+        }
+        if fresh || regenerated || self.token_passer.rotation_started() {
+            if let Some(hook) = self.new_token_hook.as_mut() {
+                hook(&mut token);
             }
-            token.clone()
+        }
+        if !fresh {
+            self.token_passer.curr_token = Some(token.clone());
+        }
+        self.passed_frames = token.frames.iter().map(|f| f.id.clone()).collect();
+        self.kicked.clear();
+
+        self.token_passer.pass_token(next_station.clone());
+        self.tokens_passed += 1;
+        self.bytes_relayed += token.size() as u64;
+        self.overhead_samples.push_back(token.overhead_ratio());
+        if self.overhead_samples.len() > OVERHEAD_WINDOW_LEN {
+            self.overhead_samples.pop_front();
+        }
+        // Observers read along but never hold the token
+        let observer_addrs = self.observers.iter()
+            .filter_map(|id| self.get_station_addr(id).map(|addr| (id.clone(), addr)))
+            .collect::<Vec<_>>();
+        for (observer_id, observer_addr) in observer_addrs.into_iter() {
+            let packet = self.token_packet(&observer_id, token.clone())?;
+            self.send_packet(observer_addr, packet).await?;
+        }
+        let packet = self.token_packet(&next_station, token)?;
+        self.send_packet(addr, packet).await
+    }
+
+    // Compressed for stations that advertised support at join
+    fn token_packet(&self, id: &WorkStationId, token: Token) -> TResult<PacketType> {
+        if self.config.compress_tokens && self.compressed_peers.contains(id) {
+            Ok(PacketType::TokenPassCompressed(token.compress()?))
         } else {
-            Token::new(Signed::new(
-                    &self.config.keypair, TokenHeader::new(
-                        self.config.id.clone()))?)
+            Ok(PacketType::TokenPass(token))
+        }
+    }
+
+    // Frames added during the sender's turn count against its budget, whatever
+    // source they claim (frame sources are not authenticated). Keeps its newest
+    // frames and drops older ones once the budget is used up.
+    fn enforce_source_budget(&self, sender: &WorkStationId, token: &mut Token) {
+        let budget = match self.global_config.source_budget {
+            Some(budget) => budget,
+            None => return
         };
+        let (mut frames, mut bytes, mut exceeded) = (0, 0, false);
+        let mut keep = vec![true; token.frames.len()];
+        for (i, f) in token.frames.iter().enumerate().rev() {
+            if self.passed_frames.contains(&f.id) {
+                continue
+            }
+            exceeded |= frames + 1 > budget.max_frames || bytes + f.size() > budget.max_bytes;
+            if exceeded {
+                keep[i] = false;
+                continue
+            }
+            frames += 1;
+            bytes += f.size();
+        }
+        if exceeded {
+            println!("{} exceeded its token budget. Dropping its oldest frames.", sender);
+        }
+        let mut keep = keep.into_iter();
+        token.frames.retain(|_| keep.next().unwrap_or(true));
+    }
 
-        self.token_passer.pass_token(next_station);
-        self.send_packet(addr, 
-            PacketType::TokenPass(token)).await
+    // Mints a new token signed by this station
+    fn generate_token(&mut self, hops: u32) -> TResult<Token> {
+        self.token_generation += 1;
+        self.reissue_token(hops)
+    }
+
+    // Signs a header for the next pass of the current generation
+    fn reissue_token(&mut self, hops: u32) -> TResult<Token> {
+        self.token_pass += 1;
+        Ok(Token::new(Signed::new(
+            &self.config.keypair, TokenHeader::new(self.config.id.clone(), self.token_generation)
+                .with_pass(self.token_pass).with_hops(hops))?))
     }
 
     async fn recv_leave(&mut self, addr: SocketAddr, id: &WorkStationId) -> TResult {
@@ -265,12 +1176,36 @@ impl ActiveStation {
     }
 
     fn verify_recv_packet(&self, packet: &QueuedPacket) -> TResult {
+        if !packet.0.content.is_valid_for(StationRole::Active) {
+            return Err(GlobalError::Internal(TokenRingError::UnexpectedPacket(
+                packet.0.header.val.source.clone(), packet.1)))
+        }
+        if self.is_banned(&packet.0.header.val.source, packet.1) {
+            return Err(GlobalError::Internal(TokenRingError::Banned(
+                packet.0.header.val.source.clone(), packet.1)))
+        }
+        // Only members may send anything but join requests. Checked before
+        // the (more expensive) signature verification.
+        let is_join = matches!(packet.0.content, PacketType::JoinRequest(..));
+        if !is_join && !self.station_ids.contains_key(&packet.1) {
+            return Err(GlobalError::Internal(TokenRingError::StationNotRegistered(
+                packet.0.header.val.source.clone(), packet.1)))
+        }
         if packet.0.header.verify() {
+            let source_id = &packet.0.header.val.source;
+            let key = packet.0.header.public_key();
+            // Join requests with a taken ID are answered by recv_join_request
+            if !is_join && self.known_keys.get(source_id).is_some_and(|known_key| known_key != key)
+                && self.prev_keys.get(source_id) != Some(key) {
+                println!("{:?}{:?} signed packet with a different key than at join.",
+                    source_id, packet.1);
+                return Err(GlobalError::Internal(TokenRingError::KeyMismatch(source_id.clone())))
+            }
             match packet.0.content {
-                PacketType::JoinRequest(_) => Ok(()),
+                PacketType::JoinRequest(..) => Ok(()),
                 _ => {
-                    if let None = self.get_station_addr(
-                        &packet.0.header.val.source).as_ref() {
+                    // Members may only speak for themselves
+                    if self.get_station_addr(&packet.0.header.val.source) != Some(packet.1) {
                         Err(GlobalError::Internal(TokenRingError::StationNotRegistered(
                             packet.0.header.val.source.clone(), packet.1)))
                     } else {
@@ -284,90 +1219,664 @@ impl ActiveStation {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum ConnectionMode {
     Offline,
     Pending(SocketAddr),
     Connected(WorkStationId, SocketAddr)
 }
 
-pub struct PassiveStation {
-    config: Config,
-    sock: Arc<UdpSocket>,
-    running: Arc<AtomicBool>,
-    conn_mode: ConnectionMode,
+// Moves a passive station between connection modes (see ConnectionMode::transition)
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionEvent {
+    // Join request sent to an active station
+    JoinRequested(SocketAddr),
+    // Join reply confirmed by the active station with this ID
+    JoinConfirmed(WorkStationId),
+    // Join request denied or timed out
+    JoinFailed,
+    // Left the ring or shut down
+    Left
+}
+
+impl ConnectionMode {
+    // Only place the connection mode changes. Rejects moves that skip a step,
+    // e.g. connecting without a pending join request.
+    pub fn transition(&mut self, event: ConnectionEvent) -> TResult {
+        let next = match (&*self, event) {
+            (ConnectionMode::Connected(_, _), ConnectionEvent::JoinRequested(_)) =>
+                return Err(GlobalError::Internal(TokenRingError::AlreadyConnected)),
+            // Pending requests may be redirected to another active station
+            (_, ConnectionEvent::JoinRequested(addr)) => ConnectionMode::Pending(addr),
+            (ConnectionMode::Pending(addr), ConnectionEvent::JoinConfirmed(id)) =>
+                ConnectionMode::Connected(id, *addr),
+            (ConnectionMode::Pending(_), ConnectionEvent::JoinFailed) => ConnectionMode::Offline,
+            (ConnectionMode::Connected(_, _), ConnectionEvent::JoinConfirmed(_)) =>
+                return Err(GlobalError::Internal(TokenRingError::AlreadyConnected)),
+            (_, ConnectionEvent::JoinConfirmed(_) | ConnectionEvent::JoinFailed) =>
+                return Err(GlobalError::Internal(TokenRingError::NotConnected)),
+            (_, ConnectionEvent::Left) => ConnectionMode::Offline
+        };
+        *self = next;
+        Ok(())
+    }
+}
+
+// Outstanding join request, resent until answered
+struct JoinAttempt {
+    // Sent again unchanged on retries, replies must echo its nonce
+    request: JoinRequest,
+    attempts: u32,
+    next_retry: Instant
+}
+
+// Key announced with KeyRotation but not acknowledged yet. The station keeps
+// signing with its current key meanwhile, so a lost announcement can be resent.
+struct KeyRotationAttempt {
+    keypair: Arc<Keypair>,
+    // Members that acknowledged the new key (active station only)
+    acked: HashSet<WorkStationId>,
+    attempts: u32,
+    next_retry: Instant
+}
+
+impl KeyRotationAttempt {
+    fn new(keypair: Arc<Keypair>, retry: &JoinRetry) -> KeyRotationAttempt {
+        KeyRotationAttempt {
+            keypair, acked: HashSet::new(), attempts: 1, next_retry: Instant::now() + retry.delay(1)
+        }
+    }
+}
+
+pub struct PassiveStation {
+    config: Config,
+    running: Arc<AtomicBool>,
+    // Stops the recv loop (see recv_loop)
+    recv_stop: Arc<Notify>,
+    // Send and recv loop, awaited to release the socket (see rebind)
+    loops: Vec<JoinHandle<()>>,
+    local_addr: SocketAddr,
+    conn_mode: ConnectionMode,
+    // Joined (or joining) as observer (see observe)
+    observer: bool,
+    join_attempt: Option<JoinAttempt>,
     cached_frames: Vec<TokenFrame>,
     curr_token: Option<Token>,
+    // Token that arrived before the join reply (see recv_next)
+    pending_token: Option<Token>,
+    // Key the active station must sign its join reply with (see connect_pinned)
+    pinned_key: Option<PublicKey>,
+    // Password of the last join request, reused by rebind
+    join_pw: Option<String>,
+    // Key of the active station, taken from the join reply. Tokens must be signed with it.
+    active_key: Option<PublicKey>,
+    // Key of the active station before its last KeyRotation. Accepted until
+    // the new one is seen, since it keeps the old key until all members acked.
+    prev_active_key: Option<PublicKey>,
+    key_rotation: Option<KeyRotationAttempt>,
+    // Held token arrived compressed, so the active station accepts it back compressed
+    compress_reply: bool,
+    app_decoders: HashMap<u16, AppFrameDecoder>,
+    // Sent unicast frames by (destination, seq)
+    pending_acks: HashMap<(WorkStationId, u16), PendingAck>,
+    // Seqs of surfaced unicast frames per source
+    seen_data: HashMap<WorkStationId, SeqWindow>,
+    // IDs of recently surfaced broadcast and multicast frames, oldest first
+    seen_frames: VecDeque<TokenFrameId>,
+    // Reported foreign active stations, oldest first, and when the last one
+    // was reported. Keeps spoofed traffic from flooding the event queue.
+    seen_split_brain: VecDeque<(PublicKey, SocketAddr)>,
+    last_split_brain: Option<Instant>,
+    events: VecDeque<Box<dyn Event>>,
+    timeouts: AMx<Timeouts>,
+    wire_dump: AMx<Option<WireDump>>,
 
-    send_queue: Sender<QueuedPacket>,
-    recv_queue: Receiver<QueuedPacket>
+    send_queue: Sender<OutgoingPacket>,
+    in_flight: Arc<AtomicUsize>,
+    recv_queue: Receiver<QueuedPacket>,
+    send_errors: Receiver<TokenRingError>,
+    // Feeds recv_queue without the transport (see inject_packet)
+    #[cfg(test)]
+    recv_inject: Sender<QueuedPacket>
 }
 
 impl PassiveStation {
+    // Port 0 lets the OS pick a free port (see local_addr)
     pub async fn new(id: WorkStationId, port: u16) -> TResult<PassiveStation> {
         let sock = UdpSocket::bind(SocketAddrV4::new(
             Ipv4Addr::UNSPECIFIED, port)).await?;
-        let sock_arced = Arc::new(sock);
+        Self::with_transport(id, Arc::new(sock))
+    }
+
+    pub fn with_transport<T: Transport>(id: WorkStationId, sock_arced: Arc<T>) -> TResult<PassiveStation> {
         let running = Arc::new(AtomicBool::new(true));
+        let local_addr = sock_arced.local_addr()?;
 
+        let timeouts = create_amx(Timeouts::default());
         let send_queue = unbounded();
-        let sender = WorkStationSender::new(running.clone(),
-            sock_arced.clone(), send_queue.1);
-        send_loop(sender)?;
+        let send_errors = unbounded();
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let wire_dump = create_amx(None);
+        let sender = WorkStationSender::new(running.clone(), sock_arced.clone(), timeouts.clone(),
+            wire_dump.clone(), send_queue.1, send_errors.0, in_flight.clone());
+        let send_handle = send_loop(sender)?;
 
         let recv_queue = unbounded();
-        let recv = WorkStationReceiver::new(running.clone(),
-            sock_arced.clone(), recv_queue.0);
-        recv_loop(recv)?;
+        let recv_stop = Arc::new(Notify::new());
+        let recv = WorkStationReceiver::new(running.clone(), recv_stop.clone(),
+            sock_arced.clone(), timeouts.clone(), wire_dump.clone(), recv_queue.0.clone());
+        let recv_handle = recv_loop(recv)?;
 
         Ok(PassiveStation {
-            config: Config::new(id), sock: sock_arced.clone(), running,
-            conn_mode: ConnectionMode::Offline, cached_frames: vec![],
-            curr_token: None,
-            send_queue: send_queue.0, recv_queue: recv_queue.1
+            config: Config::new(id), running, recv_stop, loops: vec![send_handle, recv_handle], local_addr,
+            conn_mode: ConnectionMode::Offline, observer: false, join_attempt: None, cached_frames: vec![],
+            curr_token: None, pending_token: None, pinned_key: None, join_pw: None, active_key: None, prev_active_key: None, key_rotation: None, compress_reply: false, app_decoders: HashMap::new(), pending_acks: HashMap::new(), seen_data: HashMap::new(), seen_frames: VecDeque::new(), seen_split_brain: VecDeque::new(), last_split_brain: None,
+            events: VecDeque::new(), timeouts, wire_dump,
+            send_queue: send_queue.0, in_flight, recv_queue: recv_queue.1, send_errors: send_errors.1,
+            #[cfg(test)]
+            recv_inject: recv_queue.0
         })
     }
 
+    // Moves the station to a new local port, e.g. after a network change broke
+    // the old socket. A joined (or joining) station requests to join the same ring again.
+    pub async fn rebind(&mut self, port: u16) -> TResult {
+        // The old socket may hold the port, so it is released first. If binding
+        // fails, the station has no socket until a rebind succeeds.
+        self.flush().await;
+        self.stop_loops().await;
+        let sock = UdpSocket::bind(SocketAddrV4::new(
+            Ipv4Addr::UNSPECIFIED, port)).await?;
+        self.rebind_transport(Arc::new(sock)).await
+    }
+
+    // Stops the send and recv loop and waits until both let go of the socket
+    async fn stop_loops(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        self.recv_stop.notify_one();
+        for handle in self.loops.drain(..) {
+            if let Err(e) = handle.await {
+                println!("Socket loop failed: {e}.");
+            }
+        }
+    }
+
+    pub async fn rebind_transport<T: Transport>(&mut self, sock_arced: Arc<T>) -> TResult {
+        let rejoin_addr = match &self.conn_mode {
+            ConnectionMode::Pending(addr) | ConnectionMode::Connected(_, addr) => Some(*addr),
+            ConnectionMode::Offline => None
+        };
+        // Nothing new is sent over the old socket, which may have gone away
+        // with the network. The active station accepts the re-join from the
+        // new address, since it is signed with the same key. Packets queued
+        // so far get a bounded chance to go out (see flush_sends).
+        self.flush().await;
+        self.stop_loops().await;
+
+        let mut fresh = PassiveStation::with_transport(self.config.id.clone(), sock_arced)?;
+        *fresh.timeouts.lock().unwrap() = *self.timeouts.lock().unwrap();
+        *fresh.wire_dump.lock().unwrap() = self.wire_dump.lock().unwrap().clone();
+        std::mem::swap(&mut self.running, &mut fresh.running);
+        std::mem::swap(&mut self.recv_stop, &mut fresh.recv_stop);
+        std::mem::swap(&mut self.loops, &mut fresh.loops);
+        std::mem::swap(&mut self.local_addr, &mut fresh.local_addr);
+        std::mem::swap(&mut self.timeouts, &mut fresh.timeouts);
+        std::mem::swap(&mut self.wire_dump, &mut fresh.wire_dump);
+        std::mem::swap(&mut self.send_queue, &mut fresh.send_queue);
+        std::mem::swap(&mut self.in_flight, &mut fresh.in_flight);
+        std::mem::swap(&mut self.recv_queue, &mut fresh.recv_queue);
+        std::mem::swap(&mut self.send_errors, &mut fresh.send_errors);
+        #[cfg(test)]
+        std::mem::swap(&mut self.recv_inject, &mut fresh.recv_inject);
+        // Old loops are already stopped
+        drop(fresh);
+        println!("Rebound passive station {} to {}.", self.config.id, self.local_addr);
+
+        // Own frames of a held token go out with the next one. The token itself
+        // was passed to the old address and is timed out by the active station.
+        if let Some(token) = self.curr_token.take() {
+            let own_frames = token.frames.into_iter().filter(|f| f.id.source == self.config.id);
+            self.cached_frames.splice(0..0, own_frames);
+        }
+        // Membership carries over, so do queued frames and delivery state
+        let kept = (std::mem::take(&mut self.cached_frames), std::mem::take(&mut self.pending_acks),
+            std::mem::take(&mut self.seen_data), std::mem::take(&mut self.seen_frames));
+        self.reset_ring_state()?;
+        (self.cached_frames, self.pending_acks, self.seen_data, self.seen_frames) = kept;
+        match (rejoin_addr, self.join_pw.clone()) {
+            (Some(addr), Some(pw)) => self.request_join(addr, pw),
+            _ => Ok(())
+        }
+    }
+
     pub async fn connect(&mut self, addr: SocketAddr, pw: String) -> TResult {
-        self.send_packet_to(addr, PacketType::JoinRequest(pw))?;
-        self.conn_mode = ConnectionMode::Pending(addr);
+        self.observer = false;
+        self.pinned_key = None;
+        self.request_join(addr, pw)
+    }
+
+    // Like connect, but only trusts a join reply signed with expected_key
+    // (obtained out of band) instead of the key of the first reply
+    pub async fn connect_pinned(&mut self, addr: SocketAddr, pw: String, expected_key: PublicKey) -> TResult {
+        self.observer = false;
+        self.pinned_key = Some(expected_key);
+        self.request_join(addr, pw)
+    }
+
+    // Joins without taking part in the rotation. Observers receive a copy of
+    // every passed token, but cannot append frames or pass it on.
+    pub async fn observe(&mut self, addr: SocketAddr, pw: String) -> TResult {
+        self.observer = true;
+        self.pinned_key = None;
+        self.request_join(addr, pw)
+    }
+
+    fn request_join(&mut self, addr: SocketAddr, pw: String) -> TResult {
+        self.conn_mode.transition(ConnectionEvent::JoinRequested(addr))?;
+        self.join_pw = Some(pw.clone());
+        let request = JoinRequest {
+            password: pw, observer: self.observer, capabilities: self.capabilities(),
+            nonce: rand::random(), metadata: self.config.join_metadata.clone()
+        };
+        self.send_packet_to(addr, PacketType::JoinRequest(request.clone()))?;
+        self.join_attempt = Some(JoinAttempt {
+            request, attempts: 1,
+            next_retry: Instant::now() + self.config.join_retry.delay(1)
+        });
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            compressed_tokens: self.config.compress_tokens
+        }
+    }
+
+    // Resends an unanswered join request once its backoff delay expired.
+    // Gives up with JoinTimedOut after the configured max attempts.
+    pub fn tick(&mut self) -> TResult {
+        self.resend_key_rotation()?;
+        let addr = match &self.conn_mode {
+            ConnectionMode::Pending(addr) => *addr,
+            _ => return Ok(())
+        };
+        let retry = self.config.join_retry;
+        let (request, attempts) = match self.join_attempt.as_ref() {
+            Some(attempt) if Instant::now() >= attempt.next_retry =>
+                (attempt.request.clone(), attempt.attempts),
+            _ => return Ok(())
+        };
+
+        if attempts >= retry.max_attempts {
+            println!("Active station {:?} did not answer {attempts} join requests. Giving up.", addr);
+            self.join_attempt = None;
+            self.conn_mode.transition(ConnectionEvent::JoinFailed)?;
+            return Err(GlobalError::Internal(TokenRingError::JoinTimedOut(addr)))
+        }
+
+        println!("No join reply from {:?} yet. Resending request (attempt {}).", addr, attempts + 1);
+        self.send_packet_to(addr, PacketType::JoinRequest(request))?;
+        if let Some(attempt) = self.join_attempt.as_mut() {
+            attempt.attempts += 1;
+            attempt.next_retry = Instant::now() + retry.delay(attempt.attempts);
+        }
         Ok(())
     }
 
+    // Address the station's socket is actually bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn connection_status(&self) -> ConnectionStatus {
+        ConnectionStatus {
+            mode: self.conn_mode.clone(), holds_token: self.holds_token(),
+            cached_frames: self.cached_frames.len()
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        matches!(self.conn_mode, ConnectionMode::Connected(_, _))
+    }
+
+    pub fn set_join_retry(&mut self, join_retry: JoinRetry) {
+        self.config.join_retry = join_retry;
+    }
+
     pub async fn shutdown(&mut self) -> TResult {
         self.send_packet(PacketType::Leave())?;
-        // Sleep on main thread for 1 sec so that background thread can
-        // send goodbye in time.
-        tokio::time::sleep(Duration::from_secs(2)).await;
+        // Background thread must send goodbye before it stops
+        self.flush().await;
         self.running.store(false, Ordering::Relaxed);
-        self.conn_mode = ConnectionMode::Offline;
+        self.recv_stop.notify_one();
+        self.conn_mode.transition(ConnectionEvent::Left)?;
+        self.active_key = None;
+        self.prev_active_key = None;
+        self.key_rotation = None;
         println!("Shutdown passive station {}.", self.config.id);
         Ok(())
     }
 
-    pub fn append_frame(&mut self, frame: TokenFrameType) {
-        let frame_container = TokenFrame::new(TokenFrameId::new(
+    // Waits until all queued packets were sent (or timed out). False if it
+    // gave up on a stuck or stopped send loop.
+    pub async fn flush(&self) -> bool {
+        let send_timeout = self.timeouts.lock().unwrap().send;
+        flush_sends(&self.in_flight, &self.running, send_timeout).await
+    }
+
+    // Leaves the current ring (if any) and requests to join the ring at new_addr.
+    // Leaves are not acknowledged by active stations; since the send queue is
+    // FIFO, the leave goes out before the join request.
+    pub async fn switch_ring(&mut self, new_addr: SocketAddr, pw: String) -> TResult {
+        if let ConnectionMode::Connected(id, _) = &self.conn_mode {
+            println!("Leaving ring of {id}.");
+            self.send_packet(PacketType::Leave())?;
+        }
+        self.reset_ring_state()?;
+        self.connect(new_addr, pw).await
+    }
+
+    // Forgets everything tied to the current ring
+    fn reset_ring_state(&mut self) -> TResult {
+        self.conn_mode.transition(ConnectionEvent::Left)?;
+        self.active_key = None;
+        self.prev_active_key = None;
+        self.key_rotation = None;
+        self.compress_reply = false;
+        self.join_attempt = None;
+        self.curr_token = None;
+        self.pending_token = None;
+        self.cached_frames.clear();
+        self.pending_acks.clear();
+        self.seen_data.clear();
+        self.seen_frames.clear();
+        Ok(())
+    }
+
+    // Switches to a fresh keypair once the active station acknowledged it.
+    // Announced with a packet signed by the old key, resent by tick until then.
+    pub fn rotate_key(&mut self) -> TResult {
+        let keypair = Arc::new(generate_keypair());
+        self.send_packet(PacketType::KeyRotation { new_key: keypair.public })?;
+        self.key_rotation = Some(KeyRotationAttempt::new(keypair, &self.config.key_rotation_retry));
+        Ok(())
+    }
+
+    pub fn set_key_rotation_retry(&mut self, key_rotation_retry: JoinRetry) {
+        self.config.key_rotation_retry = key_rotation_retry;
+    }
+
+    // Gives up with KeyRotationTimedOut and keeps the current key after the
+    // configured max attempts
+    fn resend_key_rotation(&mut self) -> TResult {
+        let retry = self.config.key_rotation_retry;
+        let (new_key, attempts) = match self.key_rotation.as_ref() {
+            Some(rotation) if Instant::now() >= rotation.next_retry =>
+                (rotation.keypair.public, rotation.attempts),
+            _ => return Ok(())
+        };
+        if attempts >= retry.max_attempts {
+            println!("Active station did not acknowledge the new key after {attempts} announcements. Keeping the current key.");
+            self.key_rotation = None;
+            return Err(GlobalError::Internal(TokenRingError::KeyRotationTimedOut))
+        }
+        println!("New key not acknowledged yet. Announcing it again (attempt {}).", attempts + 1);
+        self.send_packet(PacketType::KeyRotation { new_key })?;
+        if let Some(rotation) = self.key_rotation.as_mut() {
+            rotation.attempts += 1;
+            rotation.next_retry = Instant::now() + retry.delay(rotation.attempts);
+        }
+        Ok(())
+    }
+
+    // App frames of unregistered types are ignored
+    pub fn register_app_frame(&mut self, type_id: u16, decoder: AppFrameDecoder) {
+        if self.app_decoders.insert(type_id, decoder).is_some() {
+            println!("Replaced decoder for app frame type {type_id}.");
+        }
+    }
+
+    pub fn append_frame(&mut self, frame: TokenFrameType) -> TResult {
+        self.append_frames(vec![frame])
+    }
+
+    // Queues all frames or none of them. Fails on the first frame that
+    // breaks the configured frame limits.
+    pub fn append_frames(&mut self, frames: Vec<TokenFrameType>) -> TResult {
+        if self.observer {
+            return Err(GlobalError::Internal(TokenRingError::ObserverReadOnly))
+        }
+        if !self.config.buffer_offline && !self.is_connected() {
+            return Err(GlobalError::Internal(TokenRingError::NotConnected))
+        }
+        let limits = self.config.frame_limits;
+        let queued = self.cached_frames.len() + self.curr_token.as_ref().map_or(0,
+            |t| t.frames.iter().filter(|f| f.id.source == self.config.id).count());
+        for (index, frame) in frames.iter().enumerate() {
+            if frame.size() > limits.max_frame_size {
+                return Err(GlobalError::Internal(TokenRingError::FrameTooLarge {
+                    index, size: frame.size(), max: limits.max_frame_size }))
+            }
+            if queued + index >= limits.max_frames {
+                return Err(GlobalError::Internal(TokenRingError::TooManyFrames {
+                    index, max: limits.max_frames }))
+            }
+            // Would only travel the ring to come back to us
+            if matches!(frame, TokenFrameType::Data { send_mode: TokenSendMode::Unicast(dest), .. }
+                if dest == &self.config.id) {
+                return Err(GlobalError::Internal(TokenRingError::SelfAddressed { index }))
+            }
+            if let TokenFrameType::Data { send_mode: TokenSendMode::Multicast(dests), .. } = frame {
+                if dests.len() > MAX_MULTICAST_RECIPIENTS {
+                    return Err(GlobalError::Internal(TokenRingError::LengthTooLarge {
+                        len: dests.len(), max: MAX_MULTICAST_RECIPIENTS }))
+                }
+            }
+        }
+
+        for frame in frames.into_iter() {
+            if let TokenFrameType::Data { send_mode: TokenSendMode::Unicast(dest), seq, .. } = &frame {
+                self.pending_acks.insert((dest.clone(), *seq), PendingAck {
+                    frame: frame.clone(), rotations: 0, retries: 0 });
+            }
+            let frame_container = self.frame_container(frame);
+            if let Some(token) = self.get_token_mut() {
+                token.frames.push(frame_container);
+            } else {
+                self.cached_frames.push(frame_container);
+            }
+        }
+        Ok(())
+    }
+
+    fn frame_container(&self, frame: TokenFrameType) -> TokenFrame {
+        let mut frame_container = TokenFrame::new(TokenFrameId::new(
             self.config.id.clone()), frame);
-        if let Some(token) = self.get_token_mut() {
-            token.frames.push(frame_container);
-        } else {
-            self.cached_frames.push(frame_container);
+        if self.config.sign_frames {
+            if let Err(e) = frame_container.sign(&self.config.keypair) {
+                println!("Failed to sign frame: {e}.");
+            }
+        }
+        frame_container
+    }
+
+    pub fn set_resend_policy(&mut self, resend: ResendPolicy) {
+        self.config.resend = resend;
+    }
+
+    // Clears acked unicast frames and resends (or gives up on) the ones
+    // that waited too long
+    fn process_acks(&mut self, token: &mut Token) {
+        for frame in token.frames.iter() {
+            match &frame.content {
+                TokenFrameType::DataReceived { source, seq } if source == &self.config.id => {
+                    self.pending_acks.remove(&(frame.id.source.clone(), *seq));
+                },
+                TokenFrameType::DeliveryFailed { source, dest, seq } if source == &self.config.id => {
+                    println!("{dest} left the ring before frame {seq} was delivered.");
+                    self.pending_acks.remove(&(dest.clone(), *seq));
+                    self.events.push_back(Box::new(DeliveryFailedEvent {
+                        source: self.config.id.clone(), dest: dest.clone(), seq: *seq }));
+                },
+                _ => ()
+            }
+        }
+        token.frames.retain(|f| !matches!(&f.content,
+            TokenFrameType::DeliveryFailed { source, .. } if source == &self.config.id));
+
+        let policy = self.config.resend;
+        let mut resend = vec![];
+        let mut failed = vec![];
+        for (key, pending) in self.pending_acks.iter_mut() {
+            pending.rotations += 1;
+            if pending.rotations < policy.ack_rotations {
+                continue
+            }
+            if pending.retries < policy.max_retries {
+                pending.rotations = 0;
+                pending.retries += 1;
+                resend.push((key.clone(), pending.frame.clone()));
+            } else {
+                failed.push(key.clone());
+            }
+        }
+        // Resends go out in seq order per destination
+        resend.sort_by(|((a_dest, a_seq), _), ((b_dest, b_seq), _)|
+            a_dest.cmp(b_dest).then(seq_cmp(*a_seq, *b_seq)));
+        for (_, frame) in resend.into_iter() {
+            token.frames.push(self.frame_container(frame));
         }
+        for (dest, seq) in failed.into_iter() {
+            println!("No ack from {dest} for frame {seq}. Giving up.");
+            self.pending_acks.remove(&(dest.clone(), seq));
+            self.events.push_back(Box::new(DeliveryFailedEvent {
+                source: self.config.id.clone(), dest, seq }));
+        }
+    }
+
+    pub fn set_buffer_offline(&mut self, buffer_offline: bool) {
+        self.config.buffer_offline = buffer_offline;
+    }
+
+    pub fn set_frame_limits(&mut self, frame_limits: FrameLimits) {
+        self.config.frame_limits = frame_limits;
     }
 
     pub fn get_token_mut(&mut self) -> Option<&mut Token> {
         self.curr_token.as_mut()
     }
 
+    pub fn set_sort_frames(&mut self, sort_frames: bool) {
+        self.config.sort_frames = sort_frames;
+    }
+
+    pub fn set_sign_frames(&mut self, sign_frames: bool) {
+        self.config.sign_frames = sign_frames;
+    }
+
+    // Sent when joining, so set it before connect
+    pub fn set_join_metadata(&mut self, join_metadata: JoinMetadata) -> TResult {
+        if let Some(len) = join_metadata.display_name.as_ref().map(|name| name.len())
+            .filter(|len| *len > MAX_DISPLAY_NAME_LEN) {
+            return Err(GlobalError::Internal(TokenRingError::LengthTooLarge {
+                len, max: MAX_DISPLAY_NAME_LEN }))
+        }
+        self.config.join_metadata = join_metadata;
+        Ok(())
+    }
+
+    // Advertised when joining, so set it before connect
+    pub fn set_compress_tokens(&mut self, compress_tokens: bool) {
+        self.config.compress_tokens = compress_tokens;
+    }
+
+    // Older active stations do not know TokenAck, only enable for current ones
+    pub fn set_ack_tokens(&mut self, ack_tokens: bool) {
+        self.config.ack_tokens = ack_tokens;
+    }
+
+    // Sends fail with SendQueueFull while this many packets are waiting
+    pub fn set_max_queued_packets(&mut self, max_queued_packets: usize) {
+        self.config.max_queued_packets = max_queued_packets;
+    }
+
+    // Applies to the next send/recv of the background loops
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        *self.timeouts.lock().unwrap() = timeouts;
+    }
+
+    // Hex dumps of every sent and received datagram (None disables)
+    pub fn set_wire_dump(&mut self, wire_dump: Option<WireDump>) {
+        *self.wire_dump.lock().unwrap() = wire_dump;
+    }
+
+    pub fn holds_token(&self) -> bool {
+        self.curr_token.is_some()
+    }
+
+    // Acks for frames this station sent, as (receiver, seq), in the held token
+    pub fn received_acks(&self) -> impl Iterator<Item = (&WorkStationId, u16)> {
+        self.curr_token.iter().flat_map(|token| token.frames.iter())
+            .filter_map(|frame| match &frame.content {
+                TokenFrameType::DataReceived { source, seq } if source == &self.config.id =>
+                    Some((&frame.id.source, *seq)),
+                _ => None
+            })
+    }
+
+    pub fn poll_event(&mut self) -> Option<Box<dyn Event>> {
+        self.events.pop_front()
+    }
+
+    // All queued events, oldest first
+    pub fn poll_events(&mut self) -> Vec<Box<dyn Event>> {
+        self.events.drain(..).collect()
+    }
+
+    // Asks the active station for more time before the held token times out,
+    // e.g. while sending a burst of frames
+    pub fn request_hold(&mut self, extra: Duration) -> TResult {
+        if self.observer {
+            return Err(GlobalError::Internal(TokenRingError::ObserverReadOnly))
+        }
+        if self.curr_token.is_none() {
+            return Err(GlobalError::Internal(TokenRingError::TokenPending))
+        }
+        self.send_packet(PacketType::TokenHoldRequest { extra })
+    }
+
     pub fn pass_on_token(&mut self) -> TResult {
         if let Some(curr_token) = self.curr_token.take() {
-            self.send_packet(PacketType::TokenPass(curr_token))
+            // Observers hold a copy, the active station does not expect it back
+            if !self.observer {
+                let packet = if self.compress_reply && self.config.compress_tokens {
+                    PacketType::TokenPassCompressed(curr_token.compress()?)
+                } else {
+                    PacketType::TokenPass(curr_token)
+                };
+                self.send_packet(packet)?;
+            }
+            self.events.push_back(Box::new(TokenReleasedEvent {
+                source: self.config.id.clone() }));
+            Ok(())
         } else {
             Err(GlobalError::Internal(TokenRingError::TokenPending))
         }
     }
 
     pub async fn recv_next(&mut self) -> TResult {
+        if let Ok(e) = self.send_errors.try_recv() {
+            return Err(GlobalError::Internal(e))
+        }
         if let Ok(packet) = self.recv_queue.try_recv() {
+            if let ConnectionMode::Connected(expected, _) = &self.conn_mode {
+                let expected = expected.clone();
+                self.detect_split_brain(&packet, expected);
+            }
+            if !packet.0.content.is_valid_for(StationRole::Passive) {
+                return Err(GlobalError::Internal(TokenRingError::UnexpectedPacket(
+                    packet.0.header.val.source, packet.1)))
+            }
             match &self.conn_mode {
                 ConnectionMode::Connected(
                     target_id, target_addr) => {
@@ -376,8 +1885,51 @@ impl PassiveStation {
                             if &packet.0.header.val.source == target_id {
                                 // Packet is legit; continue.
                                 match packet.0.content {
-                                    PacketType::TokenPass(token) => self.recv_token_pass(token),
-                                    n @ _ => println!("Received invalid packet type: {:?}.", n)
+                                    PacketType::TokenPass(token) => self.accept_token(token, false)?,
+                                    PacketType::TokenPassCompressed(bytes) =>
+                                        self.accept_token(Token::decompress(&bytes)?, true)?,
+                                    PacketType::KeyRotation { new_key } => {
+                                        // Only the current key may hand over trust. Announcements resent
+                                        // with the previous key (lost ack) are only acknowledged again.
+                                        if self.active_key.as_ref().is_some_and(|key| packet.0.header.verify_key(key)) {
+                                            if self.active_key != Some(new_key) {
+                                                println!("Active station rotated its signing key.");
+                                                self.prev_active_key = self.active_key.replace(new_key);
+                                            }
+                                        } else if !(self.active_key == Some(new_key) && self.prev_active_key.as_ref()
+                                            .is_some_and(|key| packet.0.header.verify_key(key))) {
+                                            println!("Received key rotation not signed by active station. Discarding.");
+                                            return Err(GlobalError::Internal(TokenRingError::InvalidSignature))
+                                        }
+                                        self.send_packet(PacketType::KeyRotationAck { new_key })?;
+                                    },
+                                    PacketType::KeyRotationAck { new_key } => {
+                                        if !self.active_key.iter().chain(self.prev_active_key.iter())
+                                            .any(|key| packet.0.header.verify_key(key)) {
+                                            println!("Received key rotation ack not signed by active station. Discarding.");
+                                            return Err(GlobalError::Internal(TokenRingError::InvalidSignature))
+                                        }
+                                        match self.key_rotation.take() {
+                                            Some(rotation) if rotation.keypair.public == new_key => {
+                                                println!("Active station acknowledged the new key. Switching to it.");
+                                                self.config.keypair = rotation.keypair;
+                                            },
+                                            rotation => {
+                                                println!("Received ack for a key that is not being rotated to. Ignoring.");
+                                                self.key_rotation = rotation;
+                                            }
+                                        }
+                                    },
+                                    PacketType::Kicked { reason } => {
+                                        if !self.active_key.iter().chain(self.prev_active_key.iter())
+                                            .any(|key| packet.0.header.verify_key(key)) {
+                                            println!("Received kick not signed by active station. Discarding.");
+                                            return Err(GlobalError::Internal(TokenRingError::InvalidSignature))
+                                        }
+                                        self.recv_kick(reason)?
+                                    },
+                                    PacketType::JoinReply(..) => println!("Received join reply while connected. Discarding."),
+                                    _ => unreachable!("Rejected by is_valid_for")
                                 }
                                 Ok(())
                             } else {
@@ -390,8 +1942,30 @@ impl PassiveStation {
                     },
                     _ =>  {
                         match packet.0.content {
-                            PacketType::JoinReply(result) => {
-                                self.recv_join_reply(result).await
+                            PacketType::JoinReply(_, _) if self.pinned_key.as_ref()
+                                .is_some_and(|key| !packet.0.header.verify_key(key)) => {
+                                // Join stays pending, the real reply may still arrive
+                                println!("Received join reply not signed by pinned key. Discarding.");
+                                Err(GlobalError::Internal(TokenRingError::KeyMismatch(packet.0.header.val.source)))
+                            },
+                            PacketType::JoinReply(result, nonce) => {
+                                self.recv_join_reply(result, nonce, &packet.0.header, packet.1).await
+                            },
+                            PacketType::TokenPass(token) if matches!(self.conn_mode,
+                                ConnectionMode::Pending(addr) if addr == packet.1) => {
+                                // Join reply may have been lost or reordered. Hold on to token
+                                // until join is confirmed.
+                                println!("Received token from {:?} before join reply. Holding it until join is confirmed.", packet.1);
+                                self.pending_token = Some(token);
+                                self.compress_reply = false;
+                                Ok(())
+                            },
+                            PacketType::TokenPassCompressed(bytes) if matches!(self.conn_mode,
+                                ConnectionMode::Pending(addr) if addr == packet.1) => {
+                                println!("Received token from {:?} before join reply. Holding it until join is confirmed.", packet.1);
+                                self.pending_token = Some(Token::decompress(&bytes)?);
+                                self.compress_reply = true;
+                                Ok(())
                             },
                             n @ _ => {
                                 println!("Received invalid packet: {:?}. Local station is not connected yet.", n);
@@ -405,7 +1979,37 @@ impl PassiveStation {
         }
     }
 
-    async fn recv_join_reply(&mut self, result: JoinAnswerResult) -> TResult {
+    // Queues a packet as if it had been received from the given address
+    #[cfg(test)]
+    pub fn inject_packet(&mut self, packet: crate::packet::Packet, from: SocketAddr) {
+        self.recv_inject.send(QueuedPacket(packet, from)).unwrap();
+    }
+
+    // Traffic only an active station sends, signed by a key other than the one
+    // of the joined active station. Still rejected by the caller.
+    fn detect_split_brain(&mut self, packet: &QueuedPacket, expected: WorkStationId) {
+        let from_active = packet.0.content.is_valid_for(StationRole::Passive);
+        let header = &packet.0.header;
+        if from_active && header.verify() && self.active_key.as_ref().is_some_and(|key| header.public_key() != key)
+            && self.prev_active_key.as_ref() != Some(header.public_key()) {
+            let seen = (*header.public_key(), packet.1);
+            if self.seen_split_brain.contains(&seen)
+                || self.last_split_brain.is_some_and(|last| last.elapsed() < SPLIT_BRAIN_INTERVAL) {
+                return
+            }
+            println!("Received ring traffic from another active station {} at {}.", header.val.source, packet.1);
+            self.events.push_back(Box::new(SplitBrainDetectedEvent {
+                expected, seen: header.val.source.clone(), addr: packet.1 }));
+            self.seen_split_brain.push_back(seen);
+            if self.seen_split_brain.len() > SEEN_SPLIT_BRAIN_LEN {
+                self.seen_split_brain.pop_front();
+            }
+            self.last_split_brain = Some(Instant::now());
+        }
+    }
+
+    async fn recv_join_reply(&mut self, result: JoinAnswerResult, nonce: u64,
+        header: &Signed<PacketHeader>, from: SocketAddr) -> TResult {
         let addr = match &self.conn_mode {
             ConnectionMode::Offline => {
                 println!("Received join reply without asking. Discarding.");
@@ -417,36 +2021,179 @@ impl PassiveStation {
             },
             ConnectionMode::Pending(addr) => *addr
         };
+        // Only the asked station may answer, and its key is trusted from here on
+        if from != addr {
+            println!("Received join reply from {from}, but asked {addr}. Discarding.");
+            return Err(GlobalError::Internal(TokenRingError::InvalidSocketAddress(from)))
+        }
+        if !header.verify() {
+            println!("Received join reply with invalid signature. Discarding.");
+            return Err(GlobalError::Internal(TokenRingError::InvalidSignature))
+        }
+        if self.join_attempt.as_ref().map(|attempt| attempt.request.nonce) != Some(nonce) {
+            println!("Received join reply for another join request. Discarding.");
+            return Err(GlobalError::Internal(TokenRingError::JoinNonceMismatch))
+        }
 
+        // Request answered either way; stop resending.
+        self.join_attempt = None;
         match result {
             JoinAnswerResult::Confirm(id) => {
                 println!("Active station {id} accepted connection. Joining ring.");
-                self.conn_mode = ConnectionMode::Connected(id, addr);
-                Ok(())
+                self.active_key = Some(*header.public_key());
+                if let Some(token) = self.pending_token.take() {
+                    if token.origin() == &id && self.verify_token_key(&token) {
+                        self.ack_token(&token, addr)?;
+                        self.recv_token_pass(token);
+                    } else {
+                        println!("Token received while pending is not from {id} or not signed by it. Discarding.");
+                    }
+                }
+                self.conn_mode.transition(ConnectionEvent::JoinConfirmed(id))
             },
             JoinAnswerResult::Deny(reason) => {
                 println!("Active workstation denied access: {reason}.");
+                self.pending_token = None;
+                self.conn_mode.transition(ConnectionEvent::JoinFailed)?;
                 Err(GlobalError::Internal(TokenRingError::FailedJoinAttempt(reason)))
             },
         }
     }
 
-    fn recv_token_pass(&mut self, mut token: Token) {
-        if let Some(prev_token) = self.curr_token.as_ref() {
-            println!("Already holding token: {:?}. Discarding old and accepting new one.", prev_token)
+    fn recv_kick(&mut self, reason: String) -> TResult {
+        let source = match &self.conn_mode {
+            ConnectionMode::Connected(id, _) => id.clone(),
+            _ => return Err(GlobalError::Internal(TokenRingError::NotConnected))
+        };
+        println!("Kicked from ring of {source}: {reason}.");
+        self.conn_mode.transition(ConnectionEvent::Left)?;
+        self.active_key = None;
+        self.prev_active_key = None;
+        self.key_rotation = None;
+        self.compress_reply = false;
+        self.curr_token = None;
+        self.pending_acks.clear();
+        self.seen_data.clear();
+        self.seen_frames.clear();
+        self.events.push_back(Box::new(KickedEvent { source, reason }));
+        Ok(())
+    }
+
+    fn accept_token(&mut self, token: Token, compressed: bool) -> TResult {
+        if !self.verify_token_key(&token) {
+            println!("Received token not signed by active station. Discarding.");
+            return Err(GlobalError::Internal(TokenRingError::InvalidSignature))
+        }
+        // Active station switched to its new key, the old one is void
+        if self.prev_active_key.is_some() && self.active_key.as_ref().is_some_and(|key| token.header.public_key() == key) {
+            self.prev_active_key = None;
+        }
+        self.compress_reply = compressed;
+        if let ConnectionMode::Connected(_, addr) = &self.conn_mode {
+            self.ack_token(&token, *addr)?;
+        }
+        self.recv_token_pass(token);
+        Ok(())
+    }
+
+    fn ack_token(&mut self, token: &Token, addr: SocketAddr) -> TResult {
+        if self.config.ack_tokens && !self.observer {
+            self.send_packet_to(addr, PacketType::TokenAck { pass: token.pass() })?;
         }
+        Ok(())
+    }
+
+    // Unicast data is only still owed while pending and not yet resent in the token
+    fn awaits_ack(&self, token: &Token, content: &TokenFrameType) -> bool {
+        match content {
+            TokenFrameType::Data { send_mode: TokenSendMode::Unicast(dest), seq, .. } =>
+                self.pending_acks.contains_key(&(dest.clone(), *seq))
+                    && !token.frames.iter().any(|f| f.id.source == self.config.id && matches!(&f.content,
+                        TokenFrameType::Data { send_mode: TokenSendMode::Unicast(d), seq: s, .. } if d == dest && s == seq)),
+            _ => true
+        }
+    }
+
+    fn verify_token_key(&self, token: &Token) -> bool {
+        self.active_key.iter().chain(self.prev_active_key.iter()).any(|key| token.header.verify_key(key))
+    }
+
+    fn recv_token_pass(&mut self, mut token: Token) {
+        let prev_token = self.curr_token.take();
         // Move all cached frames into new token.
         token.frames.append(&mut self.cached_frames.drain(..).collect::<Vec<_>>());
+        self.process_acks(&mut token);
+        if let Some(prev_token) = prev_token {
+            // Latest token wins, since the active station expects that one back.
+            // Frames appended to the old one are not lost, unless already acked or resent.
+            let own_frames = prev_token.frames.into_iter()
+                .filter(|f| f.id.source == self.config.id && !token.frames.contains(f)
+                    && self.awaits_ack(&token, &f.content))
+                .collect::<Vec<_>>();
+            println!("Already holding token. Replacing it with new one and moving {} own frames over.", own_frames.len());
+            self.events.push_back(Box::new(TokenConflictEvent {
+                source: token.origin().clone(), moved_frames: own_frames.len() }));
+            token.frames.extend(own_frames);
+        }
+        // Stale frames are never surfaced
+        token.drop_expired_frames(timestamp());
+        if self.config.sort_frames {
+            token.sort_data_frames();
+        }
+        for frame in token.frames.iter().filter(|f| f.id.source != self.config.id) {
+            match &frame.content {
+                TokenFrameType::Presence { status } =>
+                    self.events.push_back(Box::new(PresenceEvent {
+                        source: frame.id.source.clone(), status: *status })),
+                TokenFrameType::Data { send_mode, seq, payload, .. } if send_mode.addresses(&self.config.id) => {
+                    if let TokenSendMode::Unicast(_) = send_mode {
+                        // Resends carry the same seq under a fresh frame ID
+                        let new = match self.seen_data.get_mut(&frame.id.source) {
+                            Some(window) => window.insert(*seq),
+                            None => {
+                                self.seen_data.insert(frame.id.source.clone(), SeqWindow::new(*seq));
+                                true
+                            }
+                        };
+                        if !new {
+                            continue
+                        }
+                    } else {
+                        // Never resent, and the seq is up to the sender
+                        if self.seen_frames.contains(&frame.id) {
+                            continue
+                        }
+                        self.seen_frames.push_back(frame.id.clone());
+                        if self.seen_frames.len() > SEEN_DATA_LEN {
+                            self.seen_frames.pop_front();
+                        }
+                    }
+                    self.events.push_back(Box::new(DataEvent {
+                        source: frame.id.source.clone(), seq: *seq, payload: payload.clone() }))
+                },
+                TokenFrameType::App { type_id, payload } => {
+                    match self.app_decoders.get(type_id).map(|decode| decode(payload)) {
+                        Some(Ok(decoded)) => self.events.push_back(Box::new(AppFrameEvent {
+                            source: frame.id.source.clone(), type_id: *type_id,
+                            payload: payload.clone(), decoded })),
+                        Some(Err(e)) => println!("Failed to decode app frame {type_id} of {}: {e}.", frame.id.source),
+                        None => ()
+                    }
+                },
+                _ => ()
+            }
+        }
+        self.events.push_back(Box::new(TokenAcquiredEvent {
+            source: token.origin().clone() }));
         self.curr_token = Some(token);
     }
 
     fn send_packet_to(&mut self, addr: SocketAddr, packet: PacketType) -> TResult {
-        let packet = Packet::new(
-            // Move packet header signature into background send thread?
-            // Hash generation is fast on eddsa algorithm but send loop exists for a reason 
-            Signed::new(&self.config.keypair, 
-                PacketHeader::new(self.config.id.clone()))?, packet);
-        Ok(self.send_queue.send(QueuedPacket(packet, addr))?)
+        if self.send_queue.len() >= self.config.max_queued_packets {
+            return Err(GlobalError::Internal(TokenRingError::SendQueueFull))
+        }
+        queue_packet(&self.send_queue, &self.in_flight, OutgoingPacket::new(self.config.keypair.clone(),
+            self.config.id.clone(), packet, addr))
     }
 
     fn send_packet(&mut self, packet: PacketType) -> TResult {
@@ -457,3 +2204,2514 @@ impl PassiveStation {
         }
     }
 }
+
+// Stations dropped without shutdown still say goodbye, so that the active
+// station does not wait for them to time out. Best effort: the leave is only
+// queued, and is lost if the send loop (or its runtime) is already gone.
+impl Drop for PassiveStation {
+    fn drop(&mut self) {
+        if self.is_connected() {
+            if let Err(e) = self.send_packet(PacketType::Leave()) {
+                println!("Failed to queue leave on drop: {e}.");
+            }
+        }
+        // Send loop drains the queue before it stops
+        self.running.store(false, Ordering::Relaxed);
+        self.recv_stop.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, net::SocketAddr, sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}, time::Duration};
+    use crate::{comm::{MemoryNetwork, QueuedPacket, MemoryTransport, LossyTransport, Transport, Timeouts, OutgoingPacket, RECV_BUF_LENGTH, MAX_WIRE_DUMP_LEN, hex_dump}, packet::{Packet, PacketHeader, PacketType, JoinRequest, JoinMetadata, JoinAnswerResult, MAX_DISPLAY_NAME_LEN}, serialize::{Serializable, Serializer}, id::WorkStationId, err::{GlobalError, TokenRingError}, signature::{generate_keypair, Signed}, token::{Token, TokenHeader, TokenFrame, TokenFrameId, TokenFrameType, TokenSendMode, PresenceStatus, MAX_MULTICAST_RECIPIENTS}, util::timestamp};
+    use ed25519_dalek::Keypair;
+    use crate::event::{TokenAcquiredEvent, TokenReleasedEvent, TokenConflictEvent, StationJoinedEvent, StationLeftEvent, PresenceEvent, DataEvent, AppFrameEvent, DeliveryFailedEvent, KickedEvent, SplitBrainDetectedEvent};
+    use crate::{pass::TokenPasser, util::MockClock};
+    use super::{ActiveStation, PassiveStation, GlobalConfig, SourceBudget, JoinRetry, FrameLimits, ResendPolicy, DepartedFrames, DenyReason, ConnectionMode, ConnectionEvent, RingSnapshot, ConnectionStatus, InitialHolder};
+
+    async fn host_station() -> ActiveStation {
+        ActiveStation::host(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), 0).await.unwrap()
+    }
+
+    async fn passive_station(name: &str) -> PassiveStation {
+        PassiveStation::new(WorkStationId::new(name.to_owned()), 0).await.unwrap()
+    }
+
+    fn create_token() -> Token {
+        Token::new(Signed::new(&generate_keypair(),
+            TokenHeader::new(WorkStationId::new("Host".to_owned()), 1)).unwrap())
+    }
+
+    // Sends a join request from a bare transport and returns the reply of the active station
+    async fn request_join(active: &mut ActiveStation, client: &MemoryTransport,
+        host_addr: SocketAddr, name: &str, pw: &str) -> JoinAnswerResult {
+        request_join_with(active, client, host_addr, &generate_keypair(), name, pw).await
+    }
+
+    async fn request_join_with(active: &mut ActiveStation, client: &MemoryTransport,
+        host_addr: SocketAddr, keypair: &Keypair, name: &str, pw: &str) -> JoinAnswerResult {
+        let packet = Packet::new(Signed::new(keypair,
+            PacketHeader::new(WorkStationId::new(name.to_owned()))).unwrap(),
+            PacketType::JoinRequest(JoinRequest::new(pw.to_owned())));
+        client.send_to(&packet.serialize().unwrap(), host_addr).await.unwrap();
+
+        let mut buf = [0u8; RECV_BUF_LENGTH];
+        for _ in 0..100 {
+            let _ = active.recv_all().await;
+            if let Ok(Ok((size, _))) = tokio::time::timeout(
+                Duration::from_millis(5), client.recv_from(&mut buf)).await {
+                if let PacketType::JoinReply(result, _) = Packet::deserialize(&buf[..size]).unwrap().content {
+                    return result
+                }
+            }
+        }
+        panic!("Active station did not reply to join request.")
+    }
+
+    // Join reply of the active station "Host" to the station's pending request
+    fn host_confirm(station: &PassiveStation) -> PacketType {
+        PacketType::JoinReply(JoinAnswerResult::Confirm(WorkStationId::new("Host".to_owned())),
+            station.join_attempt.as_ref().unwrap().request.nonce)
+    }
+
+    // Sends a packet signed with given keypair from a bare transport
+    async fn send_raw(from: &MemoryTransport, to: SocketAddr, keypair: &Keypair,
+        name: &str, content: PacketType) {
+        let packet = Packet::new(Signed::new(keypair,
+            PacketHeader::new(WorkStationId::new(name.to_owned()))).unwrap(), content);
+        from.send_to(&packet.serialize().unwrap(), to).await.unwrap();
+    }
+
+    // Lets the passive station process incoming packets for a while
+    async fn pump(station: &mut PassiveStation) {
+        for _ in 0..20 {
+            let _ = station.recv_next().await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    // Next token passed to a bare transport
+    async fn recv_token(member: &MemoryTransport) -> Token {
+        let mut buf = [0u8; RECV_BUF_LENGTH];
+        while let Ok(Ok((size, _))) = tokio::time::timeout(Duration::from_millis(100), member.recv_from(&mut buf)).await {
+            if let PacketType::TokenPass(token) = Packet::deserialize(&buf[..size]).unwrap().content {
+                return token
+            }
+        }
+        panic!("No token was passed to {:?}.", member.local_addr().unwrap())
+    }
+
+    fn station_addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[tokio::test]
+    async fn seal_ring() {
+        let mut station = host_station().await;
+        station.set_accepting(false);
+        let result = station.recv_join_request(station_addr(5001),
+            WorkStationId::new("Bob".to_owned()), "pw".to_owned(), false, 0, &generate_keypair().public).await;
+        match result {
+            Err(GlobalError::Internal(TokenRingError::RejectedJoinAttempt(_, reason))) =>
+                assert_eq!(reason, DenyReason::ConnectionsBlocked),
+            _ => panic!("Join attempt should be rejected while sealed.")
+        }
+        assert!(station.connected_stations.is_empty());
+
+        station.set_accepting(true);
+        assert!(station.recv_join_request(station_addr(5001),
+            WorkStationId::new("Bob".to_owned()), "pw".to_owned(), false, 0, &generate_keypair().public).await.is_ok());
+        assert_eq!(station.connected_stations.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn sorted_frame_delivery() {
+        let mut station = passive_station("Bob").await;
+        station.set_sort_frames(true);
+        let mut token = create_token();
+        for (seq, timestamp) in [(0, 30), (1, 10), (2, 20)] {
+            token.frames.push(TokenFrame::new(TokenFrameId::with_timestamp(
+                WorkStationId::new("Alice".to_owned()), timestamp),
+                TokenFrameType::Data { send_mode: TokenSendMode::Broadcast, seq, payload: vec![], expires_at: None }));
+        }
+        station.recv_token_pass(token);
+
+        let seqs = station.get_token_mut().unwrap().frames.iter().map(|f| match f.content {
+            TokenFrameType::Data { seq, .. } => seq,
+            _ => unreachable!()
+        }).collect::<Vec<_>>();
+        assert_eq!(seqs, vec![1, 2, 0]);
+    }
+
+    #[tokio::test]
+    async fn join_retry() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        // Lose the first two join requests on their way to the active station
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let dropped_count = dropped.clone();
+        network.drop_when(move |_, to| to == host_addr &&
+            dropped_count.fetch_update(Ordering::Relaxed, Ordering::Relaxed,
+                |n| if n < 2 { Some(n + 1) } else { None }).is_ok());
+
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.),
+            Arc::new(network.bind(host_addr))).unwrap();
+        let mut passive = PassiveStation::with_transport(WorkStationId::new("Bob".to_owned()),
+            Arc::new(network.bind(station_addr(6001)))).unwrap();
+        passive.set_join_retry(JoinRetry::new(
+            Duration::from_millis(10), Duration::from_millis(40), 5));
+        passive.connect(host_addr, "pw".to_owned()).await.unwrap();
+
+        for _ in 0..100 {
+            let _ = active.recv_all().await;
+            let _ = passive.recv_next().await;
+            passive.tick().unwrap();
+            if passive.is_connected() {
+                break
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(passive.is_connected());
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn join_timeout() {
+        let network = MemoryNetwork::new();
+        let mut passive = PassiveStation::with_transport(WorkStationId::new("Bob".to_owned()),
+            Arc::new(network.bind(station_addr(6001)))).unwrap();
+        passive.set_join_retry(JoinRetry::new(
+            Duration::from_millis(1), Duration::from_millis(2), 3));
+        passive.connect(station_addr(6000), "pw".to_owned()).await.unwrap();
+
+        let mut result = Ok(());
+        for _ in 0..100 {
+            result = passive.tick();
+            if result.is_err() {
+                break
+            }
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        assert!(matches!(result, Err(GlobalError::Internal(TokenRingError::JoinTimedOut(_)))));
+        assert!(!passive.is_connected());
+    }
+
+    #[tokio::test]
+    async fn send_timeout() {
+        let network = MemoryNetwork::new();
+        network.stall_sends(true);
+        let mut passive = PassiveStation::with_transport(WorkStationId::new("Bob".to_owned()),
+            Arc::new(network.bind(station_addr(6001)))).unwrap();
+        passive.set_timeouts(Timeouts::new(Duration::from_millis(5), Duration::from_secs(30)));
+        passive.connect(station_addr(6000), "pw".to_owned()).await.unwrap();
+
+        let mut result = Ok(());
+        for _ in 0..100 {
+            result = passive.recv_next().await;
+            if result.is_err() {
+                break
+            }
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        assert!(matches!(result, Err(GlobalError::Internal(
+            TokenRingError::SendTimeout(addr))) if addr == station_addr(6000)));
+    }
+
+    #[tokio::test]
+    async fn deny_reasons() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 1, 5.),
+            Arc::new(network.bind(host_addr))).unwrap();
+        let alice = network.bind(station_addr(6001));
+        let bob = network.bind(station_addr(6002));
+
+        let alice_keypair = generate_keypair();
+
+        assert_eq!(request_join(&mut active, &alice, host_addr, "Alice", "nope").await,
+            JoinAnswerResult::Deny(DenyReason::WrongPassword));
+        assert!(matches!(request_join_with(&mut active, &alice, host_addr, &alice_keypair, "Alice", "pw").await,
+            JoinAnswerResult::Confirm(_)));
+        assert_eq!(request_join(&mut active, &bob, host_addr, "Alice", "pw").await,
+            JoinAnswerResult::Deny(DenyReason::IdInUse));
+        assert_eq!(request_join(&mut active, &bob, host_addr, "Bob", "pw").await,
+            JoinAnswerResult::Deny(DenyReason::RingFull(1)));
+        active.set_accepting(false);
+        assert_eq!(request_join(&mut active, &bob, host_addr, "Bob", "pw").await,
+            JoinAnswerResult::Deny(DenyReason::ConnectionsBlocked));
+    }
+
+    #[tokio::test]
+    async fn token_generations() {
+        let mut station = host_station().await;
+        let first = station.generate_token(0).unwrap();
+        let second = station.generate_token(0).unwrap();
+        assert_eq!(second.generation(), first.generation() + 1);
+        // Further passes of the same token keep its generation
+        let third = station.reissue_token(0).unwrap();
+        assert_eq!(third.generation(), second.generation());
+        assert_eq!(third.pass(), second.pass() + 1);
+
+        let mut buf = vec![];
+        third.write(&mut buf).unwrap();
+        let deserialized = Token::read(&mut Cursor::new(buf.as_slice())).unwrap();
+        assert_eq!((deserialized.generation(), deserialized.pass()), (third.generation(), third.pass()));
+    }
+
+    #[tokio::test]
+    async fn holds_token() {
+        let mut station = passive_station("Bob").await;
+        station.conn_mode = ConnectionMode::Connected(
+            WorkStationId::new("Host".to_owned()), station_addr(6000));
+        assert!(!station.holds_token());
+
+        station.recv_token_pass(create_token());
+        assert!(station.holds_token());
+        let event = station.poll_event().unwrap();
+        assert_eq!(event.downcast_ref::<TokenAcquiredEvent>().unwrap().source,
+            WorkStationId::new("Host".to_owned()));
+
+        station.pass_on_token().unwrap();
+        assert!(!station.holds_token());
+        assert!(station.poll_event().unwrap().is::<TokenReleasedEvent>());
+        assert!(station.poll_event().is_none());
+    }
+
+    #[tokio::test]
+    async fn membership_events() {
+        let mut station = host_station().await;
+        let bob = WorkStationId::new("Bob".to_owned());
+        station.recv_join_request(station_addr(5001), bob.clone(), "pw".to_owned(), false, 0,
+            &generate_keypair().public).await.unwrap();
+        station.recv_leave(station_addr(5001), &bob).await.unwrap();
+
+        let joined = station.poll_event().unwrap();
+        let joined = joined.downcast_ref::<StationJoinedEvent>().unwrap();
+        assert_eq!((&joined.source, joined.members), (&bob, 1));
+        let left = station.poll_event().unwrap();
+        let left = left.downcast_ref::<StationLeftEvent>().unwrap();
+        assert_eq!((&left.source, left.members), (&bob, 0));
+        assert!(station.poll_event().is_none());
+    }
+
+    #[tokio::test]
+    async fn token_while_pending() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let host = network.bind(host_addr);
+        let host_keypair = generate_keypair();
+        let mut station = PassiveStation::with_transport(WorkStationId::new("Bob".to_owned()),
+            Arc::new(network.bind(station_addr(6001)))).unwrap();
+        station.connect(host_addr, "pw".to_owned()).await.unwrap();
+
+        // Token overtakes join reply
+        let token = Token::new(Signed::new(&host_keypair,
+            TokenHeader::new(WorkStationId::new("Host".to_owned()), 1)).unwrap());
+        send_raw(&host, station_addr(6001), &host_keypair, "Host",
+            PacketType::TokenPass(token)).await;
+        pump(&mut station).await;
+        assert!(!station.holds_token());
+        send_raw(&host, station_addr(6001), &host_keypair, "Host", host_confirm(&station)).await;
+        pump(&mut station).await;
+
+        assert!(station.is_connected());
+        assert!(station.holds_token());
+    }
+
+    #[tokio::test]
+    async fn reject_foreign_token_key() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let host = network.bind(host_addr);
+        let host_keypair = generate_keypair();
+        let mut station = PassiveStation::with_transport(WorkStationId::new("Bob".to_owned()),
+            Arc::new(network.bind(station_addr(6001)))).unwrap();
+        station.connect(host_addr, "pw".to_owned()).await.unwrap();
+        send_raw(&host, station_addr(6001), &host_keypair, "Host", host_confirm(&station)).await;
+        pump(&mut station).await;
+        assert!(station.is_connected());
+
+        // Right address and ID, but token header signed by someone else
+        send_raw(&host, station_addr(6001), &host_keypair, "Host",
+            PacketType::TokenPass(create_token())).await;
+        let mut result = Ok(());
+        for _ in 0..20 {
+            result = station.recv_next().await;
+            if result.is_err() {
+                break
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(matches!(result, Err(GlobalError::Internal(TokenRingError::InvalidSignature))));
+        assert!(!station.holds_token());
+
+        let token = Token::new(Signed::new(&host_keypair,
+            TokenHeader::new(WorkStationId::new("Host".to_owned()), 1)).unwrap());
+        send_raw(&host, station_addr(6001), &host_keypair, "Host",
+            PacketType::TokenPass(token)).await;
+        pump(&mut station).await;
+        assert!(station.holds_token());
+    }
+
+    #[tokio::test]
+    async fn restore_snapshot() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let alice = network.bind(station_addr(6001));
+        let bob = network.bind(station_addr(6002));
+        request_join(&mut active, &alice, host_addr, "Alice", "pw").await;
+        request_join(&mut active, &bob, host_addr, "Bob", "pw").await;
+        active.add_station(WorkStationId::new("Carol".to_owned()), station_addr(6004), true);
+        active.ban(station_addr(6005));
+        let mut token = active.generate_token(0).unwrap();
+        token.frames.push(TokenFrame::new(TokenFrameId::new(WorkStationId::new("Alice".to_owned())),
+            TokenFrameType::Empty));
+        active.token_passer.curr_token = Some(token.clone());
+
+        let mut buf = vec![];
+        active.snapshot().write(&mut buf).unwrap();
+        let snapshot = RingSnapshot::read(&mut Cursor::new(&buf[..])).unwrap();
+        assert!(snapshot == active.snapshot());
+
+        let restored = ActiveStation::restore(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), active.keypair(),
+            Arc::new(network.bind(station_addr(6003))), snapshot).unwrap();
+        assert_eq!(restored.connected_stations, active.connected_stations);
+        assert_eq!(restored.known_keys, active.known_keys);
+        assert_eq!(restored.observers, active.observers);
+        assert_eq!(restored.member_metadata, active.member_metadata);
+        assert_eq!(restored.banned, active.banned);
+        assert_eq!(restored.rotation_order(), active.rotation_order());
+        assert_eq!(restored.config.keypair.public, active.config.keypair.public);
+        assert_eq!(restored.token_generation, 1);
+        assert!(restored.token_passer.curr_token == Some(token));
+        assert!(restored.snapshot() == active.snapshot());
+    }
+
+    #[tokio::test]
+    async fn restore_keeps_pinned_key() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6200);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let mut passive = PassiveStation::with_transport(WorkStationId::new("Bob".to_owned()),
+            Arc::new(network.bind(station_addr(6201)))).unwrap();
+        passive.connect(host_addr, "pw".to_owned()).await.unwrap();
+        for _ in 0..100 {
+            let _ = active.recv_all().await;
+            let _ = passive.recv_next().await;
+            if passive.is_connected() {
+                break
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(passive.is_connected());
+
+        // Restart of the active station on the same address
+        let (snapshot, keypair) = (active.snapshot(), active.keypair());
+        drop(active);
+        let mut restored = ActiveStation::restore(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), keypair,
+            Arc::new(network.bind(host_addr)), snapshot).unwrap();
+        restored.force_new_token().await.unwrap();
+        pump(&mut passive).await;
+        assert!(passive.holds_token());
+    }
+
+    #[tokio::test]
+    async fn append_frames_atomically() {
+        let mut station = passive_station("Bob").await;
+        station.set_buffer_offline(true);
+        station.set_frame_limits(FrameLimits::new(4, 64));
+        let data = |len: usize| TokenFrameType::Data {
+            send_mode: TokenSendMode::Broadcast, seq: 0, payload: vec![0; len], expires_at: None };
+
+        let result = station.append_frames(vec![data(8), data(80), data(8)]);
+        assert!(matches!(result, Err(GlobalError::Internal(
+            TokenRingError::FrameTooLarge { index: 1, .. }))));
+        assert!(station.cached_frames.is_empty());
+
+        station.append_frames(vec![data(8), data(8)]).unwrap();
+        let result = station.append_frames(vec![data(8), data(8), data(8)]);
+        assert!(matches!(result, Err(GlobalError::Internal(
+            TokenRingError::TooManyFrames { index: 2, max: 4 }))));
+        assert_eq!(station.cached_frames.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn reject_changed_key() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let bob = network.bind(station_addr(6001));
+        let (bob_keypair, other_keypair) = (generate_keypair(), generate_keypair());
+        send_raw(&bob, host_addr, &bob_keypair, "Bob", PacketType::JoinRequest(JoinRequest::new("pw".to_owned()))).await;
+        for _ in 0..20 {
+            active.recv_all().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(active.connected_stations.len(), 1);
+
+        // Same ID and address, different key
+        send_raw(&bob, host_addr, &other_keypair, "Bob", PacketType::Leave()).await;
+        let mut result = Ok(());
+        for _ in 0..20 {
+            result = active.recv_all().await;
+            if result.is_err() {
+                break
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(matches!(result, Err(GlobalError::Internal(TokenRingError::KeyMismatch(_)))));
+        assert_eq!(active.connected_stations.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn presence_frames() {
+        let presence = |name: &str| TokenFrame::new(TokenFrameId::new(
+            WorkStationId::new(name.to_owned())), TokenFrameType::Presence { status: PresenceStatus::Typing });
+
+        // Passive station surfaces presence of others
+        let mut station = passive_station("Bob").await;
+        let mut token = create_token();
+        token.frames.push(presence("Alice"));
+        station.recv_token_pass(token);
+        let event = station.poll_event().unwrap();
+        let event = event.downcast_ref::<PresenceEvent>().unwrap();
+        assert_eq!(event.source, WorkStationId::new("Alice".to_owned()));
+        assert_eq!(event.status, PresenceStatus::Typing);
+
+        // Active station drops it once the token is back at its source
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let alice = network.bind(station_addr(6001));
+        request_join(&mut active, &alice, host_addr, "Alice", "pw").await;
+        let mut token = active.generate_token(0).unwrap();
+        token.frames.push(presence("Alice"));
+        token.frames.push(presence("Bob"));
+        active.token_passer.curr_token = Some(token);
+        active.pass_on_token().await.unwrap();
+        let frames = &active.token_passer.curr_token.as_ref().unwrap().frames;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].id.source, WorkStationId::new("Bob".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn os_assigned_port() {
+        let active = host_station().await;
+        assert_ne!(active.local_addr().port(), 0);
+        let passive = passive_station("Bob").await;
+        assert_ne!(passive.local_addr().port(), 0);
+        assert_ne!(passive.local_addr().port(), active.local_addr().port());
+
+        let network = MemoryNetwork::new();
+        let passive = PassiveStation::with_transport(WorkStationId::new("Alice".to_owned()),
+            Arc::new(network.bind(station_addr(6001)))).unwrap();
+        assert_eq!(passive.local_addr(), station_addr(6001));
+    }
+
+    #[tokio::test]
+    async fn drop_expired_frames() {
+        let mut station = passive_station("Bob").await;
+        let data = |seq, expires_at| TokenFrame::new(TokenFrameId::new(WorkStationId::new("Alice".to_owned())),
+            TokenFrameType::Data { send_mode: TokenSendMode::Broadcast, seq, payload: vec![], expires_at });
+        let mut token = create_token();
+        token.frames.push(data(0, Some(timestamp() - 1)));
+        token.frames.push(data(1, Some(timestamp() + 60)));
+        token.frames.push(data(2, None));
+        station.recv_token_pass(token);
+
+        let seqs = station.get_token_mut().unwrap().frames.iter().map(|f| match f.content {
+            TokenFrameType::Data { seq, .. } => seq,
+            _ => panic!("Expected data frame.")
+        }).collect::<Vec<_>>();
+        assert_eq!(seqs, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn switch_ring() {
+        let network = MemoryNetwork::new();
+        let (first_addr, second_addr) = (station_addr(6000), station_addr(6010));
+        let mut first = ActiveStation::with_transport(WorkStationId::new("First".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(first_addr))).unwrap();
+        let mut second = ActiveStation::with_transport(WorkStationId::new("Second".to_owned()),
+            GlobalConfig::new("pw2".to_owned(), true, 8, 5.), Arc::new(network.bind(second_addr))).unwrap();
+        let mut station = PassiveStation::with_transport(WorkStationId::new("Bob".to_owned()),
+            Arc::new(network.bind(station_addr(6001)))).unwrap();
+        let bob = WorkStationId::new("Bob".to_owned());
+
+        station.connect(first_addr, "pw".to_owned()).await.unwrap();
+        for _ in 0..20 {
+            let _ = first.recv_all().await;
+            let _ = station.recv_next().await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(station.is_connected());
+        assert!(first.connected_stations.contains_key(&bob));
+
+        station.append_frame(TokenFrameType::Empty).unwrap();
+        station.switch_ring(second_addr, "pw2".to_owned()).await.unwrap();
+        assert!(!station.is_connected());
+        assert!(station.cached_frames.is_empty());
+        for _ in 0..20 {
+            let _ = first.recv_all().await;
+            let _ = second.recv_all().await;
+            let _ = station.recv_next().await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(matches!(&station.conn_mode, ConnectionMode::Connected(id, addr)
+            if id == &WorkStationId::new("Second".to_owned()) && *addr == second_addr));
+        assert!(!first.connected_stations.contains_key(&bob));
+        assert!(second.connected_stations.contains_key(&bob));
+    }
+
+    #[tokio::test]
+    async fn app_frames() {
+        let mut station = passive_station("Bob").await;
+        station.register_app_frame(7, Box::new(|payload| {
+            let text = String::from_utf8(payload.to_vec())
+                .map_err(|_| GlobalError::Internal(TokenRingError::InvalidUtf8))?;
+            Ok(Box::new(text))
+        }));
+        let app = |type_id, payload: &[u8]| TokenFrame::new(TokenFrameId::new(WorkStationId::new("Alice".to_owned())),
+            TokenFrameType::App { type_id, payload: payload.to_vec() });
+        let mut token = create_token();
+        token.frames.push(app(7, b"ping"));
+        token.frames.push(app(8, b"unregistered"));
+        station.recv_token_pass(token);
+
+        let event = station.poll_event().unwrap();
+        let event = event.downcast_ref::<AppFrameEvent>().unwrap();
+        assert_eq!(event.type_id, 7);
+        assert_eq!(event.payload, b"ping");
+        assert_eq!(event.decoded_ref::<String>().unwrap(), "ping");
+        assert!(station.poll_event().unwrap().is::<TokenAcquiredEvent>());
+    }
+
+    #[tokio::test]
+    async fn duplicate_id() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let alice = network.bind(station_addr(6001));
+        let mallory = network.bind(station_addr(6002));
+        let alice_keypair = generate_keypair();
+        let alice_id = WorkStationId::new("Alice".to_owned());
+
+        assert!(matches!(request_join_with(&mut active, &alice, host_addr, &alice_keypair, "Alice", "pw").await,
+            JoinAnswerResult::Confirm(_)));
+        assert_eq!(request_join(&mut active, &mallory, host_addr, "Alice", "pw").await,
+            JoinAnswerResult::Deny(DenyReason::IdInUse));
+        assert_eq!(active.connected_stations.get(&alice_id), Some(&station_addr(6001)));
+
+        // Same ID from the same address is a reconnect
+        assert!(matches!(request_join_with(&mut active, &alice, host_addr, &alice_keypair, "Alice", "pw").await,
+            JoinAnswerResult::Confirm(_)));
+        assert_eq!(active.connected_stations.len(), 1);
+
+        // ... but only when signed with the pinned key
+        assert_eq!(request_join(&mut active, &alice, host_addr, "Alice", "nope").await,
+            JoinAnswerResult::Deny(DenyReason::IdInUse));
+        assert_eq!(active.known_keys.get(&alice_id), Some(&alice_keypair.public));
+    }
+
+    #[tokio::test]
+    async fn reject_token_replay() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let alice = network.bind(station_addr(6001));
+        let alice_id = WorkStationId::new("Alice".to_owned());
+        request_join(&mut active, &alice, host_addr, "Alice", "pw").await;
+        active.token_passer.curr_token = Some(active.generate_token(0).unwrap());
+        active.pass_on_token().await.unwrap();
+        let token = active.token_passer.curr_token.clone().unwrap();
+
+        active.recv_token_pass(station_addr(6001), &alice_id, token.clone()).await.unwrap();
+        assert!(matches!(active.recv_token_pass(station_addr(6001), &alice_id, token).await,
+            Err(GlobalError::Internal(TokenRingError::TokenReplay(_)))));
+    }
+
+    #[tokio::test]
+    async fn send_queue_quota() {
+        let network = MemoryNetwork::new();
+        network.stall_sends(true);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(station_addr(6000)))).unwrap();
+        active.set_max_queued_packets(4);
+
+        let mut results = vec![];
+        for _ in 0..10 {
+            results.push(active.send_packet(station_addr(6001), PacketType::Leave()).await);
+        }
+        assert!(results[..4].iter().all(|r| r.is_ok()));
+        assert!(matches!(results.last().unwrap(),
+            Err(GlobalError::Internal(TokenRingError::SendQueueFull))));
+        assert!(active.send_queue.len() <= 4);
+    }
+
+    #[tokio::test]
+    async fn key_rotation() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let mut station = PassiveStation::with_transport(WorkStationId::new("Bob".to_owned()),
+            Arc::new(network.bind(station_addr(6001)))).unwrap();
+        let bob = WorkStationId::new("Bob".to_owned());
+        station.connect(host_addr, "pw".to_owned()).await.unwrap();
+        for _ in 0..20 {
+            let _ = active.recv_all().await;
+            let _ = station.recv_next().await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(station.is_connected());
+
+        active.rotate_key().await.unwrap();
+        station.rotate_key().unwrap();
+        for _ in 0..20 {
+            active.recv_all().await.unwrap();
+            station.recv_next().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(station.active_key, Some(active.config.keypair.public));
+        assert_eq!(active.known_keys.get(&bob), Some(&station.config.keypair.public));
+
+        // Tokens signed with the new key are accepted
+        active.pass_on_token().await.unwrap();
+        pump(&mut station).await;
+        assert!(station.holds_token());
+    }
+
+    #[tokio::test]
+    async fn key_rotation_over_lossy_network() {
+        let network = MemoryNetwork::new();
+        let lossy = |port, seed| Arc::new(LossyTransport::new(network.bind(station_addr(port)),
+            0.5, Duration::ZERO, seed).unwrap());
+        let host_addr = station_addr(6202);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), lossy(6202, 4)).unwrap();
+        let mut station = PassiveStation::with_transport(WorkStationId::new("Bob".to_owned()), lossy(6203, 5)).unwrap();
+        let retry = JoinRetry::new(Duration::from_millis(2), Duration::from_millis(2), 100);
+        active.set_key_rotation_retry(retry);
+        station.set_key_rotation_retry(retry);
+        station.set_join_retry(retry);
+        station.connect(host_addr, "pw".to_owned()).await.unwrap();
+        for _ in 0..500 {
+            if station.is_connected() {
+                break
+            }
+            let _ = active.recv_all().await;
+            let _ = station.recv_next().await;
+            let _ = station.tick();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(station.is_connected());
+
+        let (active_key, station_key) = (active.config.keypair.public, station.config.keypair.public);
+        active.rotate_key().await.unwrap();
+        station.rotate_key().unwrap();
+        for _ in 0..500 {
+            if active.key_rotation.is_none() && station.key_rotation.is_none() {
+                break
+            }
+            active.recv_all().await.unwrap();
+            while !station.recv_queue.is_empty() {
+                station.recv_next().await.unwrap();
+            }
+            station.tick().unwrap();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        // Both switched only after the other side learned the new key
+        assert_ne!(active.config.keypair.public, active_key);
+        assert_ne!(station.config.keypair.public, station_key);
+        assert_eq!(station.active_key, Some(active.config.keypair.public));
+        assert_eq!(active.known_keys.get(&WorkStationId::new("Bob".to_owned())),
+            Some(&station.config.keypair.public));
+    }
+
+    #[tokio::test]
+    async fn reject_rotated_key() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let bob = network.bind(station_addr(6001));
+        let (old_keypair, new_keypair) = (generate_keypair(), generate_keypair());
+        send_raw(&bob, host_addr, &old_keypair, "Bob", PacketType::JoinRequest(JoinRequest::new("pw".to_owned()))).await;
+        send_raw(&bob, host_addr, &old_keypair, "Bob",
+            PacketType::KeyRotation { new_key: new_keypair.public }).await;
+        // Resent announcement (ack got lost) is still signed with the old key
+        send_raw(&bob, host_addr, &old_keypair, "Bob",
+            PacketType::KeyRotation { new_key: new_keypair.public }).await;
+        for _ in 0..20 {
+            active.recv_all().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let mut buf = [0u8; RECV_BUF_LENGTH];
+        let mut acks = 0;
+        while let Ok(Ok((size, _))) = tokio::time::timeout(Duration::from_millis(5), bob.recv_from(&mut buf)).await {
+            if matches!(Packet::deserialize(&buf[..size]).unwrap().content,
+                PacketType::KeyRotationAck { new_key } if new_key == new_keypair.public) {
+                acks += 1;
+            }
+        }
+        assert_eq!(acks, 2);
+
+        // Old key is no longer trusted once the new one was used
+        send_raw(&bob, host_addr, &new_keypair, "Bob",
+            PacketType::TokenHoldRequest { extra: Duration::from_secs(1) }).await;
+        send_raw(&bob, host_addr, &old_keypair, "Bob", PacketType::Leave()).await;
+        let mut result = Ok(());
+        for _ in 0..20 {
+            result = active.recv_all().await;
+            if result.is_err() {
+                break
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(matches!(result, Err(GlobalError::Internal(TokenRingError::KeyMismatch(_)))));
+
+        send_raw(&bob, host_addr, &new_keypair, "Bob", PacketType::Leave()).await;
+        for _ in 0..20 {
+            active.recv_all().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(active.connected_stations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn ring_metrics() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let clock = MockClock::new();
+        active.token_passer = TokenPasser::with_clock(5., Box::new(clock.clone()));
+        let (alice, bob) = (network.bind(station_addr(6001)), network.bind(station_addr(6002)));
+        request_join(&mut active, &alice, host_addr, "Alice", "pw").await;
+        request_join(&mut active, &bob, host_addr, "Bob", "pw").await;
+        assert_eq!(active.ring_metrics().avg_rotation_time, None);
+        assert_eq!(active.ring_metrics().avg_token_overhead_permille, None);
+
+        // Alice returns the token after 1s, Bob times out after 5s
+        active.pass_on_token().await.unwrap();
+        clock.advance(Duration::from_secs(1));
+        active.token_passer.recv_token(create_token(), &WorkStationId::new("Alice".to_owned())).unwrap();
+        active.pass_on_token().await.unwrap();
+        clock.advance(Duration::from_secs(5));
+        assert!(active.token_passer.pass_ready());
+        active.pass_on_token().await.unwrap();
+
+        let metrics = active.ring_metrics();
+        assert_eq!(metrics.members, 2);
+        assert_eq!(metrics.tokens_passed, 3);
+        assert_eq!(metrics.avg_rotation_time, Some(Duration::from_secs(6)));
+        assert_eq!(metrics.timed_out_stations, 1);
+        // No frames were appended, so every passed token has the same size
+        assert_eq!(metrics.bytes_relayed, 3 * create_token().size() as u64);
+        // ... and consists of overhead only
+        assert_eq!(metrics.avg_token_overhead_permille, Some(1000));
+    }
+
+    #[tokio::test]
+    async fn single_member_idle() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let (alice, bob) = (network.bind(station_addr(6001)), network.bind(station_addr(6002)));
+        request_join(&mut active, &alice, host_addr, "Alice", "pw").await;
+        for _ in 0..3 {
+            active.poll_token_pass().await.unwrap();
+        }
+        assert_eq!(active.ring_metrics().tokens_passed, 0);
+
+        request_join(&mut active, &bob, host_addr, "Bob", "pw").await;
+        active.poll_token_pass().await.unwrap();
+        assert_eq!(active.ring_metrics().tokens_passed, 1);
+    }
+
+    // First error the active station reports while draining its queue
+    async fn recv_error(active: &mut ActiveStation) -> Option<GlobalError> {
+        for _ in 0..20 {
+            if let Err(e) = active.recv_all().await {
+                return Some(e)
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn ban_stations() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let (alice, bob) = (network.bind(station_addr(6001)), network.bind(station_addr(6002)));
+        let alice_keypair = generate_keypair();
+        request_join_with(&mut active, &alice, host_addr, &alice_keypair, "Alice", "pw").await;
+        request_join(&mut active, &bob, host_addr, "Bob", "pw").await;
+
+        // By ID: member is kicked, its packets and joins are dropped
+        active.ban(WorkStationId::new("Alice".to_owned()));
+        assert!(!active.connected_stations.contains_key(&WorkStationId::new("Alice".to_owned())));
+        send_raw(&alice, host_addr, &alice_keypair, "Alice", PacketType::Leave()).await;
+        assert!(matches!(recv_error(&mut active).await,
+            Some(GlobalError::Internal(TokenRingError::Banned(..)))));
+        send_raw(&alice, host_addr, &alice_keypair, "Alice", PacketType::JoinRequest(JoinRequest::new("pw".to_owned()))).await;
+        assert!(matches!(recv_error(&mut active).await,
+            Some(GlobalError::Internal(TokenRingError::Banned(..)))));
+
+        // By address: any ID from it is dropped
+        active.ban(station_addr(6002));
+        assert!(active.connected_stations.is_empty());
+        send_raw(&bob, host_addr, &generate_keypair(), "Carol", PacketType::JoinRequest(JoinRequest::new("pw".to_owned()))).await;
+        assert!(matches!(recv_error(&mut active).await,
+            Some(GlobalError::Internal(TokenRingError::Banned(..)))));
+        assert!(active.connected_stations.is_empty());
+
+        active.unban(WorkStationId::new("Alice".to_owned()));
+        assert!(matches!(request_join(&mut active, &alice, host_addr, "Alice", "pw").await,
+            JoinAnswerResult::Confirm(_)));
+    }
+
+    #[tokio::test]
+    async fn resend_unacked_frames() {
+        let mut station = passive_station("Bob").await;
+        station.set_buffer_offline(true);
+        station.set_resend_policy(ResendPolicy::new(2, 1));
+        let alice = WorkStationId::new("Alice".to_owned());
+        let unicast = |seq| TokenFrameType::Data { send_mode: TokenSendMode::Unicast(alice.clone()),
+            seq, payload: vec![], expires_at: None };
+        let sent = |station: &mut PassiveStation, seq| station.get_token_mut().unwrap().frames.iter()
+            .filter(|f| f.content == unicast(seq)).count();
+        station.append_frames(vec![unicast(1), unicast(2)]).unwrap();
+
+        // Alice only acks frame 2
+        station.recv_token_pass(create_token());
+        assert_eq!(sent(&mut station, 1), 1);
+        let mut token = create_token();
+        token.frames.push(TokenFrame::new(TokenFrameId::new(alice.clone()),
+            TokenFrameType::DataReceived { source: WorkStationId::new("Bob".to_owned()), seq: 2 }));
+        station.recv_token_pass(token);
+        assert_eq!(sent(&mut station, 1), 1);
+        assert_eq!(sent(&mut station, 2), 0);
+
+        station.recv_token_pass(create_token());
+        station.recv_token_pass(create_token());
+        assert_eq!(sent(&mut station, 1), 0);
+        let mut failed = vec![];
+        while let Some(event) = station.poll_event() {
+            if let Some(event) = event.downcast_ref::<DeliveryFailedEvent>() {
+                failed.push((event.dest.clone(), event.seq));
+            }
+        }
+        assert_eq!(failed, vec![(alice.clone(), 1)]);
+    }
+
+    #[tokio::test]
+    async fn drop_resent_frames() {
+        let mut station = passive_station("Bob").await;
+        let alice = WorkStationId::new("Alice".to_owned());
+        let unicast = |seq| TokenFrame::new(TokenFrameId::new(alice.clone()), TokenFrameType::Data {
+            send_mode: TokenSendMode::Unicast(WorkStationId::new("Bob".to_owned())),
+            seq, payload: vec![seq as u8], expires_at: None });
+        let mut received = vec![];
+        // Alice resends frame 1 (fresh frame ID) since its ack got lost
+        for seqs in [vec![1], vec![1, 2], vec![2]] {
+            let mut token = create_token();
+            token.frames.extend(seqs.into_iter().map(unicast));
+            station.curr_token = None;
+            station.recv_token_pass(token);
+            while let Some(event) = station.poll_event() {
+                if let Some(event) = event.downcast_ref::<DataEvent>() {
+                    received.push((event.source.clone(), event.seq));
+                }
+            }
+        }
+        assert_eq!(received, vec![(alice.clone(), 1), (alice, 2)]);
+    }
+
+    #[tokio::test]
+    async fn data_event_once_per_frame() {
+        let mut station = passive_station("Bob").await;
+        let alice = WorkStationId::new("Alice".to_owned());
+        let data = |send_mode, seq| TokenFrame::new(TokenFrameId::new(alice.clone()), TokenFrameType::Data {
+            send_mode, seq, payload: vec![], expires_at: None });
+        let frames = vec![data(TokenSendMode::Multicast(vec![WorkStationId::new("Bob".to_owned())]), 1),
+            data(TokenSendMode::Broadcast, 2)];
+        // Neither frame is removed after delivery, so Bob sees them every rotation
+        let mut received = vec![];
+        for _ in 0..3 {
+            let mut token = create_token();
+            token.frames = frames.clone();
+            station.curr_token = None;
+            station.recv_token_pass(token);
+            while let Some(event) = station.poll_event() {
+                if let Some(event) = event.downcast_ref::<DataEvent>() {
+                    received.push(event.seq);
+                }
+            }
+        }
+        assert_eq!(received, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn broadcasts_with_same_seq() {
+        let mut station = passive_station("Bob").await;
+        let alice = WorkStationId::new("Alice".to_owned());
+        // Like the chat client, Alice sends every message with seq 0
+        let mut received = vec![];
+        for payload in [b"hi", b"yo"] {
+            let mut token = create_token();
+            token.frames.push(TokenFrame::new(TokenFrameId::new(alice.clone()), TokenFrameType::Data {
+                send_mode: TokenSendMode::Broadcast, seq: 0, payload: payload.to_vec(), expires_at: None }));
+            station.curr_token = None;
+            station.recv_token_pass(token);
+            while let Some(event) = station.poll_event() {
+                if let Some(event) = event.downcast_ref::<DataEvent>() {
+                    received.push(event.payload.clone());
+                }
+            }
+        }
+        assert_eq!(received, vec![b"hi".to_vec(), b"yo".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn list_connections() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let (alice, bob) = (network.bind(station_addr(6001)), network.bind(station_addr(6002)));
+        request_join(&mut active, &alice, host_addr, "Alice", "pw").await;
+        request_join(&mut active, &bob, host_addr, "Bob", "pw").await;
+        assert_eq!(active.connections(), vec![
+            (WorkStationId::new("Alice".to_owned()), station_addr(6001)),
+            (WorkStationId::new("Bob".to_owned()), station_addr(6002))
+        ]);
+    }
+
+    #[tokio::test]
+    async fn authorized_keys() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let (alice, bob) = (network.bind(station_addr(6001)), network.bind(station_addr(6002)));
+        let alice_keypair = generate_keypair();
+        active.set_authorized_keys(Some(vec![alice_keypair.public]));
+
+        assert!(matches!(request_join(&mut active, &bob, host_addr, "Bob", "pw").await,
+            JoinAnswerResult::Deny(DenyReason::KeyNotAuthorized)));
+        assert!(matches!(request_join_with(&mut active, &alice, host_addr, &alice_keypair, "Alice", "pw").await,
+            JoinAnswerResult::Confirm(_)));
+        assert_eq!(active.connected_stations.len(), 1);
+
+        // Authorized key is enough without password
+        active.set_require_password(false);
+        active.recv_leave(station_addr(6001), &WorkStationId::new("Alice".to_owned())).await.unwrap();
+        assert!(matches!(request_join_with(&mut active, &alice, host_addr, &alice_keypair, "Alice", "").await,
+            JoinAnswerResult::Confirm(_)));
+    }
+
+    fn signed_packet(keypair: &Keypair, name: &str, content: PacketType) -> Packet {
+        Packet::new(Signed::new(keypair,
+            PacketHeader::new(WorkStationId::new(name.to_owned()))).unwrap(), content)
+    }
+
+    #[tokio::test]
+    async fn inject_join_packets() {
+        let mut active = host_station().await;
+        let bob_keypair = generate_keypair();
+        active.inject_packet(signed_packet(&bob_keypair, "Bob",
+            PacketType::JoinRequest(JoinRequest::new("pw".to_owned()))), station_addr(5001));
+        active.recv_all().await.unwrap();
+        assert_eq!(active.connections(), vec![(WorkStationId::new("Bob".to_owned()), station_addr(5001))]);
+
+        // Header signature broken in transit
+        let mut buf = signed_packet(&generate_keypair(), "Alice",
+            PacketType::JoinRequest(JoinRequest::new("pw".to_owned()))).serialize().unwrap();
+        buf[4 + 32] ^= 1;
+        active.inject_packet(Packet::deserialize(&buf).unwrap(), station_addr(5002));
+        assert!(matches!(active.recv_all().await,
+            Err(GlobalError::Internal(TokenRingError::InvalidSignature))));
+        assert_eq!(active.connected_stations.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn inject_token_packets() {
+        let mut active = host_station().await;
+        active.inject_packet(signed_packet(&generate_keypair(), "Bob",
+            PacketType::TokenPass(create_token())), station_addr(5001));
+        assert!(matches!(active.recv_all().await,
+            Err(GlobalError::Internal(TokenRingError::StationNotRegistered(..)))));
+
+        let mut station = passive_station("Bob").await;
+        let host_keypair = generate_keypair();
+        station.connect(station_addr(5000), "pw".to_owned()).await.unwrap();
+        station.inject_packet(signed_packet(&host_keypair, "Host", host_confirm(&station)), station_addr(5000));
+        station.recv_next().await.unwrap();
+        assert!(station.is_connected());
+
+        let token = Token::new(Signed::new(&host_keypair,
+            TokenHeader::new(WorkStationId::new("Host".to_owned()), 1)).unwrap());
+        station.inject_packet(signed_packet(&host_keypair, "Host",
+            PacketType::TokenPass(token.clone())), station_addr(5009));
+        assert!(matches!(station.recv_next().await,
+            Err(GlobalError::Internal(TokenRingError::InvalidSocketAddress(_)))));
+        station.inject_packet(signed_packet(&host_keypair, "Host",
+            PacketType::TokenPass(token)), station_addr(5000));
+        station.recv_next().await.unwrap();
+        assert!(station.holds_token());
+    }
+
+    #[tokio::test]
+    async fn join_reply_from_asked_station() {
+        let mut station = passive_station("Bob").await;
+        let host_keypair = generate_keypair();
+        station.connect(station_addr(5000), "pw".to_owned()).await.unwrap();
+
+        station.inject_packet(signed_packet(&generate_keypair(), "Host", host_confirm(&station)), station_addr(5009));
+        assert!(matches!(station.recv_next().await,
+            Err(GlobalError::Internal(TokenRingError::InvalidSocketAddress(_)))));
+        let mut buf = signed_packet(&generate_keypair(), "Host", host_confirm(&station)).serialize().unwrap();
+        buf[4 + 32] ^= 1;
+        station.inject_packet(Packet::deserialize(&buf).unwrap(), station_addr(5000));
+        assert!(matches!(station.recv_next().await,
+            Err(GlobalError::Internal(TokenRingError::InvalidSignature))));
+        assert!(!station.is_connected());
+
+        station.inject_packet(signed_packet(&host_keypair, "Host", host_confirm(&station)), station_addr(5000));
+        station.recv_next().await.unwrap();
+        assert!(station.is_connected());
+        assert_eq!(station.active_key, Some(host_keypair.public));
+    }
+
+    #[tokio::test]
+    async fn forged_kick_rejected() {
+        let mut station = passive_station("Bob").await;
+        let host_keypair = generate_keypair();
+        station.connect(station_addr(5000), "pw".to_owned()).await.unwrap();
+        station.inject_packet(signed_packet(&host_keypair, "Host", host_confirm(&station)), station_addr(5000));
+        station.recv_next().await.unwrap();
+
+        // Right address and ID, but signed by someone else
+        let kick = PacketType::Kicked { reason: "Spoofed".to_owned() };
+        station.inject_packet(signed_packet(&generate_keypair(), "Host", kick.clone()), station_addr(5000));
+        assert!(matches!(station.recv_next().await,
+            Err(GlobalError::Internal(TokenRingError::InvalidSignature))));
+        assert!(station.is_connected());
+        station.inject_packet(signed_packet(&host_keypair, "Host", kick), station_addr(5000));
+        station.recv_next().await.unwrap();
+        assert!(!station.is_connected());
+    }
+
+    #[tokio::test]
+    async fn append_while_offline() {
+        let mut station = passive_station("Bob").await;
+        assert!(matches!(station.append_frame(TokenFrameType::Empty),
+            Err(GlobalError::Internal(TokenRingError::NotConnected))));
+        station.connect(station_addr(5000), "pw".to_owned()).await.unwrap();
+        assert!(matches!(station.append_frame(TokenFrameType::Empty),
+            Err(GlobalError::Internal(TokenRingError::NotConnected))));
+
+        station.inject_packet(signed_packet(&generate_keypair(), "Host", host_confirm(&station)), station_addr(5000));
+        station.recv_next().await.unwrap();
+        station.append_frame(TokenFrameType::Empty).unwrap();
+
+        let mut station = passive_station("Alice").await;
+        station.set_buffer_offline(true);
+        station.append_frame(TokenFrameType::Empty).unwrap();
+        assert_eq!(station.cached_frames.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn full_batch_kept() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let (alice, bob) = (network.bind(station_addr(6001)), network.bind(station_addr(6002)));
+        request_join(&mut active, &alice, host_addr, "Alice", "pw").await;
+        request_join(&mut active, &bob, host_addr, "Bob", "pw").await;
+        let alice_id = WorkStationId::new("Alice".to_owned());
+
+        // More frames than twice the members, all within FrameLimits
+        active.pass_on_token().await.unwrap();
+        let mut token = recv_token(&alice).await;
+        token.frames.extend((0..5).map(|seq| TokenFrame::new(TokenFrameId::new(alice_id.clone()),
+            TokenFrameType::Data { send_mode: TokenSendMode::Unicast(WorkStationId::new("Bob".to_owned())),
+                seq, payload: vec![], expires_at: None })));
+        active.recv_token_pass(station_addr(6001), &alice_id, token).await.unwrap();
+        active.pass_on_token().await.unwrap();
+        assert_eq!(recv_token(&bob).await.frames.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn departed_station_frames() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let (alice, bob, carol) = (network.bind(station_addr(6001)), network.bind(station_addr(6002)),
+            network.bind(station_addr(6003)));
+        request_join(&mut active, &alice, host_addr, "Alice", "pw").await;
+        request_join(&mut active, &bob, host_addr, "Bob", "pw").await;
+        request_join(&mut active, &carol, host_addr, "Carol", "pw").await;
+        let (alice_id, bob_id) = (WorkStationId::new("Alice".to_owned()), WorkStationId::new("Bob".to_owned()));
+        let unicast = |dest: &WorkStationId, seq| TokenFrame::new(TokenFrameId::new(alice_id.clone()),
+            TokenFrameType::Data { send_mode: TokenSendMode::Unicast(dest.clone()), seq,
+                payload: vec![], expires_at: None });
+        let contents = |active: &ActiveStation| active.token_passer.curr_token.as_ref().unwrap()
+            .frames.iter().map(|f| f.content.clone()).collect::<Vec<_>>();
+
+        let mut token = active.generate_token(0).unwrap();
+        token.frames.push(unicast(&bob_id, 1));
+        token.frames.push(unicast(&WorkStationId::new("Carol".to_owned()), 2));
+        active.token_passer.curr_token = Some(token);
+        active.pass_on_token().await.unwrap();
+
+        // Bob leaves while Alice holds the token, the frame is cleared from
+        // the token she returns
+        active.recv_leave(station_addr(6002), &bob_id).await.unwrap();
+        let token = recv_token(&alice).await;
+        assert_eq!(token.frames.len(), 2);
+        active.recv_token_pass(station_addr(6001), &alice_id, token).await.unwrap();
+        active.pass_on_token().await.unwrap();
+        assert_eq!(contents(&active), vec![unicast(&WorkStationId::new("Carol".to_owned()), 2).content]);
+
+        // Carol leaves with the token, the kept copy is passed on
+        active.set_departed_frames(DepartedFrames::Notify);
+        active.recv_leave(station_addr(6003), &WorkStationId::new("Carol".to_owned())).await.unwrap();
+        active.pass_on_token().await.unwrap();
+        assert_eq!(contents(&active), vec![TokenFrameType::DeliveryFailed {
+            source: alice_id.clone(), dest: WorkStationId::new("Carol".to_owned()), seq: 2 }]);
+    }
+
+    #[tokio::test]
+    async fn unknown_address_dropped_early() {
+        let mut active = host_station().await;
+        let bob_keypair = generate_keypair();
+        active.inject_packet(signed_packet(&bob_keypair, "Bob",
+            PacketType::JoinRequest(JoinRequest::new("pw".to_owned()))), station_addr(5001));
+        active.recv_all().await.unwrap();
+        assert_eq!(active.station_ids.get(&station_addr(5001)), Some(&WorkStationId::new("Bob".to_owned())));
+
+        // Broken signature, but the unknown address is rejected first
+        let mut buf = signed_packet(&bob_keypair, "Bob", PacketType::Leave()).serialize().unwrap();
+        buf[4 + 32] ^= 1;
+        let tampered = Packet::deserialize(&buf).unwrap();
+        active.inject_packet(tampered.clone(), station_addr(5002));
+        assert!(matches!(active.recv_all().await,
+            Err(GlobalError::Internal(TokenRingError::StationNotRegistered(..)))));
+        active.inject_packet(tampered, station_addr(5001));
+        assert!(matches!(active.recv_all().await,
+            Err(GlobalError::Internal(TokenRingError::InvalidSignature))));
+
+        active.recv_leave(station_addr(5001), &WorkStationId::new("Bob".to_owned())).await.unwrap();
+        assert!(active.station_ids.is_empty());
+    }
+
+    #[tokio::test]
+    async fn flush_send_queue() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let host = network.bind(host_addr);
+        let mut station = PassiveStation::with_transport(WorkStationId::new("Bob".to_owned()),
+            Arc::new(network.bind(station_addr(6001)))).unwrap();
+        station.connect(host_addr, "pw".to_owned()).await.unwrap();
+        station.inject_packet(signed_packet(&generate_keypair(), "Host", host_confirm(&station)), host_addr);
+        station.recv_next().await.unwrap();
+        for _ in 0..5 {
+            station.send_packet(PacketType::Leave()).unwrap();
+        }
+        tokio::time::timeout(Duration::from_millis(500), station.flush()).await.unwrap();
+        assert_eq!(station.in_flight.load(Ordering::Relaxed), 0);
+        let mut buf = [0u8; RECV_BUF_LENGTH];
+        for _ in 0..6 {
+            tokio::time::timeout(Duration::from_millis(10), host.recv_from(&mut buf)).await.unwrap().unwrap();
+        }
+
+        // Leave is flushed instead of waiting a fixed delay
+        let start = std::time::Instant::now();
+        station.shutdown().await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let (size, _) = tokio::time::timeout(Duration::from_millis(10), host.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert!(matches!(Packet::deserialize(&buf[..size]).unwrap().content, PacketType::Leave()));
+    }
+
+    #[tokio::test]
+    async fn flush_stopped_send_loop() {
+        let network = MemoryNetwork::new();
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(station_addr(6208)))).unwrap();
+        active.add_station(WorkStationId::new("Alice".to_owned()), station_addr(6209), false);
+        active.shutdown();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // Packets the stopped loop can not take are not counted as in flight
+        assert!(active.send_packet(station_addr(6209), PacketType::Leave()).await.is_err());
+        assert!(active.send_to_all(PacketType::Leave()).await.is_err());
+        assert_eq!(active.in_flight.load(Ordering::Relaxed), 0);
+
+        // Flushing a stopped loop gives up right away
+        active.in_flight.fetch_add(1, Ordering::Relaxed);
+        let start = std::time::Instant::now();
+        assert!(!tokio::time::timeout(Duration::from_secs(1), active.flush()).await.unwrap());
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn observer_station() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let (alice, bob) = (network.bind(station_addr(6001)), network.bind(station_addr(6002)));
+        request_join(&mut active, &alice, host_addr, "Alice", "pw").await;
+        request_join(&mut active, &bob, host_addr, "Bob", "pw").await;
+        let mut observer = PassiveStation::with_transport(WorkStationId::new("Olivia".to_owned()),
+            Arc::new(network.bind(station_addr(6003)))).unwrap();
+        observer.observe(host_addr, "pw".to_owned()).await.unwrap();
+        for _ in 0..20 {
+            let _ = active.recv_all().await;
+            let _ = observer.recv_next().await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(observer.is_connected());
+        assert_eq!(active.connections().len(), 3);
+
+        let olivia = WorkStationId::new("Olivia".to_owned());
+        for _ in 0..6 {
+            assert_ne!(active.token_passer.select_next_station(), Some(olivia.clone()));
+        }
+
+        // Broadcast frames reach the observer with every pass
+        let mut token = active.generate_token(0).unwrap();
+        token.frames.push(TokenFrame::new(TokenFrameId::new(WorkStationId::new("Alice".to_owned())),
+            TokenFrameType::Data { send_mode: TokenSendMode::Broadcast, seq: 4, payload: vec![1], expires_at: None }));
+        active.token_passer.curr_token = Some(token);
+        active.pass_on_token().await.unwrap();
+        pump(&mut observer).await;
+        assert!(observer.get_token_mut().unwrap().frames.iter()
+            .any(|f| matches!(f.content, TokenFrameType::Data { seq: 4, .. })));
+        assert!(matches!(observer.append_frame(TokenFrameType::Empty),
+            Err(GlobalError::Internal(TokenRingError::ObserverReadOnly))));
+        observer.pass_on_token().unwrap();
+        assert!(!observer.holds_token());
+    }
+
+    #[tokio::test]
+    async fn deferred_signing() {
+        let keypair = Arc::new(generate_keypair());
+        let content = PacketType::JoinRequest(JoinRequest::new("pw".to_owned()));
+        let deferred = OutgoingPacket::new(keypair.clone(), WorkStationId::new("Bob".to_owned()),
+            content.clone(), station_addr(6000)).sign().unwrap();
+        assert!(deferred.0.header.verify());
+        // Signed directly from the very header the send loop built, so that no
+        // separately taken value (e.g. a future header timestamp) can differ
+        let direct = Packet::new(Signed::new(&keypair, deferred.0.header.val.clone()).unwrap(), content);
+        assert_eq!(deferred.0.header.val, PacketHeader::new(WorkStationId::new("Bob".to_owned())));
+        assert_eq!(deferred.0.serialize().unwrap(), direct.serialize().unwrap());
+
+        // Signed by the send loop with the station's key
+        let network = MemoryNetwork::new();
+        let host = network.bind(station_addr(6000));
+        let mut station = PassiveStation::with_transport(WorkStationId::new("Bob".to_owned()),
+            Arc::new(network.bind(station_addr(6001)))).unwrap();
+        station.connect(station_addr(6000), "pw".to_owned()).await.unwrap();
+        let mut buf = [0u8; RECV_BUF_LENGTH];
+        let (size, _) = tokio::time::timeout(Duration::from_millis(100), host.recv_from(&mut buf)).await.unwrap().unwrap();
+        let packet = Packet::deserialize(&buf[..size]).unwrap();
+        assert!(packet.header.verify_key(&station.config.keypair.public));
+    }
+
+    #[tokio::test]
+    async fn pause_rotation() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let (alice, bob, carol) = (network.bind(station_addr(6001)), network.bind(station_addr(6002)),
+            network.bind(station_addr(6003)));
+        request_join(&mut active, &alice, host_addr, "Alice", "pw").await;
+        request_join(&mut active, &bob, host_addr, "Bob", "pw").await;
+        active.poll_token_pass().await.unwrap();
+
+        active.pause_rotation();
+        for _ in 0..3 {
+            assert!(matches!(active.poll_token_pass().await,
+                Err(GlobalError::Internal(TokenRingError::RotationPaused))));
+        }
+        assert_eq!(active.ring_metrics().tokens_passed, 1);
+        // Membership changes are still handled
+        assert!(matches!(request_join(&mut active, &carol, host_addr, "Carol", "pw").await,
+            JoinAnswerResult::Confirm(_)));
+        let token = recv_token(&alice).await;
+        active.recv_token_pass(station_addr(6001), &WorkStationId::new("Alice".to_owned()), token).await.unwrap();
+
+        active.resume_rotation();
+        active.poll_token_pass().await.unwrap();
+        assert_eq!(active.ring_metrics().tokens_passed, 2);
+        assert_eq!(active.ring_metrics().timed_out_stations, 0);
+    }
+
+    #[tokio::test]
+    async fn resume_with_token_out() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6213);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let clock = MockClock::new();
+        active.token_passer = TokenPasser::with_clock(5., Box::new(clock.clone()));
+        let (alice, bob) = (network.bind(station_addr(6214)), network.bind(station_addr(6215)));
+        request_join(&mut active, &alice, host_addr, "Alice", "pw").await;
+        request_join(&mut active, &bob, host_addr, "Bob", "pw").await;
+        active.poll_token_pass().await.unwrap();
+
+        // Resuming right away waits for Alice to return the token
+        active.pause_rotation();
+        active.resume_rotation();
+        assert!(matches!(active.poll_token_pass().await,
+            Err(GlobalError::Internal(TokenRingError::TokenPending))));
+        assert_eq!(active.ring_metrics().tokens_passed, 1);
+        let token = recv_token(&alice).await;
+        active.recv_token_pass(station_addr(6214), &WorkStationId::new("Alice".to_owned()), token).await.unwrap();
+        active.poll_token_pass().await.unwrap();
+        assert_eq!(active.ring_metrics().tokens_passed, 2);
+        recv_token(&bob).await;
+
+        // Bob's turn runs out during the pause, which is not held against him
+        active.pause_rotation();
+        clock.advance(Duration::from_secs(6));
+        active.resume_rotation();
+        active.poll_token_pass().await.unwrap();
+        assert_eq!(active.ring_metrics().tokens_passed, 3);
+        assert_eq!(active.ring_metrics().timed_out_stations, 0);
+    }
+
+    #[tokio::test]
+    async fn quorum_loss_keeps_single_token() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6204);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        active.set_min_members(2);
+        let (alice, bob, carol) = (network.bind(station_addr(6205)), network.bind(station_addr(6206)),
+            network.bind(station_addr(6207)));
+        request_join(&mut active, &alice, host_addr, "Alice", "pw").await;
+        request_join(&mut active, &bob, host_addr, "Bob", "pw").await;
+        active.poll_token_pass().await.unwrap();
+        let token = recv_token(&alice).await;
+
+        // Quorum is lost and regained while Alice holds the token
+        active.remove_station(&WorkStationId::new("Bob".to_owned()));
+        assert!(matches!(active.poll_token_pass().await,
+            Err(GlobalError::Internal(TokenRingError::QuorumNotMet { members: 1, min: 2 }))));
+        request_join(&mut active, &carol, host_addr, "Carol", "pw").await;
+        assert!(matches!(active.poll_token_pass().await,
+            Err(GlobalError::Internal(TokenRingError::TokenPending))));
+        assert_eq!(active.ring_metrics().tokens_passed, 1);
+
+        active.recv_token_pass(station_addr(6205), &WorkStationId::new("Alice".to_owned()), token).await.unwrap();
+        active.poll_token_pass().await.unwrap();
+        assert_eq!(active.ring_metrics().tokens_passed, 2);
+
+        // Same for a ring that shrinks to a single member
+        active.set_min_members(0);
+        let token = recv_token(&carol).await;
+        active.remove_station(&WorkStationId::new("Alice".to_owned()));
+        active.poll_token_pass().await.unwrap();
+        request_join(&mut active, &alice, host_addr, "Alice", "pw").await;
+        assert!(matches!(active.poll_token_pass().await,
+            Err(GlobalError::Internal(TokenRingError::TokenPending))));
+        active.recv_token_pass(station_addr(6207), &WorkStationId::new("Carol".to_owned()), token).await.unwrap();
+        active.poll_token_pass().await.unwrap();
+        assert_eq!(active.ring_metrics().tokens_passed, 3);
+    }
+
+    #[tokio::test]
+    async fn min_members() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        active.set_min_members(3);
+        let (alice, bob, carol) = (network.bind(station_addr(6001)), network.bind(station_addr(6002)),
+            network.bind(station_addr(6003)));
+        request_join(&mut active, &alice, host_addr, "Alice", "pw").await;
+        request_join(&mut active, &bob, host_addr, "Bob", "pw").await;
+        assert!(matches!(active.poll_token_pass().await,
+            Err(GlobalError::Internal(TokenRingError::QuorumNotMet { members: 2, min: 3 }))));
+        assert_eq!(active.ring_metrics().tokens_passed, 0);
+
+        request_join(&mut active, &carol, host_addr, "Carol", "pw").await;
+        active.poll_token_pass().await.unwrap();
+        assert_eq!(active.ring_metrics().tokens_passed, 1);
+    }
+
+    #[tokio::test]
+    async fn source_budget() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let (alice, bob) = (network.bind(station_addr(6001)), network.bind(station_addr(6002)));
+        request_join(&mut active, &alice, host_addr, "Alice", "pw").await;
+        request_join(&mut active, &bob, host_addr, "Bob", "pw").await;
+        active.set_source_budget(Some(SourceBudget::new(2, 1024)));
+        let data = |name: &str, seq| TokenFrame::new(TokenFrameId::new(WorkStationId::new(name.to_owned())),
+            TokenFrameType::Data { send_mode: TokenSendMode::Broadcast, seq, payload: vec![0; 8], expires_at: None });
+
+        // Alice floods, also with a frame claiming to be from Bob. Only her
+        // newest frames are kept.
+        active.pass_on_token().await.unwrap();
+        let mut token = recv_token(&alice).await;
+        token.frames.extend([data("Alice", 0), data("Alice", 1), data("Alice", 2), data("Bob", 9)]);
+        active.recv_token_pass(station_addr(6001), &WorkStationId::new("Alice".to_owned()), token).await.unwrap();
+
+        // Bob stays within budget. Frames of earlier turns don't count against it.
+        active.pass_on_token().await.unwrap();
+        let mut token = recv_token(&bob).await;
+        token.frames.extend([data("Bob", 0), data("Bob", 1)]);
+        active.recv_token_pass(station_addr(6002), &WorkStationId::new("Bob".to_owned()), token).await.unwrap();
+        active.pass_on_token().await.unwrap();
+        let frames = active.token_passer.curr_token.as_ref().unwrap().frames.iter()
+            .map(|f| (f.id.source.to_string(), match f.content {
+                TokenFrameType::Data { seq, .. } => seq,
+                _ => unreachable!()
+            })).collect::<Vec<_>>();
+        assert_eq!(frames, vec![("Alice".to_owned(), 2), ("Bob".to_owned(), 9), ("Bob".to_owned(), 0), ("Bob".to_owned(), 1)]);
+    }
+
+    #[tokio::test]
+    async fn id_for_addr() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6000);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let alice = network.bind(station_addr(6001));
+        request_join(&mut active, &alice, host_addr, "Alice", "pw").await;
+        assert_eq!(active.id_for_addr(station_addr(6001)), Some(WorkStationId::new("Alice".to_owned())));
+        assert_eq!(active.id_for_addr(station_addr(6002)), None);
+    }
+
+    #[tokio::test]
+    async fn max_password_len() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6010);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        active.set_max_password_len(4);
+        let alice = network.bind(station_addr(6011));
+        assert_eq!(request_join(&mut active, &alice, host_addr, "Alice", "password").await,
+            JoinAnswerResult::Deny(DenyReason::WrongPassword));
+        assert!(active.id_for_addr(station_addr(6011)).is_none());
+        assert_eq!(request_join(&mut active, &alice, host_addr, "Alice", "pw").await,
+            JoinAnswerResult::Confirm(WorkStationId::new("Host".to_owned())));
+
+        // Length is not checked if no password is required
+        active.set_require_password(false);
+        let bob = network.bind(station_addr(6012));
+        assert_eq!(request_join(&mut active, &bob, host_addr, "Bob", "password").await,
+            JoinAnswerResult::Confirm(WorkStationId::new("Host".to_owned())));
+    }
+
+    #[tokio::test]
+    async fn received_acks() {
+        let mut station = passive_station("Bob").await;
+        let (alice, carol) = (WorkStationId::new("Alice".to_owned()), WorkStationId::new("Carol".to_owned()));
+        let ack = |from: &WorkStationId, source: &str, seq| TokenFrame::new(TokenFrameId::new(from.clone()),
+            TokenFrameType::DataReceived { source: WorkStationId::new(source.to_owned()), seq });
+        let mut token = create_token();
+        token.frames.extend([ack(&alice, "Bob", 1), ack(&carol, "Bob", 4), ack(&alice, "Carol", 2)]);
+        assert_eq!(station.received_acks().count(), 0);
+        station.recv_token_pass(token);
+        assert_eq!(station.received_acks().collect::<Vec<_>>(), vec![(&alice, 1), (&carol, 4)]);
+    }
+
+    #[tokio::test]
+    async fn compressed_token_pass() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6020);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        active.set_compress_tokens(true);
+        let mut alice = PassiveStation::with_transport(WorkStationId::new("Alice".to_owned()),
+            Arc::new(network.bind(station_addr(6021)))).unwrap();
+        alice.set_compress_tokens(true);
+        let mut bob = PassiveStation::with_transport(WorkStationId::new("Bob".to_owned()),
+            Arc::new(network.bind(station_addr(6022)))).unwrap();
+        alice.connect(host_addr, "pw".to_owned()).await.unwrap();
+        bob.connect(host_addr, "pw".to_owned()).await.unwrap();
+        for _ in 0..20 {
+            let _ = active.recv_all().await;
+            let _ = alice.recv_next().await;
+            let _ = bob.recv_next().await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(alice.is_connected() && bob.is_connected());
+        assert!(active.compressed_peers.contains(&WorkStationId::new("Alice".to_owned())));
+        assert!(!active.compressed_peers.contains(&WorkStationId::new("Bob".to_owned())));
+
+        let mut token = active.generate_token(0).unwrap();
+        // Stays below the frame cap of the active station (two per member)
+        for seq in 0..4 {
+            token.frames.push(TokenFrame::new(TokenFrameId::new(WorkStationId::new("Host".to_owned())),
+                TokenFrameType::Data { send_mode: TokenSendMode::Broadcast, seq, payload: vec![7; 256], expires_at: None }));
+        }
+        let frames = token.frames.clone();
+        active.token_passer.curr_token = Some(token);
+        active.pass_on_token().await.unwrap();
+        pump(&mut alice).await;
+        assert!(alice.compress_reply);
+        assert_eq!(alice.get_token_mut().unwrap().frames, frames);
+
+        // Alice answers compressed, Bob falls back to plain token passes
+        alice.pass_on_token().unwrap();
+        for _ in 0..20 {
+            active.recv_all().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(active.token_passer.curr_token.as_ref().unwrap().frames, frames);
+        active.pass_on_token().await.unwrap();
+        pump(&mut bob).await;
+        assert!(!bob.compress_reply);
+        assert_eq!(bob.get_token_mut().unwrap().frames, frames);
+    }
+
+    #[test]
+    fn connection_transitions() {
+        let (host, addr) = (WorkStationId::new("Host".to_owned()), station_addr(6000));
+        let mut mode = ConnectionMode::Offline;
+        mode.transition(ConnectionEvent::JoinRequested(addr)).unwrap();
+        assert_eq!(mode, ConnectionMode::Pending(addr));
+        mode.transition(ConnectionEvent::JoinFailed).unwrap();
+        assert_eq!(mode, ConnectionMode::Offline);
+        mode.transition(ConnectionEvent::JoinRequested(addr)).unwrap();
+        mode.transition(ConnectionEvent::JoinConfirmed(host.clone())).unwrap();
+        assert_eq!(mode, ConnectionMode::Connected(host.clone(), addr));
+        mode.transition(ConnectionEvent::Left).unwrap();
+        assert_eq!(mode, ConnectionMode::Offline);
+
+        // Invalid moves leave the mode untouched
+        assert!(matches!(mode.transition(ConnectionEvent::JoinConfirmed(host.clone())),
+            Err(GlobalError::Internal(TokenRingError::NotConnected))));
+        assert!(matches!(mode.transition(ConnectionEvent::JoinFailed),
+            Err(GlobalError::Internal(TokenRingError::NotConnected))));
+        assert_eq!(mode, ConnectionMode::Offline);
+        let mut mode = ConnectionMode::Connected(host.clone(), addr);
+        assert!(matches!(mode.transition(ConnectionEvent::JoinRequested(station_addr(6010))),
+            Err(GlobalError::Internal(TokenRingError::AlreadyConnected))));
+        assert!(matches!(mode.transition(ConnectionEvent::JoinConfirmed(host.clone())),
+            Err(GlobalError::Internal(TokenRingError::AlreadyConnected))));
+        assert_eq!(mode, ConnectionMode::Connected(host, addr));
+    }
+
+    #[tokio::test]
+    async fn kick_station() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6030);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let mut alice = PassiveStation::with_transport(WorkStationId::new("Alice".to_owned()),
+            Arc::new(network.bind(station_addr(6031)))).unwrap();
+        let bob = network.bind(station_addr(6032));
+        request_join(&mut active, &bob, host_addr, "Bob", "pw").await;
+        alice.connect(host_addr, "pw".to_owned()).await.unwrap();
+        for _ in 0..20 {
+            let _ = active.recv_all().await;
+            let _ = alice.recv_next().await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(alice.is_connected());
+
+        // Alice is kicked while Bob holds the token, her frames are cleared
+        // from the token he returns
+        let alice_id = WorkStationId::new("Alice".to_owned());
+        let bob_id = WorkStationId::new("Bob".to_owned());
+        active.pass_on_token().await.unwrap();
+        let mut token = recv_token(&bob).await;
+        active.kick(&alice_id, "Spam".to_owned()).await.unwrap();
+        assert_eq!(active.connections(), vec![(bob_id.clone(), station_addr(6032))]);
+        assert!(!active.token_passer.station_status.contains_key(&alice_id));
+        for source in [&alice_id, &bob_id] {
+            token.frames.push(TokenFrame::new(TokenFrameId::new(source.clone()), TokenFrameType::Empty));
+        }
+        active.recv_token_pass(station_addr(6032), &bob_id, token).await.unwrap();
+        active.pass_on_token().await.unwrap();
+        let frames = recv_token(&bob).await.frames;
+        assert!(frames.len() == 1 && frames[0].id.source == bob_id);
+
+        pump(&mut alice).await;
+        assert!(!alice.is_connected());
+        let mut reasons = vec![];
+        while let Some(event) = alice.poll_event() {
+            if let Some(event) = event.downcast_ref::<KickedEvent>() {
+                reasons.push(event.reason.clone());
+            }
+        }
+        assert_eq!(reasons, vec!["Spam".to_owned()]);
+        assert!(active.kick(&alice_id, "Again".to_owned()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn leave_on_drop() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6040);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let mut alice = PassiveStation::with_transport(WorkStationId::new("Alice".to_owned()),
+            Arc::new(network.bind(station_addr(6041)))).unwrap();
+        alice.connect(host_addr, "pw".to_owned()).await.unwrap();
+        for _ in 0..20 {
+            let _ = active.recv_all().await;
+            let _ = alice.recv_next().await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(active.connections().len(), 1);
+
+        drop(alice);
+        for _ in 0..20 {
+            let _ = active.recv_all().await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(active.connections().is_empty());
+    }
+
+    #[tokio::test]
+    async fn token_ack() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6050);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let mut alice = PassiveStation::with_transport(WorkStationId::new("Alice".to_owned()),
+            Arc::new(network.bind(station_addr(6051)))).unwrap();
+        alice.set_ack_tokens(true);
+        alice.connect(host_addr, "pw".to_owned()).await.unwrap();
+        for _ in 0..20 {
+            let _ = active.recv_all().await;
+            let _ = alice.recv_next().await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        active.pass_on_token().await.unwrap();
+        let pass = active.token_pass;
+        // Acks of another pass do not count
+        active.inject_packet(signed_packet(&alice.config.keypair, "Alice",
+            PacketType::TokenAck { pass: pass - 1 }), station_addr(6051));
+        active.recv_all().await.unwrap();
+        assert!(!active.token_passer.holder_acked());
+
+        pump(&mut alice).await;
+        assert_eq!(alice.get_token_mut().unwrap().pass(), pass);
+        for _ in 0..20 {
+            active.recv_all().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(active.token_passer.holder_acked());
+    }
+
+    #[tokio::test]
+    async fn send_to_all() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6060);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let members = [network.bind(station_addr(6061)), network.bind(station_addr(6062))];
+        for (member, name) in members.iter().zip(["Alice", "Bob"]) {
+            request_join(&mut active, member, host_addr, name, "pw").await;
+        }
+        active.flush().await;
+        let mut buf = [0u8; RECV_BUF_LENGTH];
+        for member in members.iter() {
+            while tokio::time::timeout(Duration::from_millis(5), member.recv_from(&mut buf)).await.is_ok() {}
+        }
+
+        active.send_to_all(PacketType::Kicked { reason: "Test".to_owned() }).await.unwrap();
+        active.flush().await;
+        let mut received = vec![];
+        for member in members.iter() {
+            let (size, _) = tokio::time::timeout(Duration::from_millis(100), member.recv_from(&mut buf))
+                .await.unwrap().unwrap();
+            received.push(buf[..size].to_vec());
+            // Exactly one packet per member
+            assert!(tokio::time::timeout(Duration::from_millis(5), member.recv_from(&mut buf)).await.is_err());
+        }
+        assert_eq!(received[0], received[1]);
+        let packet = Packet::deserialize(&received[0]).unwrap();
+        assert!(packet.header.verify_key(&active.config.keypair.public));
+        assert_eq!(packet.content, PacketType::Kicked { reason: "Test".to_owned() });
+    }
+
+    #[tokio::test]
+    async fn join_reply_nonce() {
+        let mut station = passive_station("Bob").await;
+        let host_keypair = generate_keypair();
+        station.connect(station_addr(5000), "pw".to_owned()).await.unwrap();
+        let nonce = station.join_attempt.as_ref().unwrap().request.nonce;
+
+        // Reply recorded from an earlier join
+        station.inject_packet(signed_packet(&host_keypair, "Host", PacketType::JoinReply(
+            JoinAnswerResult::Confirm(WorkStationId::new("Host".to_owned())), nonce.wrapping_add(1))),
+            station_addr(5000));
+        assert!(matches!(station.recv_next().await,
+            Err(GlobalError::Internal(TokenRingError::JoinNonceMismatch))));
+        assert!(!station.is_connected());
+        assert!(station.join_attempt.is_some());
+
+        station.inject_packet(signed_packet(&host_keypair, "Host", host_confirm(&station)), station_addr(5000));
+        station.recv_next().await.unwrap();
+        assert!(station.is_connected());
+    }
+
+    #[tokio::test]
+    async fn invalid_config() {
+        let id = WorkStationId::new("Host".to_owned());
+        for (config, reason) in [(GlobalConfig::new("pw".to_owned(), true, 8, 0.), "max_passover_time must be positive"),
+            (GlobalConfig::new("pw".to_owned(), true, 8, f32::NAN), "max_passover_time must be positive"),
+            (GlobalConfig::new("pw".to_owned(), true, 0, 5.), "max_connections must be at least 1")] {
+            match ActiveStation::host(id.clone(), config, 0).await {
+                Err(GlobalError::Internal(TokenRingError::InvalidConfig(r))) => assert_eq!(r, reason),
+                r => panic!("Expected invalid config, got {:?}.", r.map(|_| ()))
+            }
+        }
+        assert!(GlobalConfig::new("pw".to_owned(), true, 1, 0.5).validate().is_ok());
+
+        // Same rule when changed at runtime
+        let mut active = host_station().await;
+        assert!(matches!(active.set_max_passover_time(Duration::ZERO),
+            Err(GlobalError::Internal(TokenRingError::InvalidConfig(_)))));
+        active.set_max_passover_time(Duration::from_millis(500)).unwrap();
+        assert_eq!(active.max_passover_time(), Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn max_hops() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6070);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let members = [network.bind(station_addr(6071)), network.bind(station_addr(6072))];
+        for (member, name) in members.iter().zip(["Alice", "Bob"]) {
+            request_join(&mut active, member, host_addr, name, "pw").await;
+        }
+        active.set_max_hops(Some(2));
+        let alice_id = WorkStationId::new("Alice".to_owned());
+        let data = TokenFrame::new(TokenFrameId::new(alice_id.clone()), TokenFrameType::Data {
+            send_mode: TokenSendMode::Unicast(WorkStationId::new("Bob".to_owned())), seq: 1,
+            payload: vec![1], expires_at: None });
+
+        // Below the cap the count goes up and frames are kept
+        let mut token = active.generate_token(1).unwrap();
+        token.frames.extend([TokenFrame::new(TokenFrameId::new(alice_id), TokenFrameType::Empty), data.clone()]);
+        active.token_passer.curr_token = Some(token);
+        active.pass_on_token().await.unwrap();
+        let token = active.token_passer.curr_token.as_ref().unwrap();
+        assert_eq!((token.hops(), token.frames.len()), (2, 2));
+
+        // Past the cap the token is regenerated, keeping frames not yet delivered
+        active.pass_on_token().await.unwrap();
+        let token = active.token_passer.curr_token.as_ref().unwrap();
+        assert_eq!(token.hops(), 0);
+        assert_eq!(token.frames, vec![data]);
+    }
+
+    #[tokio::test]
+    async fn has_held_token() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6080);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let members = [network.bind(station_addr(6081)), network.bind(station_addr(6082))];
+        for (member, name) in members.iter().zip(["Alice", "Bob"]) {
+            request_join(&mut active, member, host_addr, name, "pw").await;
+        }
+        let alice = WorkStationId::new("Alice".to_owned());
+        let bob = WorkStationId::new("Bob".to_owned());
+        assert_eq!(active.has_held_token(&WorkStationId::new("Carol".to_owned())), None);
+        assert_eq!((active.has_held_token(&alice), active.has_held_token(&bob)), (Some(false), Some(false)));
+
+        let mut buf = [0u8; RECV_BUF_LENGTH];
+        for (i, (member, id)) in members.iter().zip([&alice, &bob]).enumerate() {
+            active.pass_on_token().await.unwrap();
+            assert_eq!(active.has_held_token(id), Some(false));
+            let (size, _) = tokio::time::timeout(Duration::from_millis(100), member.recv_from(&mut buf))
+                .await.unwrap().unwrap();
+            let token = match Packet::deserialize(&buf[..size]).unwrap().content {
+                PacketType::TokenPass(token) => token,
+                p => panic!("Expected token pass, got {:?}.", p)
+            };
+            active.recv_token_pass(member.local_addr().unwrap(), id, token).await.unwrap();
+            assert_eq!((active.has_held_token(&alice), active.has_held_token(&bob)), (Some(true), Some(i == 1)));
+        }
+
+        // Next pass starts a new rotation
+        active.pass_on_token().await.unwrap();
+        assert_eq!((active.has_held_token(&alice), active.has_held_token(&bob)), (Some(false), Some(false)));
+    }
+
+    #[tokio::test]
+    async fn unicast_over_lossy_network() {
+        let network = MemoryNetwork::new();
+        // No reordering, so datagrams are delivered right away and the run
+        // only depends on the seeds
+        let lossy = |port, seed| Arc::new(LossyTransport::new(network.bind(station_addr(port)),
+            0.2, Duration::ZERO, seed).unwrap());
+        let host_addr = station_addr(6090);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 0.05), lossy(6090, 1)).unwrap();
+        let clock = MockClock::new();
+        active.token_passer = TokenPasser::with_clock(0.05, Box::new(clock.clone()));
+        let mut alice = PassiveStation::with_transport(WorkStationId::new("Alice".to_owned()), lossy(6091, 2)).unwrap();
+        let mut bob = PassiveStation::with_transport(WorkStationId::new("Bob".to_owned()), lossy(6092, 3)).unwrap();
+        for station in [&mut alice, &mut bob] {
+            station.set_join_retry(JoinRetry::new(Duration::ZERO, Duration::ZERO, 1000));
+            station.set_resend_policy(ResendPolicy::new(2, 100));
+            station.connect(host_addr, "pw".to_owned()).await.unwrap();
+        }
+        let (alice_id, bob_id) = (WorkStationId::new("Alice".to_owned()), WorkStationId::new("Bob".to_owned()));
+        let unicast = TokenFrameType::Data { send_mode: TokenSendMode::Unicast(alice_id.clone()),
+            seq: 1, payload: vec![7], expires_at: None };
+
+        let (mut sent, mut delivered, mut acked) = (false, false, false);
+        for _ in 0..3000 {
+            let _ = active.recv_all().await;
+            let _ = active.poll_token_pass().await;
+            for station in [&mut alice, &mut bob] {
+                let _ = station.tick();
+                while !station.recv_queue.is_empty() {
+                    let _ = station.recv_next().await;
+                }
+            }
+            if bob.is_connected() && !sent {
+                bob.append_frame(unicast.clone()).unwrap();
+                sent = true;
+            }
+            if let Some(token) = alice.get_token_mut() {
+                if token.frames.iter().any(|f| f.id.source == bob_id && f.content == unicast) {
+                    delivered = true;
+                    token.frames.push(TokenFrame::new(TokenFrameId::new(alice_id.clone()),
+                        TokenFrameType::DataReceived { source: bob_id.clone(), seq: 1 }));
+                }
+                alice.pass_on_token().unwrap();
+            }
+            if bob.holds_token() {
+                acked |= bob.received_acks().any(|ack| ack == (&alice_id, 1));
+                bob.pass_on_token().unwrap();
+            }
+            if acked {
+                break
+            }
+            // Lost tokens time out after a few iterations
+            clock.advance(Duration::from_millis(10));
+            tokio::task::yield_now().await;
+        }
+        assert!(delivered && acked);
+        while let Some(event) = bob.poll_event() {
+            assert!(event.downcast_ref::<DeliveryFailedEvent>().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn lossy_transport() {
+        let network = MemoryNetwork::new();
+        for drop_rate in [f64::NAN, -0.1, 1.5] {
+            assert!(matches!(LossyTransport::new(network.bind(station_addr(6210)), drop_rate, Duration::ZERO, 1),
+                Err(GlobalError::Internal(TokenRingError::InvalidConfig(_)))));
+        }
+
+        // The seed alone decides which datagrams are lost, with or without reordering
+        let receiver = network.bind(station_addr(6211));
+        let mut received = vec![];
+        for reorder_window in [Duration::ZERO, Duration::from_millis(2)] {
+            let lossy = LossyTransport::new(network.bind(station_addr(6212)), 0.2, reorder_window, 7).unwrap();
+            for i in 0..500u16 {
+                lossy.send_to(&i.to_be_bytes(), station_addr(6211)).await.unwrap();
+            }
+            let mut buf = [0u8; 2];
+            let mut seqs = vec![];
+            while let Ok(Ok(_)) = tokio::time::timeout(Duration::from_millis(20), receiver.recv_from(&mut buf)).await {
+                seqs.push(u16::from_be_bytes(buf));
+            }
+            seqs.sort();
+            received.push(seqs);
+        }
+        assert_eq!(received[0], received[1]);
+        assert!((350..450).contains(&received[0].len()));
+    }
+
+    #[tokio::test]
+    async fn self_addressed_unicast() {
+        let mut station = passive_station("Bob").await;
+        station.set_buffer_offline(true);
+        let unicast = |dest: &str| TokenFrameType::Data { send_mode: TokenSendMode::Unicast(
+            WorkStationId::new(dest.to_owned())), seq: 1, payload: vec![], expires_at: None };
+        assert!(matches!(station.append_frames(vec![unicast("Alice"), unicast("Bob")]),
+            Err(GlobalError::Internal(TokenRingError::SelfAddressed { index: 1 }))));
+        assert!(station.cached_frames.is_empty());
+        station.append_frame(unicast("Alice")).unwrap();
+        assert_eq!(station.cached_frames.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn member_metadata() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6100);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let mut alice = PassiveStation::with_transport(WorkStationId::new("Alice".to_owned()),
+            Arc::new(network.bind(station_addr(6101)))).unwrap();
+        let metadata = JoinMetadata { client_version: 7, display_name: Some("Alice Liddell".to_owned()) };
+        assert!(alice.set_join_metadata(JoinMetadata { client_version: 7,
+            display_name: Some("x".repeat(MAX_DISPLAY_NAME_LEN + 1)) }).is_err());
+        alice.set_join_metadata(metadata.clone()).unwrap();
+        alice.connect(host_addr, "pw".to_owned()).await.unwrap();
+        for _ in 0..20 {
+            let _ = active.recv_all().await;
+            let _ = alice.recv_next().await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(alice.is_connected());
+        let alice_id = WorkStationId::new("Alice".to_owned());
+        assert_eq!(active.member_metadata(&alice_id), Some(&metadata));
+
+        active.kick(&alice_id, "Bye".to_owned()).await.unwrap();
+        assert_eq!(active.member_metadata(&alice_id), None);
+    }
+
+    #[tokio::test]
+    async fn force_new_token() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6110);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let members = [network.bind(station_addr(6111)), network.bind(station_addr(6112))];
+        for (member, name) in members.iter().zip(["Alice", "Bob"]) {
+            request_join(&mut active, member, host_addr, name, "pw").await;
+        }
+        let (alice, bob) = (WorkStationId::new("Alice".to_owned()), WorkStationId::new("Bob".to_owned()));
+        active.set_initial_holder(InitialHolder::LastJoined);
+        let mut token = active.generate_token(0).unwrap();
+        token.frames.push(TokenFrame::new(TokenFrameId::new(alice.clone()), TokenFrameType::Empty));
+        active.token_passer.curr_token = Some(token);
+        active.token_passer.station_status.get_mut(&alice).unwrap().0 = true;
+        let generation = active.token_generation;
+
+        active.force_new_token().await.unwrap();
+        assert_eq!(active.token_generation, generation + 1);
+        assert!(active.token_passer.curr_token.is_none());
+        assert_eq!((active.has_held_token(&alice), active.has_held_token(&bob)), (Some(false), Some(false)));
+        active.flush().await;
+        let mut buf = [0u8; RECV_BUF_LENGTH];
+        let (size, _) = tokio::time::timeout(Duration::from_millis(100), members[1].recv_from(&mut buf))
+            .await.unwrap().unwrap();
+        match Packet::deserialize(&buf[..size]).unwrap().content {
+            PacketType::TokenPass(token) => {
+                assert_eq!(token.generation(), generation + 1);
+                assert!(token.frames.is_empty());
+            },
+            p => panic!("Expected token pass, got {:?}.", p)
+        }
+
+        active.pause_rotation();
+        assert!(matches!(active.force_new_token().await,
+            Err(GlobalError::Internal(TokenRingError::RotationPaused))));
+    }
+
+    #[tokio::test]
+    async fn multicast_data() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6120);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 0.05), Arc::new(network.bind(host_addr))).unwrap();
+        let mut stations = vec![];
+        for (port, name) in [(6121, "Dave"), (6122, "Alice"), (6123, "Bob"), (6124, "Carol")] {
+            let mut station = PassiveStation::with_transport(WorkStationId::new(name.to_owned()),
+                Arc::new(network.bind(station_addr(port)))).unwrap();
+            station.connect(host_addr, "pw".to_owned()).await.unwrap();
+            stations.push(station);
+        }
+        let multicast = TokenFrameType::Data { send_mode: TokenSendMode::Multicast(vec![
+            WorkStationId::new("Alice".to_owned()), WorkStationId::new("Bob".to_owned())]),
+            seq: 1, payload: vec![3], expires_at: None };
+
+        // Dave sends, Alice and Bob receive, Carol only carries the frame
+        let mut received = [false; 4];
+        let (mut sent, mut carol_saw) = (false, false);
+        for _ in 0..2000 {
+            let _ = active.recv_all().await;
+            let _ = active.poll_token_pass().await;
+            for (i, station) in stations.iter_mut().enumerate() {
+                let _ = station.tick();
+                while !station.recv_queue.is_empty() {
+                    let _ = station.recv_next().await;
+                }
+                while let Some(event) = station.poll_event() {
+                    if let Some(event) = event.downcast_ref::<DataEvent>() {
+                        assert_eq!(event.source, WorkStationId::new("Dave".to_owned()));
+                        assert_eq!(event.payload, vec![3]);
+                        received[i] = true;
+                    }
+                }
+                if i == 3 {
+                    carol_saw |= station.get_token_mut().is_some_and(|t| t.frames.iter()
+                        .any(|f| f.content == multicast));
+                }
+                if station.holds_token() {
+                    station.pass_on_token().unwrap();
+                }
+            }
+            if stations[0].is_connected() && !sent {
+                stations[0].append_frame(multicast.clone()).unwrap();
+                sent = true;
+            }
+            if received[1] && received[2] && carol_saw {
+                break
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(received, [false, true, true, false]);
+        assert!(carol_saw);
+
+        // Recipient lists are bounded
+        let oversized = TokenFrameType::Data { send_mode: TokenSendMode::Multicast(
+            vec![WorkStationId::new("Alice".to_owned()); MAX_MULTICAST_RECIPIENTS + 1]),
+            seq: 2, payload: vec![], expires_at: None };
+        assert!(matches!(stations[0].append_frame(oversized),
+            Err(GlobalError::Internal(TokenRingError::LengthTooLarge { .. }))));
+    }
+
+    #[tokio::test]
+    async fn split_brain() {
+        let mut station = passive_station("Bob").await;
+        let (host_keypair, other_keypair) = (generate_keypair(), generate_keypair());
+        station.connect(station_addr(5000), "pw".to_owned()).await.unwrap();
+        station.inject_packet(signed_packet(&host_keypair, "Host", host_confirm(&station)), station_addr(5000));
+        station.recv_next().await.unwrap();
+        while station.poll_event().is_some() {}
+
+        // Tokens of the joined active station are not reported
+        let token = |keypair: &Keypair, name: &str| Token::new(Signed::new(keypair,
+            TokenHeader::new(WorkStationId::new(name.to_owned()), 1)).unwrap());
+        station.inject_packet(signed_packet(&host_keypair, "Host",
+            PacketType::TokenPass(token(&host_keypair, "Host"))), station_addr(5000));
+        station.recv_next().await.unwrap();
+        assert!(station.poll_event().unwrap().downcast_ref::<SplitBrainDetectedEvent>().is_none());
+        station.pass_on_token().unwrap();
+        while station.poll_event().is_some() {}
+
+        // Second active station hosting a ring with the same members
+        station.inject_packet(signed_packet(&other_keypair, "Host2",
+            PacketType::TokenPass(token(&other_keypair, "Host2"))), station_addr(5001));
+        assert!(station.recv_next().await.is_err());
+        assert!(!station.holds_token());
+        let event = station.poll_event().unwrap();
+        let event = event.downcast_ref::<SplitBrainDetectedEvent>().unwrap();
+        assert_eq!(event.expected, WorkStationId::new("Host".to_owned()));
+        assert_eq!(event.seen, WorkStationId::new("Host2".to_owned()));
+        assert_eq!(event.addr, station_addr(5001));
+
+        // Reported once, and further foreign stations not right away
+        station.inject_packet(signed_packet(&other_keypair, "Host2",
+            PacketType::TokenPass(token(&other_keypair, "Host2"))), station_addr(5001));
+        let third_keypair = generate_keypair();
+        station.inject_packet(signed_packet(&third_keypair, "Host3",
+            PacketType::TokenPass(token(&third_keypair, "Host3"))), station_addr(5002));
+        for _ in 0..2 {
+            assert!(station.recv_next().await.is_err());
+        }
+        while let Some(event) = station.poll_event() {
+            assert!(event.downcast_ref::<SplitBrainDetectedEvent>().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_status() {
+        let mut station = passive_station("Bob").await;
+        let host_keypair = generate_keypair();
+        let host_id = WorkStationId::new("Host".to_owned());
+        let status = |mode, holds_token, cached_frames| ConnectionStatus { mode, holds_token, cached_frames };
+        assert_eq!(station.connection_status(), status(ConnectionMode::Offline, false, 0));
+
+        station.connect(station_addr(5000), "pw".to_owned()).await.unwrap();
+        assert_eq!(station.connection_status(), status(ConnectionMode::Pending(station_addr(5000)), false, 0));
+
+        station.inject_packet(signed_packet(&host_keypair, "Host", host_confirm(&station)), station_addr(5000));
+        station.recv_next().await.unwrap();
+        station.append_frame(TokenFrameType::Empty).unwrap();
+        let connected = ConnectionMode::Connected(host_id.clone(), station_addr(5000));
+        assert_eq!(station.connection_status(), status(connected.clone(), false, 1));
+
+        station.inject_packet(signed_packet(&host_keypair, "Host", PacketType::TokenPass(
+            Token::new(Signed::new(&host_keypair, TokenHeader::new(host_id, 1)).unwrap()))), station_addr(5000));
+        station.recv_next().await.unwrap();
+        let held = station.connection_status();
+        assert_eq!(held, status(connected, true, 0));
+
+        let mut buf = vec![];
+        held.write(&mut buf).unwrap();
+        assert_eq!(buf.len(), held.size());
+        assert_eq!(ConnectionStatus::read(&mut Cursor::new(buf.as_slice())).unwrap(), held);
+
+        buf[0] = 3;
+        assert!(matches!(ConnectionStatus::read(&mut Cursor::new(buf.as_slice())),
+            Err(GlobalError::Internal(TokenRingError::InvalidTag { kind: "ConnectionMode", tag: 3 }))));
+    }
+
+    #[tokio::test]
+    async fn single_token_in_flight() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6130);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let members = [network.bind(station_addr(6131)), network.bind(station_addr(6132))];
+        for (member, name) in members.iter().zip(["Alice", "Bob"]) {
+            request_join(&mut active, member, host_addr, name, "pw").await;
+        }
+
+        active.poll_token_pass().await.unwrap();
+        assert!(matches!(active.poll_token_pass().await,
+            Err(GlobalError::Internal(TokenRingError::TokenPending))));
+        assert_eq!(active.tokens_passed, 1);
+
+        // A token returned by a member that does not hold it does not free the ring
+        let token = active.token_passer.curr_token.clone().unwrap_or_else(create_token);
+        let _ = active.token_passer.recv_token(token, &WorkStationId::new("Bob".to_owned()));
+        assert!(matches!(active.poll_token_pass().await,
+            Err(GlobalError::Internal(TokenRingError::TokenPending))));
+        assert_eq!(active.tokens_passed, 1);
+    }
+
+    #[tokio::test]
+    async fn connect_pinned() {
+        let mut station = passive_station("Bob").await;
+        let (host_keypair, other_keypair) = (generate_keypair(), generate_keypair());
+        station.connect_pinned(station_addr(5000), "pw".to_owned(), host_keypair.public).await.unwrap();
+
+        // Right address and nonce, wrong key
+        station.inject_packet(signed_packet(&other_keypair, "Host", host_confirm(&station)), station_addr(5000));
+        assert!(matches!(station.recv_next().await,
+            Err(GlobalError::Internal(TokenRingError::KeyMismatch(_)))));
+        assert_eq!(station.conn_mode, ConnectionMode::Pending(station_addr(5000)));
+
+        station.inject_packet(signed_packet(&host_keypair, "Host", host_confirm(&station)), station_addr(5000));
+        station.recv_next().await.unwrap();
+        assert!(station.is_connected());
+        assert_eq!(station.active_key, Some(host_keypair.public));
+    }
+
+    #[tokio::test]
+    async fn poll_events() {
+        let mut station = passive_station("Carol").await;
+        let mut token = create_token();
+        for name in ["Alice", "Bob"] {
+            token.frames.push(TokenFrame::new(TokenFrameId::new(WorkStationId::new(name.to_owned())),
+                TokenFrameType::Presence { status: PresenceStatus::Typing }));
+        }
+        station.recv_token_pass(token);
+
+        let events = station.poll_events();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].downcast_ref::<PresenceEvent>().unwrap().source, WorkStationId::new("Alice".to_owned()));
+        assert_eq!(events[1].downcast_ref::<PresenceEvent>().unwrap().source, WorkStationId::new("Bob".to_owned()));
+        assert!(events[2].is::<TokenAcquiredEvent>());
+        assert!(station.poll_event().is_none());
+        assert!(station.poll_events().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reject_stale_token() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6140);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let alice = network.bind(station_addr(6141));
+        let alice_id = WorkStationId::new("Alice".to_owned());
+        request_join(&mut active, &alice, host_addr, "Alice", "pw").await;
+        active.token_passer.curr_token = Some(active.generate_token(0).unwrap());
+        active.pass_on_token().await.unwrap();
+        let old_token = active.token_passer.curr_token.clone().unwrap();
+        // Newer token issued while the old one is still out (e.g. after a skip)
+        active.pass_on_token().await.unwrap();
+        let new_token = active.token_passer.curr_token.clone().unwrap();
+        assert_eq!(new_token.generation(), old_token.generation());
+
+        match active.recv_token_pass(station_addr(6141), &alice_id, old_token).await {
+            Err(GlobalError::Internal(TokenRingError::StaleToken { pass, current })) =>
+                assert!(pass < current && current == new_token.pass()),
+            r => panic!("Expected stale token, got {:?}.", r)
+        }
+        assert!(!active.token_passer.pass_ready());
+        active.recv_token_pass(station_addr(6141), &alice_id, new_token).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn wire_dump() {
+        let network = MemoryNetwork::new();
+        let mut station = PassiveStation::with_transport(WorkStationId::new("Bob".to_owned()),
+            Arc::new(network.bind(station_addr(6150)))).unwrap();
+        let lines = Arc::new(Mutex::new(vec![]));
+        let captured = lines.clone();
+        station.set_wire_dump(Some(Arc::new(move |line: &str| captured.lock().unwrap().push(line.to_owned()))));
+
+        station.connect(station_addr(6151), "pw".to_owned()).await.unwrap();
+        station.flush().await;
+        {
+            let lines = lines.lock().unwrap();
+            assert_eq!(lines.len(), 1);
+            // Starts with the packet magic
+            assert!(lines[0].contains("Join request") && lines[0].contains(": 54 52 4e 47 "));
+        }
+
+        station.set_wire_dump(None);
+        station.connect(station_addr(6151), "pw".to_owned()).await.unwrap();
+        station.flush().await;
+        assert_eq!(lines.lock().unwrap().len(), 1);
+
+        assert_eq!(hex_dump(&[0, 15, 255]), "00 0f ff");
+        assert!(hex_dump(&[0; MAX_WIRE_DUMP_LEN + 1]).ends_with(&format!("00 ... ({} bytes total)", MAX_WIRE_DUMP_LEN + 1)));
+    }
+
+    #[tokio::test]
+    async fn late_join_reply() {
+        let mut station = passive_station("Bob").await;
+        let host_keypair = generate_keypair();
+        station.set_join_retry(JoinRetry::new(Duration::ZERO, Duration::ZERO, 1));
+        station.connect(station_addr(5000), "pw".to_owned()).await.unwrap();
+        let stale_reply = host_confirm(&station);
+        assert!(matches!(station.tick(), Err(GlobalError::Internal(TokenRingError::JoinTimedOut(_)))));
+
+        // Reply to the abandoned attempt arrives after reconnecting
+        station.connect(station_addr(5000), "pw".to_owned()).await.unwrap();
+        station.inject_packet(signed_packet(&host_keypair, "Host", stale_reply), station_addr(5000));
+        assert!(matches!(station.recv_next().await,
+            Err(GlobalError::Internal(TokenRingError::JoinNonceMismatch))));
+        assert_eq!(station.conn_mode, ConnectionMode::Pending(station_addr(5000)));
+
+        station.inject_packet(signed_packet(&host_keypair, "Host", host_confirm(&station)), station_addr(5000));
+        station.recv_next().await.unwrap();
+        assert!(station.is_connected());
+    }
+
+    #[tokio::test]
+    async fn with_members() {
+        let network = MemoryNetwork::new();
+        let ids = ["Alice", "Bob", "Carol"].map(|name| WorkStationId::new(name.to_owned()));
+        let members = [6161, 6162, 6163].map(|port| network.bind(station_addr(port)));
+        let keypairs = [(); 3].map(|_| generate_keypair());
+        let mut active = ActiveStation::with_members(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(station_addr(6160))),
+            ids.iter().cloned().zip([6161, 6162, 6163].map(station_addr)).zip(keypairs.iter())
+                .map(|((id, addr), keypair)| (id, addr, keypair.public)).collect()).unwrap();
+        assert_eq!(active.connected_stations.len(), 3);
+
+        // Members sign with their own key and send from their own address
+        let leave = |keypair: &Keypair, port| QueuedPacket(signed_packet(keypair, "Alice", PacketType::Leave()), station_addr(port));
+        assert!(active.verify_recv_packet(&leave(&keypairs[0], 6161)).is_ok());
+        assert!(matches!(active.verify_recv_packet(&leave(&keypairs[1], 6161)),
+            Err(GlobalError::Internal(TokenRingError::KeyMismatch(_)))));
+        assert!(matches!(active.verify_recv_packet(&leave(&keypairs[0], 6162)),
+            Err(GlobalError::Internal(TokenRingError::StationNotRegistered(..)))));
+
+        // Two full rotations in membership order
+        let mut buf = [0u8; RECV_BUF_LENGTH];
+        for _ in 0..2 {
+            for (member, id) in members.iter().zip(ids.iter()) {
+                active.poll_token_pass().await.unwrap();
+                let (size, _) = tokio::time::timeout(Duration::from_millis(100), member.recv_from(&mut buf))
+                    .await.unwrap().unwrap();
+                let token = match Packet::deserialize(&buf[..size]).unwrap().content {
+                    PacketType::TokenPass(token) => token,
+                    p => panic!("Expected token pass, got {:?}.", p)
+                };
+                active.recv_token_pass(member.local_addr().unwrap(), id, token).await.unwrap();
+            }
+            assert!(ids.iter().all(|id| active.has_held_token(id) == Some(true)));
+        }
+        assert_eq!(active.tokens_passed, 6);
+    }
+
+    #[tokio::test]
+    async fn rebind() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6170);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let mut station = PassiveStation::with_transport(WorkStationId::new("Bob".to_owned()),
+            Arc::new(network.bind(station_addr(6171)))).unwrap();
+        let bob = WorkStationId::new("Bob".to_owned());
+
+        station.connect(host_addr, "pw".to_owned()).await.unwrap();
+        for _ in 0..20 {
+            let _ = active.recv_all().await;
+            let _ = station.recv_next().await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(station.is_connected());
+        station.append_frame(TokenFrameType::Empty).unwrap();
+
+        // Network change: the old socket can not send anymore
+        network.break_sends(station_addr(6171));
+        station.rebind_transport(Arc::new(network.bind(station_addr(6172)))).await.unwrap();
+        assert_eq!(station.local_addr(), station_addr(6172));
+        assert_eq!(station.conn_mode, ConnectionMode::Pending(host_addr));
+        assert_eq!(station.cached_frames.len(), 1);
+        for _ in 0..20 {
+            let _ = active.recv_all().await;
+            let _ = station.recv_next().await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(station.is_connected());
+        assert_eq!(active.connected_stations.get(&bob), Some(&station_addr(6172)));
+        assert_eq!(active.id_for_addr(station_addr(6171)), None);
+        assert_eq!(station.cached_frames.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rebind_same_port() {
+        let mut station = PassiveStation::new(WorkStationId::new("Bob".to_owned()), 0).await.unwrap();
+        let port = station.local_addr().port();
+        // Old loops must let go of the socket, although nothing arrives
+        station.rebind(port).await.unwrap();
+        assert_eq!(station.local_addr().port(), port);
+        assert_eq!(station.loops.len(), 2);
+        station.rebind(port).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unexpected_packet() {
+        let mut active = host_station().await;
+        let mut station = passive_station("Bob").await;
+        let keypair = generate_keypair();
+
+        active.inject_packet(signed_packet(&keypair, "Bob",
+            PacketType::JoinReply(JoinAnswerResult::Confirm(WorkStationId::new("Bob".to_owned())), 0)), station_addr(5001));
+        assert!(matches!(active.recv_all().await,
+            Err(GlobalError::Internal(TokenRingError::UnexpectedPacket(_, addr))) if addr == station_addr(5001)));
+        assert!(active.connected_stations.is_empty());
+
+        station.inject_packet(signed_packet(&keypair, "Alice",
+            PacketType::JoinRequest(JoinRequest::new("pw".to_owned()))), station_addr(5002));
+        assert!(matches!(station.recv_next().await,
+            Err(GlobalError::Internal(TokenRingError::UnexpectedPacket(_, addr))) if addr == station_addr(5002)));
+    }
+
+    #[tokio::test]
+    async fn token_hold_request() {
+        let network = MemoryNetwork::new();
+        let host_addr = station_addr(6180);
+        let mut active = ActiveStation::with_transport(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(host_addr))).unwrap();
+        let clock = MockClock::new();
+        active.token_passer = TokenPasser::with_clock(5., Box::new(clock.clone()));
+        active.set_max_hold_extension(Duration::from_secs(3));
+        let (alice, bob) = (network.bind(station_addr(6181)), network.bind(station_addr(6182)));
+        let alice_keypair = generate_keypair();
+        request_join_with(&mut active, &alice, host_addr, &alice_keypair, "Alice", "pw").await;
+        request_join(&mut active, &bob, host_addr, "Bob", "pw").await;
+
+        active.pass_on_token().await.unwrap();
+        send_raw(&alice, host_addr, &alice_keypair, "Alice",
+            PacketType::TokenHoldRequest { extra: Duration::from_secs(10) }).await;
+        for _ in 0..10 {
+            active.recv_all().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // Would have timed out after 5s, extension is capped at 3s
+        clock.advance(Duration::from_secs(6));
+        assert!(!active.token_passer.pass_ready());
+        clock.advance(Duration::from_secs(2));
+        assert!(active.token_passer.pass_ready());
+    }
+
+    #[tokio::test]
+    async fn on_new_token() {
+        let network = MemoryNetwork::new();
+        let ids = ["Alice", "Bob"].map(|name| WorkStationId::new(name.to_owned()));
+        let members = [6191, 6192].map(|port| network.bind(station_addr(port)));
+        let mut active = ActiveStation::with_members(WorkStationId::new("Host".to_owned()),
+            GlobalConfig::new("pw".to_owned(), true, 8, 5.), Arc::new(network.bind(station_addr(6190))),
+            ids.iter().cloned().zip([6191, 6192].map(station_addr))
+                .map(|(id, addr)| (id, addr, generate_keypair().public)).collect()).unwrap();
+        let mut seq = 0;
+        active.on_new_token(Box::new(move |token| {
+            token.frames.push(TokenFrame::new(TokenFrameId::new(WorkStationId::new("Host".to_owned())),
+                TokenFrameType::Data { send_mode: TokenSendMode::Broadcast, seq, payload: vec![], expires_at: None }));
+            seq += 1;
+        }));
+
+        // Two rotations; every holder returns the token unchanged
+        let mut buf = [0u8; RECV_BUF_LENGTH];
+        let mut passed = vec![];
+        for _ in 0..2 {
+            for (member, id) in members.iter().zip(ids.iter()) {
+                active.poll_token_pass().await.unwrap();
+                let (size, _) = tokio::time::timeout(Duration::from_millis(100), member.recv_from(&mut buf))
+                    .await.unwrap().unwrap();
+                let token = match Packet::deserialize(&buf[..size]).unwrap().content {
+                    PacketType::TokenPass(token) => token,
+                    p => panic!("Expected token pass, got {:?}.", p)
+                };
+                passed.push(token.frames.iter().map(|f| match f.content {
+                    TokenFrameType::Data { seq, .. } => seq,
+                    _ => panic!("Expected data frame.")
+                }).collect::<Vec<_>>());
+                active.recv_token_pass(member.local_addr().unwrap(), id, token).await.unwrap();
+            }
+        }
+        assert_eq!(passed, vec![vec![0], vec![0], vec![0, 1], vec![0, 1]]);
+    }
+
+    #[tokio::test]
+    async fn token_conflict() {
+        let mut station = passive_station("Bob").await;
+        station.set_buffer_offline(true);
+        let bob = WorkStationId::new("Bob".to_owned());
+        let alice_frame = TokenFrame::new(TokenFrameId::new(WorkStationId::new("Alice".to_owned())),
+            TokenFrameType::Empty);
+        let mut token = create_token();
+        token.frames.push(alice_frame.clone());
+        station.recv_token_pass(token.clone());
+        station.append_frame(TokenFrameType::Data { send_mode: TokenSendMode::Broadcast, seq: 1,
+            payload: b"queued".to_vec(), expires_at: None }).unwrap();
+        station.poll_events();
+
+        // Second token carries Alice's frame too, but not the queued one
+        station.recv_token_pass(token);
+        let events = station.poll_events();
+        assert_eq!(events[0].downcast_ref::<TokenConflictEvent>().unwrap().moved_frames, 1);
+        let frames = &station.curr_token.as_ref().unwrap().frames;
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], alice_frame);
+        assert!(frames[1].id.source == bob && matches!(&frames[1].content,
+            TokenFrameType::Data { payload, .. } if payload == b"queued"));
+
+        // Acked unicast frames of the old token are not moved back in
+        let alice = WorkStationId::new("Alice".to_owned());
+        station.append_frame(TokenFrameType::Data { send_mode: TokenSendMode::Unicast(alice.clone()),
+            seq: 2, payload: vec![], expires_at: None }).unwrap();
+        let mut token = create_token();
+        token.frames.push(TokenFrame::new(TokenFrameId::new(alice),
+            TokenFrameType::DataReceived { source: bob, seq: 2 }));
+        station.recv_token_pass(token);
+        let events = station.poll_events();
+        assert_eq!(events[0].downcast_ref::<TokenConflictEvent>().unwrap().moved_frames, 1);
+        assert_eq!(station.curr_token.as_ref().unwrap().frames.len(), 2);
+    }
+}
@@ -1,20 +1,48 @@
 use core::fmt;
-use std::{io::Cursor};
+use std::{io::Cursor, collections::HashMap, sync::atomic::{AtomicU32, Ordering}};
 use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
-use crate::{id::WorkStationId, serialize::{Serializable, write_vec, read_vec, write_byte_vec, read_byte_vec}, signature::Signed, err::TResult, util::timestamp};
+use ed25519_dalek::{Keypair, PublicKey};
+use crate::{id::WorkStationId, serialize::{Serializable, write_vec, read_vec, write_byte_vec, read_byte_vec}, signature::{Signed, DetachedSignature}, err::{TResult, GlobalError, TokenRingError}, util::timestamp};
+
+// Largest token accepted when decompressing, checked before allocating
+pub const MAX_TOKEN_LEN: usize = 64 * 1024;
+// Largest recipient list of a multicast frame
+pub const MAX_MULTICAST_RECIPIENTS: usize = 16;
+
+// Tiebreaker for frame IDs created within the same second (see TokenFrameId::order_key)
+static FRAME_COUNTER: AtomicU32 = AtomicU32::new(0);
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TokenHeader {
     origin: WorkStationId,
-    timestamp: u64
+    timestamp: u64,
+    // Incremented by the active station for every newly minted token (first
+    // pass, regeneration, force_new_token). Stays the same while it circulates.
+    generation: u64,
+    // Incremented by the active station for every pass, tells returned tokens
+    // apart (see TokenReplay and StaleToken)
+    pass: u64,
+    // Passes since the frames were last reset (see GlobalConfig::max_hops)
+    hops: u32
 }
 
 impl TokenHeader {
-    pub fn new(origin: WorkStationId) -> TokenHeader {
+    pub fn new(origin: WorkStationId, generation: u64) -> TokenHeader {
         TokenHeader {
-            origin, timestamp: timestamp()
+            origin, timestamp: timestamp(), generation, pass: 0, hops: 0
         }
     }
+
+    pub fn with_pass(mut self, pass: u64) -> TokenHeader {
+        self.pass = pass;
+        self
+    }
+
+    pub fn with_hops(mut self, hops: u32) -> TokenHeader {
+        self.hops = hops;
+        self
+    }
 }
 
 impl Serializable for TokenHeader {
@@ -22,24 +50,44 @@ impl Serializable for TokenHeader {
 
     fn write(&self, buf: &mut Vec<u8>) -> TResult {
         self.origin.write(buf)?;
-        Ok(buf.write_u64::<BigEndian>(self.timestamp)?)
+        buf.write_u64::<BigEndian>(self.timestamp)?;
+        buf.write_u64::<BigEndian>(self.generation)?;
+        buf.write_u64::<BigEndian>(self.pass)?;
+        Ok(buf.write_u32::<BigEndian>(self.hops)?)
     }
 
     fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
         let origin = WorkStationId::read(buf)?;
         let timestamp = buf.read_u64::<BigEndian>()?;
-        Ok(TokenHeader { origin, timestamp })
+        let generation = buf.read_u64::<BigEndian>()?;
+        let pass = buf.read_u64::<BigEndian>()?;
+        let hops = buf.read_u32::<BigEndian>()?;
+        Ok(TokenHeader { origin, timestamp, generation, pass, hops })
     }
 
     fn size(&self) -> usize {
-        self.origin.size() + 4
+        self.origin.size() + 8 + 8 + 8 + 4
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TokenSendMode {
     Unicast(WorkStationId),
-    Broadcast
+    Broadcast,
+    // Subset of the ring, at most MAX_MULTICAST_RECIPIENTS stations
+    Multicast(Vec<WorkStationId>)
+}
+
+impl TokenSendMode {
+    // Whether a frame sent with this mode is meant for the station with id
+    pub fn addresses(&self, id: &WorkStationId) -> bool {
+        match self {
+            TokenSendMode::Unicast(dest) => dest == id,
+            TokenSendMode::Broadcast => true,
+            TokenSendMode::Multicast(dests) => dests.contains(id)
+        }
+    }
 }
 
 impl Serializable for TokenSendMode {
@@ -52,6 +100,17 @@ impl Serializable for TokenSendMode {
                 dest.write(buf)?;
             },
             TokenSendMode::Broadcast => buf.write_u8(1)?,
+            TokenSendMode::Multicast(dests) => {
+                if dests.len() > MAX_MULTICAST_RECIPIENTS {
+                    return Err(GlobalError::Internal(TokenRingError::LengthTooLarge {
+                        len: dests.len(), max: MAX_MULTICAST_RECIPIENTS }))
+                }
+                buf.write_u8(2)?;
+                buf.write_u8(dests.len() as u8)?;
+                for dest in dests.iter() {
+                    dest.write(buf)?;
+                }
+            }
         })
     }
 
@@ -61,7 +120,19 @@ impl Serializable for TokenSendMode {
                 TokenSendMode::Unicast(WorkStationId::read(buf)?)
             },
             1 => TokenSendMode::Broadcast,
-            n @ _ => panic!("Index out of bounds: {n}.")
+            2 => {
+                let len = buf.read_u8()? as usize;
+                if len > MAX_MULTICAST_RECIPIENTS {
+                    return Err(GlobalError::Internal(TokenRingError::LengthTooLarge {
+                        len, max: MAX_MULTICAST_RECIPIENTS }))
+                }
+                let mut dests = Vec::with_capacity(len);
+                for _ in 0..len {
+                    dests.push(WorkStationId::read(buf)?);
+                }
+                TokenSendMode::Multicast(dests)
+            },
+            tag => return Err(GlobalError::Internal(TokenRingError::InvalidTag { kind: "TokenSendMode", tag }))
         })
     }
 
@@ -69,22 +140,74 @@ impl Serializable for TokenSendMode {
         1 + match self {
             TokenSendMode::Unicast(dest) => dest.size(),
             TokenSendMode::Broadcast => 0,
+            TokenSendMode::Multicast(dests) => 1 + dests.iter().map(|d| d.size()).sum::<usize>(),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+// Ephemeral station state carried by presence frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    Typing
+}
+
+impl Serializable for PresenceStatus {
+    type Output = PresenceStatus;
+
+    fn write(&self, buf: &mut Vec<u8>) -> TResult {
+        Ok(buf.write_u8(match self {
+            PresenceStatus::Online => 0,
+            PresenceStatus::Away => 1,
+            PresenceStatus::Typing => 2
+        })?)
+    }
+
+    fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
+        Ok(match buf.read_u8()? {
+            0 => PresenceStatus::Online,
+            1 => PresenceStatus::Away,
+            2 => PresenceStatus::Typing,
+            tag => return Err(GlobalError::Internal(TokenRingError::InvalidTag { kind: "PresenceStatus", tag }))
+        })
+    }
+
+    fn size(&self) -> usize {
+        1
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TokenFrameId {
     pub source: WorkStationId,
     timestamp: u64,
+    // Increases with every ID created by this process (wraps)
+    counter: u32
 }
 
 impl TokenFrameId {
     pub fn new(source: WorkStationId) -> TokenFrameId {
         TokenFrameId {
-            source, timestamp: timestamp()
+            source, timestamp: timestamp(), counter: FRAME_COUNTER.fetch_add(1, Ordering::Relaxed)
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_timestamp(source: WorkStationId, timestamp: u64) -> TokenFrameId {
+        TokenFrameId {
+            source, timestamp, counter: 0
         }
     }
+
+    // Frames are ordered by timestamp, then source ID, then creation order at
+    // the source. Timestamps are whole seconds, so the counter tells apart
+    // frames a station created within the same second.
+    fn order_key(&self) -> (u64, &WorkStationId, u32) {
+        (self.timestamp, &self.source, self.counter)
+    }
 }
 
 impl Serializable for TokenFrameId {
@@ -92,28 +215,33 @@ impl Serializable for TokenFrameId {
 
     fn write(&self, buf: &mut Vec<u8>) -> TResult {
         self.source.write(buf)?;
-        Ok(buf.write_u64::<BigEndian>(self.timestamp)?)
+        buf.write_u64::<BigEndian>(self.timestamp)?;
+        Ok(buf.write_u32::<BigEndian>(self.counter)?)
     }
 
     fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
         let source = WorkStationId::read(buf)?;
         let timestamp = buf.read_u64::<BigEndian>()?;
+        let counter = buf.read_u32::<BigEndian>()?;
         Ok(TokenFrameId {
-            source, timestamp
+            source, timestamp, counter
         })
     }
 
     fn size(&self) -> usize {
-        self.source.size() + 4 // Timestamp stored as f32
+        self.source.size() + 8 + 4
     }
 }
 
 #[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Token {
     pub header: Signed<TokenHeader>,
     // Signed container not necessary anymore
     // Using star topology now, so active monitor (de facto server) will 
     // be able to check validity of token changes by each client after they pass it on.
+    // Count is u32-prefixed, but a token sent over the wire must fit into
+    // RECV_BUF_LENGTH, so real tokens carry far fewer frames.
     pub frames: Vec<TokenFrame>
 }
 
@@ -123,6 +251,96 @@ impl Token {
             header, frames: vec![]
         }
     }
+
+    // Sorts all data frames by their ID (see TokenFrameId::order_key). Other frame
+    // types keep their position in the token.
+    pub fn sort_data_frames(&mut self) {
+        let slots = self.frames.iter().enumerate()
+            .filter(|(_, f)| matches!(f.content, TokenFrameType::Data { .. }))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let mut data_frames = slots.iter()
+            .map(|i| self.frames[*i].clone())
+            .collect::<Vec<_>>();
+        data_frames.sort_by(|a, b| a.id.order_key().cmp(&b.id.order_key()));
+        for (i, frame) in slots.into_iter().zip(data_frames) {
+            self.frames[i] = frame;
+        }
+    }
+
+    pub fn origin(&self) -> &WorkStationId {
+        &self.header.val.origin
+    }
+
+    // Same for every pass of a minted token, see TokenHeader::generation
+    pub fn generation(&self) -> u64 {
+        self.header.val.generation
+    }
+
+    pub fn pass(&self) -> u64 {
+        self.header.val.pass
+    }
+
+    pub fn hops(&self) -> u32 {
+        self.header.val.hops
+    }
+
+    // When the active station minted the token (unix seconds)
+    pub fn origin_timestamp(&self) -> u64 {
+        self.header.val.timestamp
+    }
+
+    // Removes data frames whose expiry has passed
+    pub fn drop_expired_frames(&mut self, now: u64) {
+        self.frames.retain(|f| !f.content.is_expired(now));
+    }
+
+    // True if every frame carries a valid signature by its source, made with
+    // the key known for that source. Frames of unknown sources never pass.
+    pub fn verify_all_frames(&self, keys: &HashMap<WorkStationId, PublicKey>) -> bool {
+        self.frames.iter().all(|f| keys.get(&f.id.source).is_some_and(|key| f.verify_key(key)))
+    }
+
+    // Share of the encoded token that is not frame payload, i.e. header,
+    // frame IDs, signatures and length prefixes (0 for an all-payload token)
+    pub fn overhead_ratio(&self) -> f32 {
+        let payload = self.frames.iter().map(|f| f.content.payload_len()).sum::<usize>();
+        let size = self.size();
+        (size - payload) as f32 / size as f32
+    }
+
+    // Frames only, without the signed header (e.g. for audit logs)
+    pub fn serialize_frames(&self) -> TResult<Vec<u8>> {
+        let mut buf = Vec::with_capacity(self.size() - self.header.size());
+        write_vec(&mut buf, &self.frames)?;
+        Ok(buf)
+    }
+
+    pub fn deserialize_frames(buf: &[u8]) -> TResult<Vec<TokenFrame>> {
+        read_vec(&mut Cursor::new(buf))
+    }
+
+    // Serialized token, LZ4 compressed and prefixed with the uncompressed size
+    pub fn compress(&self) -> TResult<Vec<u8>> {
+        let mut raw = Vec::with_capacity(self.size());
+        self.write(&mut raw)?;
+        let compressed = lz4_flex::block::compress(&raw);
+        let mut buf = Vec::with_capacity(4 + compressed.len());
+        buf.write_u32::<BigEndian>(raw.len() as u32)?;
+        buf.extend_from_slice(&compressed);
+        Ok(buf)
+    }
+
+    pub fn decompress(buf: &[u8]) -> TResult<Token> {
+        let mut cursor = Cursor::new(buf);
+        let len = cursor.read_u32::<BigEndian>()? as usize;
+        if len > MAX_TOKEN_LEN {
+            return Err(GlobalError::Internal(TokenRingError::LengthTooLarge { len, max: MAX_TOKEN_LEN }))
+        }
+        let raw = lz4_flex::block::decompress(&buf[4..], len)
+            .map_err(|_| GlobalError::Internal(TokenRingError::InvalidCompression))?;
+        Token::read(&mut Cursor::new(raw.as_slice()))
+    }
 }
 
 impl fmt::Debug for Token {
@@ -148,23 +366,70 @@ impl Serializable for Token {
     }
 
     fn size(&self) -> usize {
-        self.header.size() + self.frames.iter().map(
+        self.header.size() + 4 + self.frames.iter().map(
             |f| f.size()).sum::<usize>()
     }
 }
 
 #[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TokenFrame {
     pub id: TokenFrameId,
-    pub content: TokenFrameType
+    pub content: TokenFrameType,
+    // Optional signature over id and content, made by the frame source.
+    // Lets stations attribute frames independently of the active station.
+    pub signature: Option<DetachedSignature>
 }
 
 impl TokenFrame {
     pub fn new(id: TokenFrameId, content: TokenFrameType) -> TokenFrame {
         TokenFrame {
-            id, content
+            id, content, signature: None
+        }
+    }
+
+    pub fn source(&self) -> &WorkStationId {
+        &self.id.source
+    }
+
+    // When the source appended the frame (unix seconds)
+    pub fn sent_at(&self) -> u64 {
+        self.id.timestamp
+    }
+
+    pub fn sign(&mut self, keypair: &Keypair) -> TResult {
+        let bytes = self.signed_bytes()?;
+        self.signature = Some(DetachedSignature::new(keypair, &bytes));
+        Ok(())
+    }
+
+    // Reads back a value stored with TokenFrameType::data_from
+    pub fn decode<T: Serializable<Output = T>>(&self) -> TResult<T> {
+        match &self.content {
+            TokenFrameType::Data { payload, .. } => T::read(&mut Cursor::new(payload.as_slice())),
+            _ => Err(GlobalError::Internal(TokenRingError::UnexpectedFrameType))
         }
     }
+
+    // Unsigned frames never verify. Says nothing about who signed, see verify_key.
+    pub fn verify(&self) -> bool {
+        match (self.signature.as_ref(), self.signed_bytes()) {
+            (Some(signature), Ok(bytes)) => signature.verify(&bytes),
+            _ => false
+        }
+    }
+
+    // Valid signature made by the given key?
+    pub fn verify_key(&self, key: &PublicKey) -> bool {
+        self.signature.as_ref().is_some_and(|s| s.public_key() == key) && self.verify()
+    }
+
+    fn signed_bytes(&self) -> TResult<Vec<u8>> {
+        let mut buf = vec![];
+        self.id.write(&mut buf)?;
+        self.content.write(&mut buf)?;
+        Ok(buf)
+    }
 }
 
 impl fmt::Debug for TokenFrame {
@@ -178,31 +443,67 @@ impl Serializable for TokenFrame {
 
     fn write(&self, buf: &mut Vec<u8>) -> TResult {
         self.id.write(buf)?;
-        self.content.write(buf)
+        self.content.write(buf)?;
+        match self.signature.as_ref() {
+            Some(signature) => {
+                buf.write_u8(1)?;
+                signature.write(buf)
+            },
+            None => Ok(buf.write_u8(0)?)
+        }
     }
 
     fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
         let id = TokenFrameId::read(buf)?;
         let content = TokenFrameType::read(buf)?;
-        Ok(TokenFrame::new(id, content))
+        let signature = match buf.read_u8()? {
+            0 => None,
+            _ => Some(DetachedSignature::read(buf)?)
+        };
+        Ok(TokenFrame {
+            id, content, signature
+        })
     }
 
     fn size(&self) -> usize {
-        self.id.size() + self.content.size()
+        self.id.size() + self.content.size() + 1 +
+            self.signature.as_ref().map_or(0, |s| s.size())
     }
 }
 
 #[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TokenFrameType {
     Empty,
     Data {
         send_mode: TokenSendMode,
-        seq: u16, // Sequence of frame (for identification purposes)
-        payload: Vec<u8>
+        seq: u16, // Sequence of frame (for identification purposes, wraps; order with util::seq_cmp)
+        payload: Vec<u8>,
+        // Epoch seconds after which the frame is dropped instead of delivered
+        expires_at: Option<u64>
     },
+    // Ack of a unicast data frame. Added by the receiver (frame ID source),
+    // source is the station that sent the acked frame.
     DataReceived {
         source: WorkStationId,
         seq: u16
+    },
+    // Not acked or stored; the active station drops it after one rotation
+    Presence {
+        status: PresenceStatus
+    },
+    // Application-defined frame, decoded by a registered decoder (see
+    // PassiveStation::register_app_frame)
+    App {
+        type_id: u16,
+        payload: Vec<u8>
+    },
+    // Added by the active station for a unicast frame of source whose
+    // destination left the ring. Consumed by source.
+    DeliveryFailed {
+        source: WorkStationId,
+        dest: WorkStationId,
+        seq: u16
     }
 }
 
@@ -213,12 +514,16 @@ impl Serializable for TokenFrameType {
         Ok(match self {
             TokenFrameType::Empty => buf.write_u8(0)?,
             TokenFrameType::Data { send_mode,
-                seq, payload } => {
-                buf.write_u8(1)?;
+                seq, payload, expires_at } => {
+                // Frames without expiry keep the original layout
+                buf.write_u8(if expires_at.is_some() { 4 } else { 1 })?;
 
                 send_mode.write(buf)?;
                 buf.write_u16::<BigEndian>(*seq)?;
                 write_byte_vec(buf, payload)?;
+                if let Some(expires_at) = expires_at {
+                    buf.write_u64::<BigEndian>(*expires_at)?;
+                }
             },
             TokenFrameType::DataReceived { source, seq } => {
                 buf.write_u8(2)?;
@@ -226,24 +531,58 @@ impl Serializable for TokenFrameType {
                 source.write(buf)?;
                 buf.write_u16::<BigEndian>(*seq)?;
             },
+            TokenFrameType::Presence { status } => {
+                buf.write_u8(3)?;
+                status.write(buf)?;
+            },
+            TokenFrameType::App { type_id, payload } => {
+                buf.write_u8(5)?;
+
+                buf.write_u16::<BigEndian>(*type_id)?;
+                write_byte_vec(buf, payload)?;
+            },
+            TokenFrameType::DeliveryFailed { source, dest, seq } => {
+                buf.write_u8(6)?;
+
+                source.write(buf)?;
+                dest.write(buf)?;
+                buf.write_u16::<BigEndian>(*seq)?;
+            }
         })
     }
 
     fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
         Ok(match buf.read_u8()? {
             0 => TokenFrameType::Empty,
-            1 => {
+            n @ (1 | 4) => {
                 let send_mode = TokenSendMode::read(buf)?;
                 let seq = buf.read_u16::<BigEndian>()?;
                 let payload = read_byte_vec(buf)?;
-                TokenFrameType::Data { send_mode, seq, payload }
+                let expires_at = if n == 4 {
+                    Some(buf.read_u64::<BigEndian>()?)
+                } else {
+                    None
+                };
+                TokenFrameType::Data { send_mode, seq, payload, expires_at }
             },
             2 => {
                 let source = WorkStationId::read(buf)?;
                 let seq = buf.read_u16::<BigEndian>()?;
                 TokenFrameType::DataReceived { source, seq }
             },
-            n @ _ => panic!("Index out of bounds: {n}.")
+            3 => TokenFrameType::Presence { status: PresenceStatus::read(buf)? },
+            5 => {
+                let type_id = buf.read_u16::<BigEndian>()?;
+                let payload = read_byte_vec(buf)?;
+                TokenFrameType::App { type_id, payload }
+            },
+            6 => {
+                let source = WorkStationId::read(buf)?;
+                let dest = WorkStationId::read(buf)?;
+                let seq = buf.read_u16::<BigEndian>()?;
+                TokenFrameType::DeliveryFailed { source, dest, seq }
+            },
+            tag => return Err(GlobalError::Internal(TokenRingError::InvalidTag { kind: "TokenFrameType", tag }))
         })
     }
 
@@ -251,10 +590,37 @@ impl Serializable for TokenFrameType {
         1 + match self {
             TokenFrameType::Empty => 0,
             TokenFrameType::Data { send_mode,
-                payload, .. } =>
-                send_mode.size() + 2 + payload.len(),
+                payload, expires_at, .. } =>
+                send_mode.size() + 2 + 2 + payload.len() + expires_at.map_or(0, |_| 8),
             TokenFrameType::DataReceived { source, .. } => 
                 source.size() + 2,
+            TokenFrameType::Presence { status } => status.size(),
+            TokenFrameType::App { payload, .. } => 2 + 2 + payload.len(),
+            TokenFrameType::DeliveryFailed { source, dest, .. } =>
+                source.size() + dest.size() + 2
+        }
+    }
+}
+
+impl TokenFrameType {
+    // Data frame (without expiry) carrying the serialized value
+    pub fn data_from<T: Serializable>(value: &T, send_mode: TokenSendMode, seq: u16) -> TResult<TokenFrameType> {
+        let mut payload = Vec::with_capacity(value.size());
+        value.write(&mut payload)?;
+        Ok(TokenFrameType::Data { send_mode, seq, payload, expires_at: None })
+    }
+
+    // Only data frames can expire
+    pub fn is_expired(&self, now: u64) -> bool {
+        matches!(self, TokenFrameType::Data { expires_at: Some(expires_at), .. }
+            if now >= *expires_at)
+    }
+
+    // Application bytes carried by data and app frames
+    pub fn payload_len(&self) -> usize {
+        match self {
+            TokenFrameType::Data { payload, .. } | TokenFrameType::App { payload, .. } => payload.len(),
+            _ => 0
         }
     }
 }
@@ -268,26 +634,33 @@ impl std::fmt::Debug for TokenFrameType {
                 write!(f, "Data: {:?}, {:?}b", send_mode, payload.len()),
             TokenFrameType::DataReceived { source, .. } => 
                 write!(f, "Data Ack: {source}"),
+            TokenFrameType::Presence { status } =>
+                write!(f, "Presence: {:?}", status),
+            TokenFrameType::App { type_id, payload } =>
+                write!(f, "App {type_id}: {:?}b", payload.len()),
+            TokenFrameType::DeliveryFailed { dest, seq, .. } =>
+                write!(f, "Delivery failed: {dest} ({seq})"),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::Cursor;
-    use crate::{signature::{generate_keypair, Signed}, id::WorkStationId, serialize::Serializable};
-    use super::{Token, TokenHeader, TokenFrame, TokenFrameId, TokenSendMode, TokenFrameType};
+    use std::{io::Cursor, collections::HashMap};
+    use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
+    use crate::{signature::{generate_keypair, Signed}, id::WorkStationId, serialize::Serializable, err::{TResult, GlobalError, TokenRingError}};
+    use super::{Token, TokenHeader, TokenFrame, TokenFrameId, TokenSendMode, TokenFrameType, PresenceStatus, MAX_TOKEN_LEN};
 
     fn create_token_stub() -> Token {
         let keypair = generate_keypair();
         let header = TokenHeader::new(
-            WorkStationId::new("Test".to_owned()));
+            WorkStationId::new("Test".to_owned()), 0);
         let signed_header = Signed::new(&keypair, header).unwrap();
         let mut token = Token::new(signed_header);
         let frame = TokenFrame::new(TokenFrameId::new(
         WorkStationId::new("Some Station".to_owned())),
         TokenFrameType::Data { send_mode: TokenSendMode::Broadcast,
-            seq: 0, payload: vec![0, 1, 2] });
+            seq: 0, payload: vec![0, 1, 2], expires_at: None });
         token.frames.push(frame);
         token
     }
@@ -310,4 +683,219 @@ mod tests {
         
         assert_eq!(token, new_token)
     }
+
+    #[test]
+    fn serialize_frames() {
+        let mut token = create_token_stub();
+        token.frames.push(TokenFrame::new(TokenFrameId::new(WorkStationId::new("Alice".to_owned())),
+            TokenFrameType::Empty));
+        let frames = token.serialize_frames().unwrap();
+        assert_eq!(Token::deserialize_frames(&frames).unwrap(), token.frames);
+
+        let mut full = vec![];
+        token.write(&mut full).unwrap();
+        assert!(frames.len() < full.len());
+    }
+
+    #[test]
+    fn timestamps() {
+        let keypair = generate_keypair();
+        let mut header = TokenHeader::new(WorkStationId::new("Host".to_owned()), 3);
+        header.timestamp = 1000;
+        let mut token = Token::new(Signed::new(&keypair, header).unwrap());
+        token.frames.push(TokenFrame::new(TokenFrameId::with_timestamp(
+            WorkStationId::new("Alice".to_owned()), 1005), TokenFrameType::Empty));
+
+        let mut buf = vec![];
+        token.write(&mut buf).unwrap();
+        let token = Token::read(&mut Cursor::new(buf.as_slice())).unwrap();
+        assert_eq!(token.origin_timestamp(), 1000);
+        assert_eq!(token.frames[0].sent_at(), 1005);
+        assert_eq!(token.frames[0].source(), &WorkStationId::new("Alice".to_owned()));
+    }
+
+    #[test]
+    fn compress() {
+        let mut token = create_token_stub();
+        for seq in 1..32 {
+            token.frames.push(TokenFrame::new(TokenFrameId::new(WorkStationId::new("Alice".to_owned())),
+                TokenFrameType::Data { send_mode: TokenSendMode::Broadcast, seq, payload: vec![0; 16], expires_at: None }));
+        }
+        let compressed = token.compress().unwrap();
+        assert!(compressed.len() < token.size());
+        assert_eq!(Token::decompress(&compressed).unwrap(), token);
+
+        let mut oversized = compressed.clone();
+        oversized[..4].copy_from_slice(&(MAX_TOKEN_LEN as u32 + 1).to_be_bytes());
+        assert!(matches!(Token::decompress(&oversized),
+            Err(GlobalError::Internal(TokenRingError::LengthTooLarge { .. }))));
+    }
+
+    #[test]
+    fn sort_data_frames() {
+        let mut token = create_token_stub();
+        token.frames.clear();
+        let data = |seq| TokenFrameType::Data {
+            send_mode: TokenSendMode::Broadcast, seq, payload: vec![], expires_at: None };
+        let alice = WorkStationId::new("Alice".to_owned());
+        let bob = WorkStationId::new("Bob".to_owned());
+        token.frames.push(TokenFrame::new(TokenFrameId::with_timestamp(bob.clone(), 20), data(0)));
+        token.frames.push(TokenFrame::new(TokenFrameId::with_timestamp(bob.clone(), 10), TokenFrameType::Empty));
+        token.frames.push(TokenFrame::new(TokenFrameId::with_timestamp(bob.clone(), 10), data(1)));
+        token.frames.push(TokenFrame::new(TokenFrameId::with_timestamp(alice.clone(), 10), data(2)));
+
+        token.sort_data_frames();
+        let order = token.frames.iter().map(|f| match f.content {
+            TokenFrameType::Data { seq, .. } => Some(seq),
+            _ => None
+        }).collect::<Vec<_>>();
+        assert_eq!(order, vec![Some(2), None, Some(1), Some(0)]);
+    }
+
+    #[test]
+    fn verify_frame_signatures() {
+        let keypair = generate_keypair();
+        let mut token = create_token_stub();
+        let keys = HashMap::from([(token.frames[0].id.source.clone(), keypair.public)]);
+        // Unsigned frames do not pass
+        assert!(!token.verify_all_frames(&keys));
+
+        token.frames[0].sign(&keypair).unwrap();
+        let mut buf = vec![];
+        token.write(&mut buf).unwrap();
+        let mut token = Token::read(&mut Cursor::new(buf.as_slice())).unwrap();
+        assert!(token.verify_all_frames(&keys));
+        // Unknown source
+        assert!(!token.verify_all_frames(&HashMap::new()));
+
+        // Tamper with signed payload
+        if let TokenFrameType::Data { payload, .. } = &mut token.frames[0].content {
+            payload.push(3);
+        }
+        assert!(!token.verify_all_frames(&keys));
+    }
+
+    #[test]
+    fn forged_frame_source() {
+        let (alice, mallory) = (generate_keypair(), generate_keypair());
+        let mut token = create_token_stub();
+        let keys = HashMap::from([(token.frames[0].id.source.clone(), alice.public)]);
+        // Validly signed, but not by the station the frame claims to be from
+        token.frames[0].sign(&mallory).unwrap();
+        assert!(token.frames[0].verify());
+        assert!(!token.verify_all_frames(&keys));
+    }
+
+    #[test]
+    fn expiring_data_frame() {
+        for expires_at in [None, Some(1_700_000_000)] {
+            let frame = TokenFrameType::Data { send_mode: TokenSendMode::Broadcast,
+                seq: 3, payload: vec![1, 2], expires_at };
+            let mut buf = vec![];
+            frame.write(&mut buf).unwrap();
+            assert_eq!(buf.len(), frame.size());
+            assert_eq!(buf[0], if expires_at.is_some() { 4 } else { 1 });
+            assert_eq!(TokenFrameType::read(&mut Cursor::new(buf.as_slice())).unwrap(), frame);
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Reading {
+        sensor: u16,
+        value: u64
+    }
+
+    impl Serializable for Reading {
+        type Output = Reading;
+
+        fn write(&self, buf: &mut Vec<u8>) -> TResult {
+            buf.write_u16::<BigEndian>(self.sensor)?;
+            Ok(buf.write_u64::<BigEndian>(self.value)?)
+        }
+
+        fn read(buf: &mut Cursor<&[u8]>) -> TResult<Self::Output> {
+            let sensor = buf.read_u16::<BigEndian>()?;
+            let value = buf.read_u64::<BigEndian>()?;
+            Ok(Reading { sensor, value })
+        }
+
+        fn size(&self) -> usize {
+            2 + 8
+        }
+    }
+
+    #[test]
+    fn decode_data_frame() {
+        let reading = Reading { sensor: 3, value: 1337 };
+        let frame = TokenFrame::new(TokenFrameId::new(WorkStationId::new("Bob".to_owned())),
+            TokenFrameType::data_from(&reading, TokenSendMode::Broadcast, 0).unwrap());
+        assert_eq!(frame.decode::<Reading>().unwrap(), reading);
+
+        let empty = TokenFrame::new(TokenFrameId::new(WorkStationId::new("Bob".to_owned())),
+            TokenFrameType::Empty);
+        assert!(empty.decode::<Reading>().is_err());
+    }
+
+    // Frame counts are only bounded by the u32 length prefix. In memory only,
+    // the wire can not carry such a token (see RECV_BUF_LENGTH).
+    #[test]
+    #[ignore = "expensive"]
+    fn many_frames() {
+        let mut token = create_token_stub();
+        let source = WorkStationId::new("Some Station".to_owned());
+        for i in 0..70_000u32 {
+            token.frames.push(TokenFrame::new(TokenFrameId::new(source.clone()),
+                TokenFrameType::Data { send_mode: TokenSendMode::Broadcast,
+                    seq: i as u16, payload: vec![], expires_at: None }));
+        }
+        token.sort_data_frames();
+        let mut buf = vec![];
+        token.write(&mut buf).unwrap();
+        assert_eq!(buf.len(), token.size());
+        let read_token = Token::read(&mut Cursor::new(buf.as_slice())).unwrap();
+        assert_eq!(read_token.frames.len(), 70_001);
+        assert!(read_token == token);
+    }
+
+    #[test]
+    fn overhead_ratio() {
+        let mut token = Token::new(Signed::new(&generate_keypair(),
+            TokenHeader::new(WorkStationId::new("Test".to_owned()), 0)).unwrap());
+        assert_eq!(token.overhead_ratio(), 1.);
+
+        let id = TokenFrameId::new(WorkStationId::new("Alice".to_owned()));
+        token.frames.push(TokenFrame::new(id.clone(), TokenFrameType::Data {
+            send_mode: TokenSendMode::Broadcast, seq: 0, payload: vec![0; 100], expires_at: None }));
+        token.frames.push(TokenFrame::new(id.clone(), TokenFrameType::App { type_id: 1, payload: vec![0; 50] }));
+        // Header and frame count, then per frame ID, type tag and signature flag
+        let metadata = token.header.size() + 4
+            + (id.size() + 1 + 1) + 1 + 2 + 2  // Send mode, seq, length prefix
+            + (id.size() + 1 + 1) + 2 + 2;     // App type, length prefix
+        assert_eq!(token.size(), metadata + 150);
+        assert_eq!(token.overhead_ratio(), metadata as f32 / (metadata + 150) as f32);
+    }
+
+    #[test]
+    fn frame_order_within_second() {
+        let alice = WorkStationId::new("Alice".to_owned());
+        let (first, second) = (TokenFrameId::new(alice.clone()), TokenFrameId::new(alice));
+        assert!(first.order_key() < second.order_key());
+
+        let mut buf = vec![];
+        second.write(&mut buf).unwrap();
+        assert_eq!(TokenFrameId::read(&mut Cursor::new(buf.as_slice())).unwrap(), second);
+    }
+
+    #[test]
+    fn unknown_tags() {
+        assert!(matches!(PresenceStatus::read(&mut Cursor::new([3u8].as_slice())),
+            Err(GlobalError::Internal(TokenRingError::InvalidTag { kind: "PresenceStatus", tag: 3 }))));
+        assert!(matches!(TokenSendMode::read(&mut Cursor::new([7u8].as_slice())),
+            Err(GlobalError::Internal(TokenRingError::InvalidTag { kind: "TokenSendMode", tag: 7 }))));
+        // Presence frame with unknown status
+        assert!(matches!(TokenFrameType::read(&mut Cursor::new([3u8, 9].as_slice())),
+            Err(GlobalError::Internal(TokenRingError::InvalidTag { kind: "PresenceStatus", tag: 9 }))));
+        assert!(matches!(TokenFrameType::read(&mut Cursor::new([200u8].as_slice())),
+            Err(GlobalError::Internal(TokenRingError::InvalidTag { kind: "TokenFrameType", tag: 200 }))));
+    }
 }
@@ -1,5 +1,123 @@
-use std::time::{UNIX_EPOCH, SystemTime};
+use std::{time::{UNIX_EPOCH, SystemTime, Instant, Duration}, sync::{Arc, Mutex}, cmp::Ordering};
 
 pub fn timestamp() -> u64 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
-}
\ No newline at end of file
+}
+
+// Orders frame sequence numbers across wraparound (serial number arithmetic,
+// RFC 1982): a is less than b if b is less than half the range ahead of a,
+// so 65535 < 0. Undefined for numbers exactly half the range apart.
+pub fn seq_cmp(a: u16, b: u16) -> Ordering {
+    (a.wrapping_sub(b) as i16).cmp(&0)
+}
+
+// Sequence numbers seen from one sender: the highest one (by seq_cmp) and
+// which of the 63 below it. Anything older counts as seen.
+#[derive(Debug, Clone, Copy)]
+pub struct SeqWindow {
+    highest: u16,
+    // Bit i is set if highest - i was seen
+    seen: u64
+}
+
+impl SeqWindow {
+    pub fn new(seq: u16) -> SeqWindow {
+        SeqWindow {
+            highest: seq, seen: 1
+        }
+    }
+
+    // Marks seq as seen. False if it was seen before or is too old to tell.
+    pub fn insert(&mut self, seq: u16) -> bool {
+        if seq_cmp(seq, self.highest) == Ordering::Greater {
+            self.seen = self.seen.checked_shl(seq.wrapping_sub(self.highest) as u32).unwrap_or(0) | 1;
+            self.highest = seq;
+            return true
+        }
+        let bit = match 1u64.checked_shl(self.highest.wrapping_sub(seq) as u32) {
+            Some(bit) => bit,
+            None => return false
+        };
+        let new = self.seen & bit == 0;
+        self.seen |= bit;
+        new
+    }
+}
+
+// Source of monotonic time, swappable so timing logic can be tested without sleeping
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// Clock that only moves when advanced. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock {
+            now: Arc::new(Mutex::new(Instant::now()))
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use super::{seq_cmp, SeqWindow};
+
+    #[test]
+    fn seq_wraparound() {
+        assert_eq!(seq_cmp(1, 2), Ordering::Less);
+        assert_eq!(seq_cmp(2, 1), Ordering::Greater);
+        assert_eq!(seq_cmp(7, 7), Ordering::Equal);
+        assert_eq!(seq_cmp(u16::MAX, 0), Ordering::Less);
+        assert_eq!(seq_cmp(0, u16::MAX), Ordering::Greater);
+        assert_eq!(seq_cmp(65530, 5), Ordering::Less);
+        assert_eq!(seq_cmp(0, 32767), Ordering::Less);
+        assert_eq!(seq_cmp(0, 32769), Ordering::Greater);
+    }
+
+    #[test]
+    fn seq_window() {
+        let mut window = SeqWindow::new(65534);
+        assert!(!window.insert(65534));
+        // Across wraparound, with a gap filled in later
+        assert!(window.insert(1));
+        assert!(window.insert(65535));
+        assert!(!window.insert(65535));
+        assert!(window.insert(0));
+        assert!(!window.insert(1));
+        // Too old to tell
+        assert!(window.insert(100));
+        assert!(!window.insert(2));
+        assert!(window.insert(99));
+    }
+}